@@ -0,0 +1,552 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::BufWriter,
+    net::Ipv4Addr,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use extractor::{
+    secs_to_nanos, Anonymizer, Column, Compression, Endpoint, ExtractOptions, Extras,
+    FeatureSchema, Features, FlowLimits, Format, HeadersInfo, Label, Labeler, OutputOptions, RowId,
+    RowPolicy, Sink, SkipCounts, TableReader, Value, Window,
+};
+use rand::{seq::SliceRandom, Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+#[derive(clap::Args)]
+pub struct BuildArgs {
+    /// JSON manifest describing the inputs of the dataset
+    manifest: PathBuf,
+    /// worker threads used when extracting pcap inputs
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+}
+
+#[derive(Deserialize)]
+struct Manifest {
+    output: PathBuf,
+    // picked from the output extension when not set
+    format: Option<Format>,
+    row_group_size: Option<usize>,
+    compression: Option<Compression>,
+    #[serde(default = "default_seed")]
+    seed: u64,
+    // downsample every class to the size of the smallest one
+    #[serde(default)]
+    balance: bool,
+    max_per_class: Option<usize>,
+    #[serde(default)]
+    class_caps: BTreeMap<String, usize>,
+    // feature config, see `FeatureConfig`; every built-in feature when not set
+    features: Option<PathBuf>,
+    // which packets of pcap inputs become rows, see `RowPolicy`
+    #[serde(default)]
+    rows: RowPolicy,
+    // secret key for hashing the IP addresses and flow ids in the id columns
+    anonymize: Option<String>,
+    // seconds of capture time after which idle flows are forgotten when extracting pcap inputs
+    flow_idle_timeout: Option<f64>,
+    // flows with state kept at once when extracting pcap inputs, `null` for no limit
+    #[serde(default = "default_max_flows")]
+    max_flows: Option<usize>,
+    // seconds packets of pcap inputs are held to put them back in timestamp order
+    reorder_window: Option<f64>,
+    inputs: Vec<Input>,
+}
+
+// merge.ipynb shuffled with random_state=1
+fn default_seed() -> u64 {
+    1
+}
+
+fn default_max_flows() -> Option<usize> {
+    FlowLimits::default().max_entries
+}
+
+#[derive(Deserialize)]
+struct Input {
+    path: PathBuf,
+    label: Option<u8>,
+    attack_class: Option<String>,
+    #[serde(default)]
+    attackers: Vec<Endpoint>,
+    #[serde(default)]
+    attack_windows: Vec<Window>,
+    flows: Option<PathBuf>,
+}
+
+impl Input {
+    fn is_csv(&self) -> bool {
+        self.path.extension().is_some_and(|ext| ext == "csv")
+    }
+
+    fn labeler(&self, base: &Path) -> anyhow::Result<Labeler> {
+        let mut labeler = Labeler::default();
+        if let Some(class) = &self.attack_class {
+            labeler = labeler.class(class);
+        }
+        if let Some(label) = self.label {
+            labeler = labeler.constant(label);
+        }
+        for &attacker in &self.attackers {
+            labeler = labeler.attacker(attacker);
+        }
+        for &window in &self.attack_windows {
+            labeler = labeler.window(window);
+        }
+        if let Some(path) = &self.flows {
+            labeler = labeler.flows_file(base.join(path))?;
+        }
+
+        Ok(labeler)
+    }
+}
+
+// label columns of a CSV written by header-to-csv with labeling enabled
+#[derive(Deserialize)]
+struct CsvLabel {
+    output: Option<u8>,
+    attack_class: Option<String>,
+}
+
+struct Row {
+    input: usize,
+    id: RowId,
+    features: Vec<Value>,
+    label: Label,
+}
+
+impl Row {
+    // the row as written, features then label then ids
+    fn into_values(mut self, anonymizer: Option<&Anonymizer>) -> Vec<Value> {
+        let mut values = self.features;
+        values.extend(self.label.values());
+        if let Some(anonymizer) = anonymizer {
+            self.id.anonymize(anonymizer);
+        }
+        values.extend(self.id.values());
+        values
+    }
+}
+
+// Keeps a uniform sample of at most `cap` rows using reservoir sampling.
+struct Reservoir {
+    cap: Option<usize>,
+    seen: usize,
+    rows: Vec<Row>,
+}
+
+impl Reservoir {
+    fn push(&mut self, row: Row, rng: &mut ChaCha8Rng) {
+        self.seen += 1;
+        match self.cap {
+            Some(cap) if self.rows.len() >= cap => {
+                let i = rng.gen_range(0..self.seen);
+                if i < cap {
+                    self.rows[i] = row;
+                }
+            }
+            _ => self.rows.push(row),
+        }
+    }
+}
+
+const SPILL_FILES: usize = 256;
+
+// Without caps or balancing every row is kept, more than may fit in memory. Rows are dealt out to
+// spill files at random as they come, and each file is shuffled on its own as it's written out.
+struct Spill {
+    dir: PathBuf,
+    files: Vec<Box<dyn Sink>>,
+}
+
+impl Spill {
+    fn new(dir: PathBuf, columns: &[Column]) -> anyhow::Result<Self> {
+        fs::create_dir_all(&dir).with_context(|| format!("creating {}", dir.display()))?;
+        let files = (0..SPILL_FILES)
+            .map(|i| {
+                let path = dir.join(format!("{i}.jsonl"));
+                extractor::create_sink(path, columns, &OutputOptions::default())
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self { dir, files })
+    }
+
+    fn push(&mut self, values: &[Value], rng: &mut ChaCha8Rng) -> anyhow::Result<()> {
+        let i = rng.gen_range(0..self.files.len());
+        self.files[i].write(values)
+    }
+
+    fn drain(self, sink: &mut dyn Sink, rng: &mut ChaCha8Rng) -> anyhow::Result<()> {
+        let count = self.files.len();
+        for file in self.files {
+            file.finish()?;
+        }
+        for i in 0..count {
+            let path = self.dir.join(format!("{i}.jsonl"));
+            let mut rows = TableReader::open(&path, Some(Format::Jsonl))?
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("reading back {}", path.display()))?;
+            rows.shuffle(rng);
+            for row in rows {
+                let values: Option<Vec<_>> = row.into_iter().collect();
+                sink.write(&values.ok_or_else(|| anyhow!("{}: missing cell", path.display()))?)?;
+            }
+        }
+
+        fs::remove_dir_all(&self.dir).with_context(|| format!("removing {}", self.dir.display()))
+    }
+}
+
+#[derive(Serialize)]
+struct InputReport {
+    path: PathBuf,
+    read: BTreeMap<String, usize>,
+    kept: BTreeMap<String, usize>,
+    // frames of pcap inputs that didn't parse, by reason
+    skipped: SkipCounts,
+}
+
+#[derive(Serialize)]
+struct Report {
+    output: PathBuf,
+    seed: u64,
+    balance: bool,
+    rows: usize,
+    classes: BTreeMap<String, usize>,
+    inputs: Vec<InputReport>,
+}
+
+pub fn run(args: BuildArgs) -> anyhow::Result<()> {
+    let file = File::open(&args.manifest)
+        .with_context(|| format!("opening {}", args.manifest.display()))?;
+    let manifest: Manifest = serde_json::from_reader(file)
+        .with_context(|| format!("parsing {}", args.manifest.display()))?;
+    // paths in the manifest are relative to it
+    let base = args.manifest.parent().unwrap_or(Path::new("."));
+    let features = match &manifest.features {
+        Some(path) => Features::load(base.join(path))?,
+        None => Features::default(),
+    };
+
+    let output = base.join(&manifest.output);
+    let mut options = OutputOptions {
+        format: manifest.format,
+        features: Some(features.config().clone()),
+        ..Default::default()
+    };
+    if let Some(row_group_size) = manifest.row_group_size {
+        options.row_group_size = row_group_size;
+    }
+    if let Some(compression) = manifest.compression {
+        options.compression = compression;
+    }
+    let mut columns = features.columns();
+    columns.extend(Label::columns());
+    columns.extend(RowId::columns());
+    let anonymizer = manifest.anonymize.as_deref().map(Anonymizer::new);
+
+    let mut rng = ChaCha8Rng::seed_from_u64(manifest.seed);
+    let mut classes: BTreeMap<String, Reservoir> = BTreeMap::new();
+    let sampled =
+        manifest.balance || manifest.max_per_class.is_some() || !manifest.class_caps.is_empty();
+    let mut spill = match sampled {
+        true => None,
+        false => Some(Spill::new(output.with_extension("spill"), &columns)?),
+    };
+    let mut reports = Vec::new();
+
+    for (index, input) in manifest.inputs.iter().enumerate() {
+        let path = base.join(&input.path);
+        let labeler = input.labeler(base)?;
+        let mut read = BTreeMap::new();
+        let mut skipped = SkipCounts::default();
+
+        let mut push = |id: RowId, features: Vec<Value>, label: Label| {
+            *read.entry(label.attack_class.clone()).or_default() += 1;
+            let row = Row {
+                input: index,
+                id,
+                features,
+                label,
+            };
+            if let Some(spill) = &mut spill {
+                return spill.push(&row.into_values(anonymizer.as_ref()), &mut rng);
+            }
+
+            let cap = manifest
+                .class_caps
+                .get(&row.label.attack_class)
+                .copied()
+                .or(manifest.max_per_class);
+            classes
+                .entry(row.label.attack_class.clone())
+                .or_insert_with(|| Reservoir {
+                    cap,
+                    seen: 0,
+                    rows: Vec::new(),
+                })
+                .push(row, &mut rng);
+            Ok(())
+        };
+
+        if input.is_csv() {
+            if !input.attackers.is_empty()
+                || !input.attack_windows.is_empty()
+                || input.flows.is_some()
+            {
+                return Err(anyhow!(
+                    "{}: CSV rows carry no addresses or timestamps, only `label` can be used",
+                    input.path.display()
+                ));
+            }
+            if features.has_groups() {
+                return Err(anyhow!(
+                    "{}: feature groups need the packets, extract from the pcap instead",
+                    input.path.display()
+                ));
+            }
+
+            // time features are read back as they are, in the unit they were written in
+            let schema = FeatureSchema::check_sidecar(&path)?;
+            if let Some(config) = schema.and_then(|schema| schema.features) {
+                if config.time_unit != features.time_unit() {
+                    return Err(anyhow!(
+                        "{}: extracted with another time unit than the feature config",
+                        input.path.display()
+                    ));
+                }
+            }
+            let mut reader = csv::Reader::from_path(&path)
+                .with_context(|| format!("opening {}", path.display()))?;
+            let headers = reader.headers()?.clone();
+            // missing columns would otherwise be read as zeros
+            let missing: Vec<_> = features
+                .builtin_inputs()
+                .into_iter()
+                .filter(|name| !headers.iter().any(|h| h == name))
+                .collect();
+            if !missing.is_empty() {
+                return Err(anyhow!(
+                    "{} has no {} column, extract it again from the pcap",
+                    input.path.display(),
+                    missing.join(", ")
+                ));
+            }
+
+            for record in reader.records() {
+                let record = record?;
+                let info: HeadersInfo = record.deserialize(Some(&headers))?;
+                let mut id: RowId = record.deserialize(Some(&headers))?;
+                // CSVs from before ids were written only know which file they came from
+                if id.capture_id.is_empty() {
+                    id.capture_id = input.path.display().to_string();
+                }
+                if !manifest.rows.keeps(id.row_kind) {
+                    continue;
+                }
+                let label = match input.label {
+                    Some(_) => {
+                        let unknown = (Ipv4Addr::UNSPECIFIED, 0);
+                        labeler.label(0, unknown, unknown)
+                    }
+                    None => match record.deserialize::<CsvLabel>(Some(&headers))? {
+                        CsvLabel {
+                            output: Some(0), ..
+                        } => Label::benign(),
+                        CsvLabel {
+                            output: Some(output),
+                            attack_class,
+                        } => Label {
+                            output,
+                            attack_class: attack_class.unwrap_or_else(|| "attack".to_string()),
+                        },
+                        CsvLabel { output: None, .. } => {
+                            return Err(anyhow!(
+                                "{} has no `output` column, give it a `label`",
+                                input.path.display()
+                            ))
+                        }
+                    },
+                };
+                push(id, features.values(&info, &Extras::default()), label)?;
+            }
+        } else {
+            let options = ExtractOptions {
+                labeler: Some(&labeler),
+                threads: args.threads,
+                rows: manifest.rows,
+                features: features.clone(),
+                flows: FlowLimits::default()
+                    .idle_timeout(manifest.flow_idle_timeout)
+                    .max_entries(manifest.max_flows),
+                reorder: manifest.reorder_window.map(secs_to_nanos),
+                quarantine: None,
+            };
+            let stats = extractor::extract_pcap(&path, &options, |id, values, label| {
+                push(id, values, label.unwrap_or_else(Label::benign))
+            })
+            .with_context(|| format!("extracting {}", path.display()))?;
+            if stats.skipped.total() > 0 {
+                eprintln!(
+                    "{}: skipped {} frames that don't parse ({})",
+                    input.path.display(),
+                    stats.skipped.total(),
+                    stats.skipped
+                );
+            }
+            if stats.late > 0 {
+                eprintln!(
+                    "{}: {} packets too late to be put in timestamp order",
+                    input.path.display(),
+                    stats.late
+                );
+            }
+            let dropped: u64 = stats.evictions().iter().map(|(_, e)| e.idle + e.lru).sum();
+            if dropped > 0 {
+                eprintln!(
+                    "{}: state of {dropped} flows dropped before they closed",
+                    input.path.display()
+                );
+            }
+            skipped = stats.skipped;
+        }
+
+        reports.push(InputReport {
+            path: input.path.clone(),
+            kept: match sampled {
+                true => BTreeMap::new(),
+                false => read.clone(),
+            },
+            read,
+            skipped,
+        });
+    }
+
+    let mut sink = extractor::create_sink(&output, &columns, &options)?;
+    let mut totals = BTreeMap::new();
+    if let Some(spill) = spill {
+        spill.drain(sink.as_mut(), &mut rng)?;
+        for report in &reports {
+            for (class, count) in &report.kept {
+                *totals.entry(class.clone()).or_default() += count;
+            }
+        }
+    }
+
+    if manifest.balance {
+        let smallest = classes.values().map(|c| c.rows.len()).min().unwrap_or(0);
+        for class in classes.values_mut() {
+            class.rows.shuffle(&mut rng);
+            class.rows.truncate(smallest);
+        }
+    }
+
+    let mut rows: Vec<Row> = classes.into_values().flat_map(|c| c.rows).collect();
+    rows.shuffle(&mut rng);
+
+    for row in rows {
+        *totals.entry(row.label.attack_class.clone()).or_default() += 1;
+        *reports[row.input]
+            .kept
+            .entry(row.label.attack_class.clone())
+            .or_default() += 1;
+        sink.write(&row.into_values(anonymizer.as_ref()))?;
+    }
+    sink.finish()?;
+
+    let report = Report {
+        output: manifest.output,
+        seed: manifest.seed,
+        balance: manifest.balance,
+        rows: totals.values().sum(),
+        classes: totals,
+        inputs: reports,
+    };
+    let report_path = output.with_extension("manifest.json");
+    let file = BufWriter::new(File::create(&report_path)?);
+    serde_json::to_writer_pretty(file, &report)?;
+
+    println!("wrote {} rows to {}", report.rows, output.display());
+    for (class, count) in &report.classes {
+        println!("{class:>16} {count}");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use extractor::{ColumnType, Role};
+
+    use super::*;
+
+    struct Collect(Vec<Vec<Value>>);
+
+    impl Sink for Collect {
+        fn write(&mut self, row: &[Value]) -> anyhow::Result<()> {
+            self.0.push(row.to_vec());
+            Ok(())
+        }
+
+        fn finish(self: Box<Self>) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("build-dataset-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn spill_writes_every_row_once() {
+        let dir = temp_dir("spill");
+        let columns = [
+            Column::new("n", ColumnType::U64, Role::Feature),
+            Column::new("class", ColumnType::Str, Role::Label),
+        ];
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let mut spill = Spill::new(dir.join("spill"), &columns).unwrap();
+        let rows: Vec<_> = (0..1000)
+            .map(|n| vec![Value::U64(n), Value::Str(format!("c{}", n % 3))])
+            .collect();
+        for row in &rows {
+            spill.push(row, &mut rng).unwrap();
+        }
+        let mut out = Collect(Vec::new());
+        spill.drain(&mut out, &mut rng).unwrap();
+
+        assert_ne!(out.0, rows);
+        let mut sorted = out.0;
+        sorted.sort_by_key(|row| match row[0] {
+            Value::U64(n) => n,
+            _ => unreachable!(),
+        });
+        assert_eq!(sorted, rows);
+        assert!(!dir.join("spill").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_csv_inputs_missing_feature_columns() {
+        let dir = temp_dir("narrow");
+        fs::write(dir.join("narrow.csv"), "packet_len,ip_len\n60,40\n").unwrap();
+        let manifest = dir.join("manifest.json");
+        let json = r#"{"output": "out.csv", "inputs": [{"path": "narrow.csv", "label": 0}]}"#;
+        fs::write(&manifest, json).unwrap();
+
+        let args = BuildArgs {
+            manifest,
+            threads: 1,
+        };
+        let error = run(args).unwrap_err().to_string();
+        assert!(error.contains("has no ip_df, ip_mf"), "{error}");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
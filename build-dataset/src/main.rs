@@ -0,0 +1,29 @@
+use clap::{Parser, Subcommand};
+
+mod build;
+mod split;
+mod stats;
+
+#[derive(Parser)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// build a labeled, shuffled dataset from a JSON manifest
+    Build(build::BuildArgs),
+    /// profile the columns of a feature file, or compare two of them
+    Stats(stats::StatsArgs),
+    /// split a dataset into train, validation and test files without leaking flows between them
+    Split(split::SplitArgs),
+}
+
+fn main() -> anyhow::Result<()> {
+    match Args::parse().command {
+        Command::Build(args) => build::run(args),
+        Command::Stats(args) => stats::run(args),
+        Command::Split(args) => split::run(args),
+    }
+}
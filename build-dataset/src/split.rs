@@ -0,0 +1,461 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use extractor::{Column, Format, OutputOptions, TableReader, Value};
+
+#[derive(clap::Args)]
+pub struct SplitArgs {
+    /// feature file with id columns, as written by header-to-csv or build-dataset
+    dataset: PathBuf,
+    /// flow, host or time; rows of one flow or host never end up in two splits, and time keeps
+    /// every training row before every validation row, and those before every test row
+    #[arg(long, default_value = "flow")]
+    by: SplitBy,
+    /// share of rows for training
+    #[arg(long, default_value_t = 0.8)]
+    train: f64,
+    /// share of rows for validation
+    #[arg(long, default_value_t = 0.0)]
+    validation: f64,
+    /// share of rows for testing
+    #[arg(long, default_value_t = 0.2)]
+    test: f64,
+    /// seed deciding which split each flow or host goes to
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+    /// directory to write `<name>.train.<ext>` and friends to, next to the dataset by default
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+    /// format of the dataset and the splits, picked from the file extension when not set
+    #[arg(long)]
+    format: Option<Format>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SplitBy {
+    Flow,
+    // the client end of each connection, taken as the endpoint with the higher port
+    Host,
+    Time,
+}
+
+impl FromStr for SplitBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flow" => Ok(SplitBy::Flow),
+            "host" => Ok(SplitBy::Host),
+            "time" => Ok(SplitBy::Time),
+            _ => Err(anyhow!("unknown split {s}, expected flow, host or time")),
+        }
+    }
+}
+
+// Positions of the columns a split mode groups or orders rows by.
+enum Keys {
+    Flow {
+        capture_id: usize,
+        flow_id: usize,
+    },
+    Host {
+        src_ip: usize,
+        dst_ip: usize,
+        src_port: usize,
+        dst_port: usize,
+    },
+    Time {
+        ts: usize,
+    },
+}
+
+impl Keys {
+    fn new(by: SplitBy, columns: &[Column]) -> anyhow::Result<Self> {
+        let position = |name: &str| {
+            columns.iter().position(|c| c.name == name).ok_or_else(|| {
+                anyhow!("no `{name}` column, extract the dataset again to get row ids")
+            })
+        };
+        // features rather than ids, a feature config may have left them out
+        let port = |name: &str| {
+            columns
+                .iter()
+                .position(|c| c.name == name)
+                .ok_or_else(|| anyhow!("no `{name}` column, splitting by host needs the ports"))
+        };
+
+        Ok(match by {
+            SplitBy::Flow => Keys::Flow {
+                capture_id: position("capture_id")?,
+                flow_id: position("flow_id")?,
+            },
+            SplitBy::Host => Keys::Host {
+                src_ip: position("src_ip")?,
+                dst_ip: position("dst_ip")?,
+                src_port: port("tcp_src_port")?,
+                dst_port: port("tcp_dst_port")?,
+            },
+            SplitBy::Time => Keys::Time {
+                ts: position("ts")?,
+            },
+        })
+    }
+
+    fn group(&self, row: &[Value]) -> String {
+        match *self {
+            Keys::Flow {
+                capture_id,
+                flow_id,
+            } => format!("{}/{}", row[capture_id], row[flow_id]),
+            Keys::Host {
+                src_ip,
+                dst_ip,
+                src_port,
+                dst_port,
+            } => match row[src_port].as_f64() > row[dst_port].as_f64() {
+                true => row[src_ip].to_string(),
+                false => row[dst_ip].to_string(),
+            },
+            Keys::Time { .. } => unreachable!("time splits don't group rows"),
+        }
+    }
+
+    fn ts(&self, row: &[Value]) -> i64 {
+        match self {
+            Keys::Time { ts } => match row[*ts] {
+                Value::I64(ts) => ts,
+                _ => 0,
+            },
+            _ => unreachable!("only time splits order rows"),
+        }
+    }
+}
+
+// FNV-1a of the seed and key, mapped to [0, 1). FNV hardly moves the high bits for keys that
+// only differ at the end, like `10.0.0.1` and `10.0.0.2`, so they're mixed in with the splitmix64
+// finalizer first.
+fn bucket(seed: u64, key: &str) -> f64 {
+    let mut hash = seed
+        .to_le_bytes()
+        .iter()
+        .chain(key.as_bytes())
+        .fold(0xcbf29ce484222325u64, |hash, &byte| {
+            (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+        });
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^= hash >> 31;
+    (hash >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// sinks want every cell, empty CSV cells can't be carried over
+fn complete(columns: &[Column], row: Vec<Option<Value>>) -> anyhow::Result<Vec<Value>> {
+    row.into_iter()
+        .zip(columns)
+        .map(|(value, column)| value.ok_or_else(|| anyhow!("empty `{}` cell", column.name)))
+        .collect()
+}
+
+fn open(path: &Path, format: Option<Format>) -> anyhow::Result<TableReader> {
+    TableReader::open(path, format).with_context(|| format!("opening {}", path.display()))
+}
+
+pub fn run(args: SplitArgs) -> anyhow::Result<()> {
+    let total = args.train + args.validation + args.test;
+    if total <= 0.0
+        || [args.train, args.validation, args.test]
+            .iter()
+            .any(|&r| r < 0.0)
+    {
+        return Err(anyhow!("split shares must be positive"));
+    }
+    let splits = [
+        ("train", args.train / total),
+        ("validation", args.validation / total),
+        ("test", args.test / total),
+    ];
+
+    let mut reader = Some(open(&args.dataset, args.format)?);
+    let columns = reader.as_ref().unwrap().columns().to_vec();
+    let keys = Keys::new(args.by, &columns)?;
+
+    // time splits cut at row count quantiles of the timestamps, so they need a first pass
+    let cuts = match args.by {
+        SplitBy::Time => {
+            if args.dataset.to_str() == Some("-") {
+                return Err(anyhow!(
+                    "splitting by time reads the dataset twice, stdin won't do"
+                ));
+            }
+            let mut ts = Vec::new();
+            for row in reader.take().unwrap() {
+                ts.push(keys.ts(&complete(&columns, row?)?));
+            }
+            ts.sort_unstable();
+
+            let mut share = 0.0;
+            let cuts: Vec<_> = splits
+                .iter()
+                .map(|(_, ratio)| {
+                    share += ratio;
+                    ts.get((share * ts.len() as f64) as usize)
+                        .copied()
+                        .unwrap_or(i64::MAX)
+                })
+                .collect();
+            Some(cuts)
+        }
+        _ => None,
+    };
+    let reader = match reader {
+        Some(reader) => reader,
+        None => open(&args.dataset, args.format)?,
+    };
+
+    let format = args
+        .format
+        .unwrap_or_else(|| Format::from_path(&args.dataset));
+    let name = match args.dataset.file_name().and_then(|n| n.to_str()) {
+        Some("-") | None => "stdin".to_string(),
+        Some(_) => args
+            .dataset
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let ext = match args.dataset.extension() {
+        Some(ext) => ext.to_string_lossy().into_owned(),
+        None => format!("{format:?}").to_lowercase(),
+    };
+    let dir = match &args.output_dir {
+        Some(dir) => dir.clone(),
+        None => args
+            .dataset
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf(),
+    };
+    let options = OutputOptions {
+        format: Some(format),
+        features: reader.features().cloned(),
+        ..Default::default()
+    };
+
+    let mut sinks = Vec::new();
+    for (split, ratio) in splits {
+        let sink = match ratio > 0.0 {
+            true => {
+                let path = dir.join(format!("{name}.{split}.{ext}"));
+                Some((
+                    path.clone(),
+                    extractor::create_sink(&path, &columns, &options)?,
+                ))
+            }
+            false => None,
+        };
+        sinks.push(sink);
+    }
+
+    let mut rows = [0usize; 3];
+    let mut groups: [HashSet<String>; 3] = Default::default();
+
+    for row in reader {
+        let row = complete(&columns, row?)?;
+        let index = match &cuts {
+            Some(cuts) => {
+                let ts = keys.ts(&row);
+                cuts.iter().position(|&cut| ts < cut).unwrap_or(2)
+            }
+            None => {
+                let group = keys.group(&row);
+                let u = bucket(args.seed, &group);
+                let mut share = 0.0;
+                let index = splits
+                    .iter()
+                    .position(|(_, ratio)| {
+                        share += ratio;
+                        u < share
+                    })
+                    .unwrap_or(2);
+                groups[index].insert(group);
+                index
+            }
+        };
+
+        // a rounding leftover can point at a split that was turned off
+        let index = (index..3)
+            .chain((0..index).rev())
+            .find(|&i| sinks[i].is_some())
+            .unwrap();
+        sinks[index].as_mut().unwrap().1.write(&row)?;
+        rows[index] += 1;
+    }
+
+    let mut summary = BTreeMap::new();
+    for (i, sink) in sinks.into_iter().enumerate() {
+        if let Some((path, sink)) = sink {
+            sink.finish()?;
+            summary.insert(i, path);
+        }
+    }
+
+    for (i, path) in summary {
+        let groups = match args.by {
+            SplitBy::Time => String::new(),
+            _ => format!(" from {} groups", groups[i].len()),
+        };
+        println!(
+            "{:>10} {} rows{groups} to {}",
+            splits[i].0,
+            rows[i],
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use extractor::{ColumnType, Role};
+
+    use super::*;
+
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("tcp_src_port", ColumnType::U16, Role::Feature),
+            Column::new("tcp_dst_port", ColumnType::U16, Role::Feature),
+            Column::new("capture_id", ColumnType::Str, Role::Id),
+            Column::new("flow_id", ColumnType::U64, Role::Id),
+            Column::new("ts", ColumnType::I64, Role::Id),
+            Column::new("src_ip", ColumnType::Str, Role::Id),
+            Column::new("dst_ip", ColumnType::Str, Role::Id),
+        ]
+    }
+
+    // 5 rows of each of 40 connections from 20 clients to the broker, alternating direction
+    fn dataset(dir: &Path) -> PathBuf {
+        let path = dir.join("all.csv");
+        let mut sink =
+            extractor::create_sink(&path, &columns(), &OutputOptions::default()).unwrap();
+        for i in 0..200u64 {
+            let flow = i % 40;
+            let client = (format!("10.0.0.{}", flow % 20), 40000 + flow as u16);
+            let broker = ("10.0.0.100".to_string(), 1883);
+            let (src, dst) = match i % 2 {
+                0 => (client, broker),
+                _ => (broker, client),
+            };
+            let row = [
+                Value::U16(src.1),
+                Value::U16(dst.1),
+                Value::Str("a.pcap".into()),
+                Value::U64(flow),
+                Value::I64(i as i64),
+                Value::Str(src.0),
+                Value::Str(dst.0),
+            ];
+            sink.write(&row).unwrap();
+        }
+        sink.finish().unwrap();
+        path
+    }
+
+    fn split(dataset: &Path, by: SplitBy) -> Vec<Vec<Vec<Value>>> {
+        let args = SplitArgs {
+            dataset: dataset.to_path_buf(),
+            by,
+            train: 0.8,
+            validation: 0.0,
+            test: 0.2,
+            seed: 1,
+            output_dir: None,
+            format: None,
+        };
+        run(args).unwrap();
+        ["train", "test"]
+            .iter()
+            .map(|split| {
+                let path = dataset.with_file_name(format!("all.{split}.csv"));
+                open(&path, None)
+                    .unwrap()
+                    .map(|row| complete(&columns(), row.unwrap()).unwrap())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("build-dataset-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn flows_and_hosts_stay_in_one_split() {
+        let dir = temp_dir("split-groups");
+        let dataset = dataset(&dir);
+        for by in [SplitBy::Flow, SplitBy::Host] {
+            let keys = Keys::new(by, &columns()).unwrap();
+            let splits = split(&dataset, by);
+            assert_eq!(splits.iter().map(Vec::len).sum::<usize>(), 200);
+            assert!(splits.iter().all(|rows| !rows.is_empty()));
+
+            let groups: Vec<HashSet<_>> = splits
+                .iter()
+                .map(|rows| rows.iter().map(|row| keys.group(row)).collect())
+                .collect();
+            assert!(groups[0].is_disjoint(&groups[1]));
+            if by == SplitBy::Host {
+                // the broker is never taken for the client
+                assert!(!groups[0].contains("10.0.0.100"));
+                assert_eq!(groups[0].len() + groups[1].len(), 20);
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn time_splits_keep_training_rows_first() {
+        let dir = temp_dir("split-time");
+        let dataset = dataset(&dir);
+        let keys = Keys::new(SplitBy::Time, &columns()).unwrap();
+        let splits = split(&dataset, SplitBy::Time);
+
+        assert_eq!((splits[0].len(), splits[1].len()), (160, 40));
+        let last_train = splits[0].iter().map(|row| keys.ts(row)).max().unwrap();
+        let first_test = splits[1].iter().map(|row| keys.ts(row)).min().unwrap();
+        assert!(last_train < first_test);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn similar_keys_spread_over_buckets() {
+        let hosts: Vec<_> = (0..100)
+            .map(|i| bucket(1, &format!("10.0.0.{i}")))
+            .collect();
+        let low = hosts.iter().filter(|&&u| u < 0.5).count();
+        assert!((30..70).contains(&low), "{low} of 100 below 0.5");
+    }
+
+    #[test]
+    fn buckets_depend_on_the_seed() {
+        let a: Vec<_> = (0..100).map(|i| bucket(1, &i.to_string())).collect();
+        let b: Vec<_> = (0..100).map(|i| bucket(2, &i.to_string())).collect();
+        assert!(a.iter().all(|u| (0.0..1.0).contains(u)));
+        assert_ne!(a, b);
+        assert_eq!(
+            a,
+            (0..100)
+                .map(|i| bucket(1, &i.to_string()))
+                .collect::<Vec<_>>()
+        );
+    }
+}
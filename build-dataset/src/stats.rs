@@ -0,0 +1,533 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use extractor::{ColumnType, Format, Role, TableReader, Value};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::Serialize;
+
+#[derive(clap::Args)]
+pub struct StatsArgs {
+    /// feature file written by header-to-csv or build-dataset, `-` for stdin
+    dataset: PathBuf,
+    /// second feature file to compare the first one against
+    other: Option<PathBuf>,
+    /// input format, overrides the one picked from the file extension
+    #[arg(long)]
+    format: Option<Format>,
+    /// also write the report as JSON to this file; `-` prints only the JSON to stdout
+    #[arg(long)]
+    json: Option<PathBuf>,
+    /// seed for the sample quantiles are computed from
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+}
+
+// quantiles are computed from a uniform sample of at most this many values per column
+const SAMPLE: usize = 100_000;
+
+const QUANTILES: [f64; 5] = [0.05, 0.25, 0.5, 0.75, 0.95];
+
+// indexed by `mqtt_msg_type`, 0 is a row without an MQTT packet
+const MSG_TYPES: [&str; 15] = [
+    "none",
+    "CONNECT",
+    "CONNACK",
+    "PUBLISH",
+    "PUBACK",
+    "PUBREC",
+    "PUBREL",
+    "PUBCOMP",
+    "SUBSCRIBE",
+    "SUBACK",
+    "UNSUBSCRIBE",
+    "UNSUBACK",
+    "PINGREQ",
+    "PINGRESP",
+    "DISCONNECT",
+];
+
+#[derive(Serialize)]
+struct ColumnStats {
+    name: String,
+    #[serde(rename = "type")]
+    ty: ColumnType,
+    role: Role,
+    nulls: u64,
+    null_rate: f64,
+    zeros: u64,
+    zero_rate: f64,
+    min: Option<f64>,
+    max: Option<f64>,
+    mean: Option<f64>,
+    std: Option<f64>,
+    quantiles: BTreeMap<String, f64>,
+    // string columns only
+    distinct: Option<usize>,
+    #[serde(skip)]
+    sample: Vec<f64>,
+}
+
+#[derive(Serialize)]
+struct MsgTypeCount {
+    msg_type: u8,
+    name: String,
+    rows: u64,
+}
+
+#[derive(Serialize)]
+struct Profile {
+    path: PathBuf,
+    rows: u64,
+    columns: Vec<ColumnStats>,
+    mqtt_msg_types: Vec<MsgTypeCount>,
+    // by `attack_class`, or `output` when there is no class column
+    classes: Option<BTreeMap<String, u64>>,
+}
+
+#[derive(Serialize)]
+struct Difference {
+    column: String,
+    mean: (f64, f64),
+    null_rate: (f64, f64),
+    zero_rate: (f64, f64),
+    // difference of the means in pooled standard deviations
+    smd: f64,
+    // two-sample Kolmogorov-Smirnov statistic over the quantile samples
+    ks: f64,
+}
+
+#[derive(Serialize)]
+struct Report {
+    datasets: Vec<Profile>,
+    comparison: Option<Vec<Difference>>,
+}
+
+struct Accumulator {
+    count: u64,
+    nulls: u64,
+    zeros: u64,
+    sum: f64,
+    sum_sq: f64,
+    min: f64,
+    max: f64,
+    sample: Vec<f64>,
+    distinct: HashSet<String>,
+}
+
+impl Accumulator {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            nulls: 0,
+            zeros: 0,
+            sum: 0.0,
+            sum_sq: 0.0,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            sample: Vec::new(),
+            distinct: HashSet::new(),
+        }
+    }
+
+    fn push(&mut self, value: Option<&Value>, rng: &mut ChaCha8Rng) {
+        let v = match value {
+            None => {
+                self.nulls += 1;
+                return;
+            }
+            Some(Value::Str(s)) => {
+                self.count += 1;
+                if !self.distinct.contains(s) {
+                    self.distinct.insert(s.clone());
+                }
+                return;
+            }
+            Some(value) => value.as_f64().unwrap(),
+        };
+
+        self.count += 1;
+        if v == 0.0 {
+            self.zeros += 1;
+        }
+        self.sum += v;
+        self.sum_sq += v * v;
+        self.min = self.min.min(v);
+        self.max = self.max.max(v);
+
+        // reservoir sampling, like build's per class caps
+        match self.sample.len() < SAMPLE {
+            true => self.sample.push(v),
+            false => {
+                let i = rng.gen_range(0..self.count as usize);
+                if i < SAMPLE {
+                    self.sample[i] = v;
+                }
+            }
+        }
+    }
+
+    fn finish(mut self, name: String, ty: ColumnType, role: Role, rows: u64) -> ColumnStats {
+        let rate = |n: u64| match rows {
+            0 => 0.0,
+            rows => n as f64 / rows as f64,
+        };
+        let numeric = ty != ColumnType::Str && self.count > 0;
+        let mean = self.sum / self.count as f64;
+        let var = (self.sum_sq / self.count as f64 - mean * mean).max(0.0);
+
+        self.sample.sort_by(f64::total_cmp);
+        let quantiles = match numeric {
+            true => QUANTILES
+                .iter()
+                .map(|&q| {
+                    (
+                        format!("p{:02}", (q * 100.0) as u32),
+                        quantile(&self.sample, q),
+                    )
+                })
+                .collect(),
+            false => BTreeMap::new(),
+        };
+
+        ColumnStats {
+            name,
+            ty,
+            role,
+            nulls: self.nulls,
+            null_rate: rate(self.nulls),
+            zeros: self.zeros,
+            zero_rate: rate(self.zeros),
+            min: numeric.then_some(self.min),
+            max: numeric.then_some(self.max),
+            mean: numeric.then_some(mean),
+            std: numeric.then_some(var.sqrt()),
+            quantiles,
+            distinct: (ty == ColumnType::Str).then_some(self.distinct.len()),
+            sample: self.sample,
+        }
+    }
+}
+
+// linear interpolation between the closest ranks of a sorted sample
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = q * (sorted.len() - 1) as f64;
+    let (lo, hi) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lo] + (sorted[hi] - sorted[lo]) * (rank - lo as f64)
+}
+
+fn ks(a: &[f64], b: &[f64]) -> f64 {
+    if a.is_empty() || b.is_empty() {
+        return f64::NAN;
+    }
+
+    let (mut i, mut j, mut d) = (0, 0, 0.0f64);
+    while i < a.len() && j < b.len() {
+        let v = a[i].min(b[j]);
+        while i < a.len() && a[i] <= v {
+            i += 1;
+        }
+        while j < b.len() && b[j] <= v {
+            j += 1;
+        }
+        d = d.max((i as f64 / a.len() as f64 - j as f64 / b.len() as f64).abs());
+    }
+
+    d
+}
+
+fn profile(path: &Path, format: Option<Format>, seed: u64) -> anyhow::Result<Profile> {
+    let reader =
+        TableReader::open(path, format).with_context(|| format!("opening {}", path.display()))?;
+    let columns = reader.columns().to_vec();
+    let position = |name: &str| columns.iter().position(|c| c.name == name);
+    let msg_type = position("mqtt_msg_type");
+    let class = position("attack_class").or_else(|| position("output"));
+
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    let mut accumulators: Vec<_> = columns.iter().map(|_| Accumulator::new()).collect();
+    let mut msg_types = BTreeMap::new();
+    let mut classes = BTreeMap::new();
+    let mut rows = 0;
+
+    for row in reader {
+        let row = row.with_context(|| format!("reading {}", path.display()))?;
+        rows += 1;
+
+        for (accumulator, value) in accumulators.iter_mut().zip(&row) {
+            accumulator.push(value.as_ref(), &mut rng);
+        }
+        if let Some(Some(Value::U8(v))) = msg_type.map(|i| &row[i]) {
+            *msg_types.entry(*v).or_default() += 1;
+        }
+        if let Some(i) = class {
+            let class = row[i].as_ref().map_or("".to_string(), |v| v.to_string());
+            *classes.entry(class).or_default() += 1;
+        }
+    }
+
+    Ok(Profile {
+        path: path.to_path_buf(),
+        rows,
+        columns: accumulators
+            .into_iter()
+            .zip(columns)
+            .map(|(a, c)| a.finish(c.name, c.ty, c.role, rows))
+            .collect(),
+        mqtt_msg_types: msg_types
+            .into_iter()
+            .map(|(msg_type, rows)| MsgTypeCount {
+                msg_type,
+                name: MSG_TYPES
+                    .get(msg_type as usize)
+                    .map_or_else(|| "unknown".to_string(), |s| s.to_string()),
+                rows,
+            })
+            .collect(),
+        classes: class.map(|_| classes),
+    })
+}
+
+fn compare(a: &Profile, b: &Profile) -> Vec<Difference> {
+    a.columns
+        .iter()
+        .filter_map(|x| {
+            let y = b.columns.iter().find(|y| y.name == x.name)?;
+            let (mean_x, mean_y) = (x.mean?, y.mean?);
+            let (std_x, std_y) = (x.std?, y.std?);
+            let pooled = ((std_x * std_x + std_y * std_y) / 2.0).sqrt();
+
+            Some(Difference {
+                column: x.name.clone(),
+                mean: (mean_x, mean_y),
+                null_rate: (x.null_rate, y.null_rate),
+                zero_rate: (x.zero_rate, y.zero_rate),
+                smd: match pooled {
+                    0.0 if mean_x == mean_y => 0.0,
+                    pooled => (mean_y - mean_x) / pooled,
+                },
+                ks: ks(&x.sample, &y.sample),
+            })
+        })
+        .collect()
+}
+
+fn num(v: f64) -> String {
+    match v.abs() {
+        _ if v.is_nan() => "-".to_string(),
+        a if v.fract() == 0.0 && a < 1e12 => format!("{v:.0}"),
+        a if !(1e-3..1e6).contains(&a) => format!("{v:.3e}"),
+        _ => format!("{v:.4}"),
+    }
+}
+
+fn percent(rate: f64) -> String {
+    format!("{:.1}%", rate * 100.0)
+}
+
+fn print_profile(out: &mut impl Write, profile: &Profile) -> io::Result<()> {
+    writeln!(out, "{}: {} rows", profile.path.display(), profile.rows)?;
+    writeln!(
+        out,
+        "{:<22} {:>7} {:>7} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11}",
+        "column", "null", "zero", "min", "p5", "p25", "p50", "p75", "p95", "max", "mean"
+    )?;
+    for c in &profile.columns {
+        let q = |name: &str| c.quantiles.get(name).copied().map_or("".to_string(), num);
+        let opt = |v: Option<f64>| v.map_or("".to_string(), num);
+        writeln!(
+            out,
+            "{:<22} {:>7} {:>7} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11} {:>11}",
+            c.name,
+            percent(c.null_rate),
+            percent(c.zero_rate),
+            opt(c.min),
+            q("p05"),
+            q("p25"),
+            q("p50"),
+            q("p75"),
+            q("p95"),
+            opt(c.max),
+            match c.distinct {
+                Some(distinct) => format!("{distinct} distinct"),
+                None => opt(c.mean),
+            },
+        )?;
+    }
+
+    if !profile.mqtt_msg_types.is_empty() {
+        writeln!(out, "\nmqtt_msg_type")?;
+        for t in &profile.mqtt_msg_types {
+            let rate = t.rows as f64 / profile.rows as f64;
+            writeln!(out, "{:>16} {:>10} {:>7}", t.name, t.rows, percent(rate))?;
+        }
+    }
+
+    if let Some(classes) = &profile.classes {
+        writeln!(out, "\nclasses")?;
+        for (class, rows) in classes {
+            let rate = *rows as f64 / profile.rows as f64;
+            writeln!(out, "{class:>16} {rows:>10} {:>7}", percent(rate))?;
+        }
+    }
+
+    writeln!(out)
+}
+
+fn print_comparison(
+    out: &mut impl Write,
+    a: &Path,
+    b: &Path,
+    diffs: &[Difference],
+) -> io::Result<()> {
+    writeln!(out, "{} vs {}", a.display(), b.display())?;
+    writeln!(
+        out,
+        "{:<22} {:>11} {:>11} {:>8} {:>8} {:>8} {:>8} {:>8} {:>6}",
+        "column", "mean a", "mean b", "null a", "null b", "zero a", "zero b", "smd", "ks"
+    )?;
+
+    // most different columns first
+    let mut diffs: Vec<_> = diffs.iter().collect();
+    diffs.sort_by(|x, y| y.ks.total_cmp(&x.ks));
+    for d in diffs {
+        writeln!(
+            out,
+            "{:<22} {:>11} {:>11} {:>8} {:>8} {:>8} {:>8} {:>8.2} {:>6.3}",
+            d.column,
+            num(d.mean.0),
+            num(d.mean.1),
+            percent(d.null_rate.0),
+            percent(d.null_rate.1),
+            percent(d.zero_rate.0),
+            percent(d.zero_rate.1),
+            d.smd,
+            d.ks,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn run(args: StatsArgs) -> anyhow::Result<()> {
+    let mut datasets = vec![profile(&args.dataset, args.format, args.seed)?];
+    if let Some(other) = &args.other {
+        datasets.push(profile(other, args.format, args.seed)?);
+    }
+
+    let comparison = match &datasets[..] {
+        [a, b] => Some(compare(a, b)),
+        _ => None,
+    };
+    let report = Report {
+        datasets,
+        comparison,
+    };
+
+    let stdout = &mut io::stdout().lock();
+    match args.json.as_ref().and_then(|p| p.to_str()) {
+        Some("-") => {
+            serde_json::to_writer_pretty(&mut *stdout, &report)?;
+            writeln!(stdout)?;
+        }
+        _ => {
+            for profile in &report.datasets {
+                print_profile(stdout, profile)?;
+            }
+            if let (Some(diffs), [a, b]) = (&report.comparison, &report.datasets[..]) {
+                print_comparison(stdout, &a.path, &b.path, diffs)?;
+            }
+            if let Some(path) = &args.json {
+                let file =
+                    File::create(path).with_context(|| format!("creating {}", path.display()))?;
+                serde_json::to_writer_pretty(BufWriter::new(file), &report)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+
+    use extractor::{Column, OutputOptions};
+
+    use super::*;
+
+    #[test]
+    fn quantiles_interpolate_and_ks_measures_the_gap() {
+        let sorted = [0.0, 10.0, 20.0, 30.0];
+        assert_eq!(quantile(&sorted, 0.0), 0.0);
+        assert_eq!(quantile(&sorted, 0.5), 15.0);
+        assert_eq!(quantile(&sorted, 1.0), 30.0);
+        assert!(quantile(&[], 0.5).is_nan());
+
+        assert_eq!(ks(&sorted, &sorted), 0.0);
+        assert_eq!(ks(&[0.0, 1.0], &[2.0, 3.0]), 1.0);
+        assert_eq!(ks(&[0.0, 2.0], &[1.0, 3.0]), 0.5);
+    }
+
+    #[test]
+    fn profiles_columns_message_types_and_classes() {
+        let dir = std::env::temp_dir().join(format!("build-dataset-stats-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("x.csv");
+        let columns = [
+            Column::new("mqtt_msg_type", ColumnType::U8, Role::Feature),
+            Column::new("tcp_len", ColumnType::U64, Role::Feature),
+            Column::new("attack_class", ColumnType::Str, Role::Label),
+        ];
+        let mut sink = extractor::create_sink(&path, &columns, &OutputOptions::default()).unwrap();
+        for (msg_type, len, class) in [(3, 0, "benign"), (3, 10, "dos"), (12, 20, "dos")] {
+            let row = [
+                Value::U8(msg_type),
+                Value::U64(len),
+                Value::Str(class.to_string()),
+            ];
+            sink.write(&row).unwrap();
+        }
+        sink.finish().unwrap();
+        // a row missing its length
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"1,,benign\n").unwrap();
+
+        let profile = profile(&path, None, 1).unwrap();
+        assert_eq!(profile.rows, 4);
+        let msg_types: Vec<_> = profile
+            .mqtt_msg_types
+            .iter()
+            .map(|m| (m.name.as_str(), m.rows))
+            .collect();
+        assert_eq!(msg_types, [("CONNECT", 1), ("PUBLISH", 2), ("PINGREQ", 1)]);
+        let classes = profile.classes.clone().unwrap();
+        assert_eq!(
+            classes,
+            BTreeMap::from([("benign".into(), 2), ("dos".into(), 2)])
+        );
+
+        let len = &profile.columns[1];
+        assert_eq!((len.nulls, len.zeros), (1, 1));
+        assert_eq!(
+            (len.min, len.max, len.mean),
+            (Some(0.0), Some(20.0), Some(10.0))
+        );
+        assert_eq!(len.quantiles["p50"], 10.0);
+        assert_eq!(profile.columns[2].distinct, Some(2));
+
+        // a dataset doesn't differ from itself
+        for difference in compare(&profile, &profile) {
+            assert_eq!((difference.smd, difference.ks), (0.0, 0.0));
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,38 +1,46 @@
-use std::collections::VecDeque;
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use anyhow::anyhow;
-use bytes::BytesMut;
-use etherparse::{InternetSlice, SlicedPacket};
-use mqttbytes::v4::{Packet, SubscribeReasonCode};
-use pcap::{Capture, Device};
-use serde::Serialize;
+use clap::Parser;
+use extractor::{
+    parse_frame, Extractor, FeatureState, Features, FlowLimits, JsonRow, Limit, Packet, RowPolicy,
+    Rules, Skipped, MAX_SNAPLEN, SCHEMA_VERSION,
+};
+use pcap::{Capture, Device, Precision};
+
+// set on Ctrl-C, the capture stops at the next packet or read timeout
+static STOP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn stop(_signal: libc::c_int) {
+    STOP.store(true, Ordering::Relaxed);
+}
 
-#[derive(Debug, Default, Clone, Serialize)]
-struct HeadersInfo {
-    packet_len: usize,
-    ip_len: u16,
-    ip_df: bool,
-    ip_mf: bool,
-    ip_ttl: u8,
-    tcp_len: usize,
-    tcp_pdu_size: u8,
-    tcp_ack: bool,
-    tcp_cwr: bool,
-    tcp_ece: bool,
-    tcp_fin: bool,
-    tcp_ns: bool,
-    tcp_push: bool,
-    tcp_reset: bool,
-    tcp_syn: bool,
-    tcp_urg: bool,
-    tcp_src_port: u16,
-    tcp_dst_port: u16,
-    tcp_tdelta: i64,
-    tcp_l20_avg: i64,
-    mqtt_len: usize,
-    mqtt_topic_len: usize,
-    mqtt_msg_type: u8,
-    mqtt_qos_lvl: u8,
+#[derive(Parser)]
+struct Args {
+    /// which packets are scored: mqtt, tcp or ip; match what the training data was extracted with
+    #[arg(long, default_value_t)]
+    rows: RowPolicy,
+    /// JSON feature config, the same one the training data was extracted with; every built-in
+    /// feature when not set
+    #[arg(long)]
+    features: Option<PathBuf>,
+    /// JSON rules checked against every scored row, over any built-in feature and the group and
+    /// derived features of --features; the rules a row sets off are printed with its score
+    #[arg(long)]
+    rules: Option<PathBuf>,
+    /// forget flows idle for this many seconds, or `none`; a long running capture should set
+    /// this, with the same value as the training data was extracted with
+    #[arg(long, default_value = "none")]
+    flow_idle_timeout: Limit<f64>,
+    /// keep state for at most this many flows, forgetting the least recently used, or `none`
+    #[arg(long, default_value = "1048576")]
+    max_flows: Limit<usize>,
+    /// write frames that don't parse to this pcap file; they are skipped and counted either way
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
 }
 
 #[allow(dead_code)]
@@ -41,6 +49,19 @@ fn run() -> anyhow::Result<()> {
 }
 
 fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let features = match &args.features {
+        Some(path) => Features::load(path)?,
+        None => Features::default(),
+    };
+    let rules = match &args.rules {
+        Some(path) => Some(Rules::load(path, &features)?),
+        None => None,
+    };
+    let limits = FlowLimits::default()
+        .idle_timeout(args.flow_idle_timeout.0)
+        .max_entries(args.max_flows.0);
+
     let mut localhost_device = None;
     for device in Device::list()? {
         if device.name == "lo" {
@@ -50,236 +71,118 @@ fn main() -> anyhow::Result<()> {
     }
 
     let device = localhost_device.ok_or(anyhow!("no lookup device"))?;
-    let mut capture = Capture::from_device(device)?.timeout(100).open()?;
+    // tv_usec holds nanoseconds from here on
+    let mut capture = Capture::from_device(device)?
+        .timeout(100)
+        .precision(Precision::Nano)
+        .open()?;
 
+    let link_type = capture.get_datalink().0 as u32;
     let agent = ureq::agent();
+    let mut skipped = Skipped::new(args.quarantine.clone());
 
     println!("starting capture");
 
-    let mut packet = loop {
-        match capture.next_packet() {
-            Err(pcap::Error::TimeoutExpired) => continue,
-            v => break v?,
-        }
-    };
-
-    let mut ts = packet.header.ts;
-    let mut prev_ts = ts.tv_sec * 1_000_000 + ts.tv_usec;
+    // so the quarantine file is flushed and the totals printed on the way out
+    unsafe { libc::signal(libc::SIGINT, stop as *const () as libc::sighandler_t) };
 
-    let mut tcp_l20_avg = 0;
-    let mut l20_diffs = VecDeque::with_capacity(20);
-    l20_diffs.push_back(0);
-
-    let mut count = 1;
-
-    'outer: loop {
-        let mut info = HeadersInfo::default();
-        info.packet_len = packet.data.len();
-
-        let parsed_packet = SlicedPacket::from_ip(&packet.data[14..])?;
-
-        match parsed_packet.ip {
-            Some(InternetSlice::Ipv4(header, _)) => {
-                info.ip_len = header.total_len();
-                info.ip_df = header.dont_fragment();
-                info.ip_mf = header.more_fragments();
-                info.ip_ttl = header.ttl();
+    let mut score = || -> anyhow::Result<()> {
+        let mut packet = loop {
+            if STOP.load(Ordering::Relaxed) {
+                return Ok(());
             }
-            _ => loop {
-                packet = match capture.next_packet() {
-                    Err(pcap::Error::TimeoutExpired) => continue,
-                    v => v?,
-                };
-
-                // println!("disconn : not ipv4");
-                count += 1;
-
-                continue 'outer;
-            },
-        };
-
-        let ts2 = packet.header.ts;
-        ts = ts2;
-        let curr_ts = ts.tv_sec * 1_000_000 + ts.tv_usec;
-        let diff = curr_ts - prev_ts;
-
-        let len = l20_diffs.len() as i64;
-        if len < 20 {
-            tcp_l20_avg = (tcp_l20_avg * len + diff) / len;
-        } else {
-            tcp_l20_avg = (tcp_l20_avg * 20 - l20_diffs.pop_front().unwrap() + diff) / 20;
-        }
-
-        l20_diffs.push_back(diff);
-        prev_ts = curr_ts;
-
-        match parsed_packet.transport {
-            Some(etherparse::TransportSlice::Tcp(header))
-                if header.source_port() != 8000 && header.destination_port() != 8000 =>
-            {
-                info.tcp_len = header.slice().len();
-                info.tcp_pdu_size = header.data_offset();
-                info.tcp_ack = header.ack();
-                info.tcp_cwr = header.cwr();
-                info.tcp_ece = header.ece();
-                info.tcp_fin = header.fin();
-                info.tcp_ns = header.ns();
-                info.tcp_push = header.psh();
-                info.tcp_reset = header.rst();
-                info.tcp_syn = header.syn();
-                info.tcp_urg = header.urg();
-                info.tcp_src_port = header.source_port();
-                info.tcp_dst_port = header.destination_port();
-                info.tcp_tdelta = diff;
-                info.tcp_l20_avg = tcp_l20_avg;
+            match capture.next_packet() {
+                Err(pcap::Error::TimeoutExpired) => continue,
+                v => break v?,
             }
-            _ => loop {
-                packet = match capture.next_packet() {
-                    Err(pcap::Error::TimeoutExpired) => continue,
-                    v => v?,
-                };
-
-                // println!("disconn : not tcp");
-                count += 1;
-
-                continue 'outer;
-            },
         };
 
-        let buf = &mut BytesMut::from(parsed_packet.payload);
-        let mut mqtt_packet = match mqttbytes::v4::read(buf, 1 << 30) {
-            Ok(p) => p,
-            Err(_) => loop {
-                packet = match capture.next_packet() {
-                    Err(pcap::Error::TimeoutExpired) => continue,
-                    v => v?,
-                };
+        let ts = packet.header.ts;
+        // the scorer listens on 8000, don't feed our own requests back into it
+        let mut extractor = Extractor::new(ts.tv_sec * 1_000_000_000 + ts.tv_usec)
+            .ignore_port(8000)
+            .flow_limits(limits)
+            .time_unit(features.time_unit());
 
-                continue 'outer;
-            },
-        };
+        let mut state = FeatureState::new(&features, limits);
+        let columns = features.columns();
+        // sent with every row, for the scorer to check against what the model was trained on
+        let feature_config = serde_json::to_string(features.config())?;
+        let mut count = 1;
 
         loop {
-            let mut info = info.clone();
-
-            match mqtt_packet {
-                Packet::Connect(conn) => {
-                    info.mqtt_len = conn.len();
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 1;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::ConnAck(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 2;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::Publish(publish) => {
-                    info.mqtt_len = publish.len();
-                    info.mqtt_topic_len = publish.topic.len();
-                    info.mqtt_msg_type = 3;
-                    info.mqtt_qos_lvl = publish.qos as u8;
-                }
-                Packet::PubAck(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 4;
-                    info.mqtt_qos_lvl = 0;
+            let ts = packet.header.ts;
+            let curr_ts = ts.tv_sec * 1_000_000_000 + ts.tv_usec;
+
+            match parse_frame(link_type, packet.data) {
+                Ok(parsed_packet) => {
+                    let extras = state.update(curr_ts, packet.data.len(), &parsed_packet);
+                    let extracted = extractor.extract(curr_ts, packet.data.len(), &parsed_packet);
+                    for (kind, info) in extracted.rows(args.rows) {
+                        let row = JsonRow {
+                            columns: &columns,
+                            values: &features.values(&info, &extras),
+                        };
+                        let response = agent
+                            .post("http://localhost:8000")
+                            .set("content-type", "application/json")
+                            .set("x-feature-schema-version", &SCHEMA_VERSION.to_string())
+                            .set("x-feature-config", &feature_config)
+                            .send_string(&serde_json::to_string(&row)?)?;
+
+                        let fired = match &rules {
+                            Some(rules) => rules.fired(&features, &info, &extras).join(", "),
+                            None => String::new(),
+                        };
+                        let fired = match fired.is_empty() {
+                            true => fired,
+                            false => format!(", rules: {fired}"),
+                        };
+                        println!(
+                            "count: {count} ({kind}{fired})\n{}\n",
+                            response.into_string()?
+                        );
+                    }
                 }
-                Packet::PubRec(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 5;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PubRel(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 6;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PubComp(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 7;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::Subscribe(subscribe) => {
-                    let mqtt_len = subscribe.len();
-                    let filter = subscribe.filters.into_iter().next().unwrap();
-                    info.mqtt_len = mqtt_len;
-                    info.mqtt_topic_len = filter.path.len();
-                    info.mqtt_msg_type = 8;
-                    info.mqtt_qos_lvl = filter.qos as u8;
-                }
-                Packet::SubAck(ack) => {
-                    let mqtt_len = 2 + ack.return_codes.len();
-                    let filter = ack.return_codes.into_iter().next().unwrap();
-                    let mqtt_qos_lvl = match filter {
-                        SubscribeReasonCode::Success(qos) => qos as u8,
-                        _ => 0,
+                Err(e) => {
+                    let frame = Packet {
+                        ts: curr_ts,
+                        nanos: true,
+                        len: packet.header.len,
+                        link_type,
+                        // what libpcap captures with when not told otherwise
+                        snaplen: MAX_SNAPLEN,
+                        data: packet.data.to_vec(),
                     };
-                    info.mqtt_len = mqtt_len;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 9;
-                    info.mqtt_qos_lvl = mqtt_qos_lvl;
-                }
-                Packet::Unsubscribe(ubsub) => {
-                    let mqtt_len = 2 + ubsub.topics.iter().map(|s| s.len() + 2).sum::<usize>();
-                    let filter = ubsub.topics.into_iter().next().unwrap();
-                    info.mqtt_len = mqtt_len;
-                    info.mqtt_topic_len = filter.len();
-                    info.mqtt_msg_type = 10;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::UnsubAck(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 11;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PingReq => {
-                    info.mqtt_len = 0;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 12;
-                    info.mqtt_qos_lvl = 0;
+                    skipped.skip(&frame, e)?;
+                    eprintln!(
+                        "count: {count} skipped, {e} (skipped so far: {})",
+                        skipped.counts()
+                    );
                 }
-                Packet::PingResp => {
-                    info.mqtt_len = 0;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 13;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::Disconnect => {
-                    info.mqtt_len = 0;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 14;
-                    info.mqtt_qos_lvl = 0;
-                }
-            };
-
-            let response = agent
-                .post("http://localhost:8000")
-                .set("content-type", "application/json")
-                .send_string(&serde_json::to_string(&info)?)?;
-
-            println!("count: {count}\n{}\n", response.into_string()?);
+            }
 
-            mqtt_packet = match mqttbytes::v4::read(buf, 1 << 30) {
-                Ok(p) => p,
-                Err(_) => {
-                    break;
+            packet = loop {
+                if STOP.load(Ordering::Relaxed) {
+                    return Ok(());
                 }
+                match capture.next_packet() {
+                    Err(pcap::Error::TimeoutExpired) => continue,
+                    v => break v?,
+                };
             };
+            count += 1;
         }
+    };
 
-        packet = loop {
-            match capture.next_packet() {
-                Err(pcap::Error::TimeoutExpired) => continue,
-                v => break v?,
-            };
-        };
-        count += 1;
+    // the capture stops on Ctrl-C or an error
+    let result = score();
+    let counts = skipped.finish()?;
+    if counts.total() > 0 {
+        eprintln!(
+            "skipped {} frames that don't parse ({counts})",
+            counts.total()
+        );
     }
+    result
 }
@@ -0,0 +1,239 @@
+use std::{
+    collections::BTreeSet,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::{id::fnv1a, Anonymizer, Column, Labeler, RowPolicy, Stream, TimeUnit};
+
+// bumped whenever the saved extraction state changes shape or meaning
+const VERSION: u32 = 6;
+
+#[derive(Deserialize)]
+struct Version {
+    version: u32,
+}
+
+// captures are told apart from other files in the directory by these, after any of `COMPRESSED`
+const EXTENSIONS: [&str; 3] = ["pcap", "pcapng", "cap"];
+const COMPRESSED: [&str; 3] = ["gz", "zst", "xz"];
+
+// What the rows of an incremental extraction depend on besides the captures. A checkpoint only
+// resumes with the same settings, or new captures would get rows made another way than the
+// earlier ones.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Settings {
+    // a different feature config, labels or id columns change them
+    pub columns: Vec<Column>,
+    pub time_unit: TimeUnit,
+    pub rows: RowPolicy,
+    // fingerprints only, a flows file can be large and the anonymization secret has to stay one
+    labeler: Option<u64>,
+    anonymize: Option<u64>,
+}
+
+impl Settings {
+    pub fn new(
+        columns: Vec<Column>,
+        time_unit: TimeUnit,
+        rows: RowPolicy,
+        labeler: Option<&Labeler>,
+        anonymizer: Option<&Anonymizer>,
+    ) -> Self {
+        Self {
+            columns,
+            time_unit,
+            rows,
+            labeler: labeler.map(|labeler| fnv1a(format!("{labeler:?}").bytes())),
+            anonymize: anonymizer.map(|anonymizer| anonymizer.hash(b"checkpoint")),
+        }
+    }
+
+    // what differs from `other`, if anything
+    fn diff(&self, other: &Self) -> Option<&'static str> {
+        if self.columns != other.columns {
+            Some("other columns")
+        } else if self.time_unit != other.time_unit {
+            Some("another time unit")
+        } else if self.rows != other.rows {
+            Some("another row policy")
+        } else if self.labeler != other.labeler {
+            Some("other labels")
+        } else if self.anonymize != other.anonymize {
+            Some("another anonymization secret")
+        } else {
+            None
+        }
+    }
+}
+
+// Progress of an incremental extraction over a directory of captures: the files already done and
+// the extraction state the last one ended with.
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    settings: Settings,
+    files: BTreeSet<String>,
+    pub stream: Option<Stream>,
+}
+
+impl Checkpoint {
+    // a fresh checkpoint when there is no file at `path` yet
+    pub fn load(path: &Path, settings: Settings) -> anyhow::Result<Self> {
+        let json = match fs::read(path) {
+            Ok(json) => json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(Self {
+                    version: VERSION,
+                    settings,
+                    files: BTreeSet::new(),
+                    stream: None,
+                })
+            }
+            Err(e) => return Err(e).with_context(|| format!("opening {}", path.display())),
+        };
+
+        // older state may not parse at all, so look at the version first
+        let version: Version =
+            serde_json::from_slice(&json).with_context(|| format!("parsing {}", path.display()))?;
+        if version.version != VERSION {
+            return Err(anyhow!(
+                "{} was written by another version, start a new checkpoint",
+                path.display()
+            ));
+        }
+        let checkpoint: Self =
+            serde_json::from_slice(&json).with_context(|| format!("parsing {}", path.display()))?;
+
+        if let Some(what) = checkpoint.settings.diff(&settings) {
+            return Err(anyhow!(
+                "{} was written with {what}, start a new checkpoint",
+                path.display()
+            ));
+        }
+        Ok(checkpoint)
+    }
+
+    // written next to `path` first, so a crash never leaves half a checkpoint behind
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        let file = File::create(&tmp).with_context(|| format!("creating {}", tmp.display()))?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        fs::rename(&tmp, path).with_context(|| format!("writing {}", path.display()))?;
+        Ok(())
+    }
+
+    // Captures in `dir` not extracted yet, in name order, which for rolled captures is time
+    // order. Files modified less than `settle` ago may still be written to and are left for later.
+    pub fn pending(&self, dir: &Path, settle: Duration) -> anyhow::Result<Vec<PathBuf>> {
+        let now = SystemTime::now();
+        let mut pending = Vec::new();
+
+        for entry in fs::read_dir(dir).with_context(|| format!("listing {}", dir.display()))? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let metadata = entry.metadata()?;
+            if name.starts_with('.')
+                || !is_capture(&name)
+                || !metadata.is_file()
+                || self.files.contains(&name)
+            {
+                continue;
+            }
+            let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+            if age >= settle {
+                pending.push(entry.path());
+            }
+        }
+
+        pending.sort();
+        Ok(pending)
+    }
+
+    pub fn done(&mut self, path: &Path) {
+        if let Some(name) = path.file_name() {
+            self.files.insert(name.to_string_lossy().into_owned());
+        }
+    }
+}
+
+// by extension, like `a.pcap` or `a.pcapng.zst`
+fn is_capture(name: &str) -> bool {
+    let mut parts = name.rsplit('.');
+    let ext = match parts.next() {
+        Some(ext) if COMPRESSED.contains(&ext) => parts.next(),
+        ext => ext,
+    };
+    // a name without a dot is its own last part
+    name.contains('.') && ext.is_some_and(|ext| EXTENSIONS.contains(&ext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Features;
+
+    fn settings(rows: RowPolicy, secret: Option<&str>) -> Settings {
+        let anonymizer = secret.map(Anonymizer::new);
+        let columns = Features::default().columns();
+        Settings::new(
+            columns,
+            TimeUnit::default(),
+            rows,
+            None,
+            anonymizer.as_ref(),
+        )
+    }
+
+    #[test]
+    fn only_resumes_with_the_same_settings() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("checkpoint.json");
+
+        let mut checkpoint = Checkpoint::load(&path, settings(RowPolicy::Tcp, Some("a"))).unwrap();
+        checkpoint.done(&dir.join("0.pcap"));
+        checkpoint.save(&path).unwrap();
+        // the secret itself isn't saved
+        assert!(!fs::read_to_string(&path).unwrap().contains("\"a\""));
+
+        let checkpoint = Checkpoint::load(&path, settings(RowPolicy::Tcp, Some("a"))).unwrap();
+        assert!(checkpoint.files.contains("0.pcap"));
+        assert!(Checkpoint::load(&path, settings(RowPolicy::Mqtt, Some("a"))).is_err());
+        assert!(Checkpoint::load(&path, settings(RowPolicy::Tcp, Some("b"))).is_err());
+        assert!(Checkpoint::load(&path, settings(RowPolicy::Tcp, None)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pending_only_lists_new_captures() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-pending-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "0.pcap",
+            "1.pcapng.zst",
+            "2.cap.gz",
+            "notes.txt",
+            "pcap",
+            "3.gz",
+            ".4.pcap",
+        ] {
+            File::create(dir.join(name)).unwrap();
+        }
+
+        let mut checkpoint =
+            Checkpoint::load(&dir.join("checkpoint.json"), settings(RowPolicy::Tcp, None)).unwrap();
+        checkpoint.done(&dir.join("0.pcap"));
+        let pending = checkpoint.pending(&dir, Duration::ZERO).unwrap();
+        assert_eq!(pending, [dir.join("1.pcapng.zst"), dir.join("2.cap.gz")]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
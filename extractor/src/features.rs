@@ -0,0 +1,658 @@
+use std::{collections::VecDeque, fs::File, io::BufReader, path::Path};
+
+use anyhow::{anyhow, Context};
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    flow_key, Column, ColumnType, Evictions, FlowKey, FlowLimits, FlowTable, HeadersInfo, Role,
+    TimeUnit, Value,
+};
+
+// how far back window aggregates look, in nanoseconds
+const WINDOW: i64 = 1_000_000_000;
+
+columns! {
+    role: Feature,
+    #[derive(Debug, Clone, Default)]
+    pub struct SessionFeatures {
+        // packets and bytes of the flow so far, this one included
+        pub flow_packets: u64,
+        pub flow_bytes: u64,
+        // since the first packet of the flow
+        pub flow_duration: i64,
+    }
+}
+
+columns! {
+    role: Feature,
+    #[derive(Debug, Clone, Default)]
+    pub struct PayloadFeatures {
+        pub payload_len: usize,
+        // shannon entropy in bits per byte
+        pub payload_entropy: f64,
+        // share of printable ASCII bytes
+        pub payload_printable: f64,
+    }
+}
+
+columns! {
+    role: Feature,
+    #[derive(Debug, Clone, Default)]
+    pub struct WindowFeatures {
+        // IPv4 packets, bytes and bare SYNs over the last second of the capture
+        pub window_packets: u64,
+        pub window_bytes: u64,
+        pub window_syns: u64,
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Group {
+    Session,
+    Payload,
+    Window,
+}
+
+impl Group {
+    fn columns(&self) -> Vec<Column> {
+        match self {
+            Group::Session => SessionFeatures::columns(),
+            Group::Payload => PayloadFeatures::columns(),
+            Group::Window => WindowFeatures::columns(),
+        }
+    }
+}
+
+// A feature config file, e.g.
+//
+// {
+//   "features": ["packet_len", "mqtt_len", "mqtt_msg_type"],
+//   "groups": ["window"],
+//   "derived": [{ "name": "mqtt_share", "expr": "mqtt_len / packet_len" }],
+//   "time_unit": "ns"
+// }
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FeatureConfig {
+    // built-in `HeadersInfo` columns to emit, in this order; all of them when left out
+    pub features: Option<Vec<String>>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+    #[serde(default)]
+    pub derived: Vec<Derived>,
+    // unit of time features (`tcp_tdelta`, `tcp_l20_avg`, `mqtt_idle`, `flow_duration`) and the
+    // `ts` id column: s, ms, us or ns
+    #[serde(default)]
+    pub time_unit: TimeUnit,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Derived {
+    pub name: String,
+    // `+`, `-`, `*`, `/` and parentheses over numbers and the names of built-in, group and
+    // earlier derived columns
+    pub expr: String,
+}
+
+// Which feature columns are written, and how to compute them. The default is every built-in
+// column and nothing else, the way the extractor always wrote them.
+#[derive(Debug, Clone)]
+pub struct Features {
+    // indices into `HeadersInfo::values`
+    selected: Vec<usize>,
+    groups: Vec<Group>,
+    derived: Vec<(String, Expr)>,
+    time_unit: TimeUnit,
+    // what it was made from, with every feature spelled out
+    config: FeatureConfig,
+}
+
+impl Default for Features {
+    fn default() -> Self {
+        Self::new(FeatureConfig::default()).unwrap()
+    }
+}
+
+impl Features {
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let config: FeatureConfig = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parsing {}", path.display()))?;
+
+        Self::new(config).with_context(|| format!("feature config {}", path.display()))
+    }
+
+    pub fn new(config: FeatureConfig) -> anyhow::Result<Self> {
+        let builtin = HeadersInfo::columns();
+        let selected: Vec<usize> = match config.features {
+            None => (0..builtin.len()).collect(),
+            Some(names) => names
+                .iter()
+                .map(|name| {
+                    builtin
+                        .iter()
+                        .position(|c| &c.name == name)
+                        .ok_or_else(|| anyhow!("no built-in feature `{name}`"))
+                })
+                .collect::<anyhow::Result<_>>()?,
+        };
+
+        let mut groups = config.groups;
+        groups.sort();
+        groups.dedup();
+        let canonical = FeatureConfig {
+            features: Some(selected.iter().map(|&i| builtin[i].name.clone()).collect()),
+            groups: groups.clone(),
+            derived: config.derived.clone(),
+            time_unit: config.time_unit,
+        };
+
+        // expressions can refer to anything computed before them
+        let mut names: Vec<_> = builtin
+            .into_iter()
+            .chain(groups.iter().flat_map(Group::columns))
+            .map(|c| c.name)
+            .collect();
+        let mut derived = Vec::new();
+        for Derived { name, expr } in config.derived {
+            if names.contains(&name) {
+                return Err(anyhow!("derived feature `{name}` is already a column"));
+            }
+            let parsed = Parser::new(&expr, &names)
+                .parse()
+                .with_context(|| format!("in derived feature `{name}`"))?;
+            names.push(name.clone());
+            derived.push((name, parsed));
+        }
+
+        Ok(Self {
+            selected,
+            groups,
+            derived,
+            time_unit: config.time_unit,
+            config: canonical,
+        })
+    }
+
+    pub fn time_unit(&self) -> TimeUnit {
+        self.time_unit
+    }
+
+    // The config these features were made from, with the built-in features listed even when the
+    // config left them out. Two `Features` compute the same columns the same way when their
+    // configs are equal.
+    pub fn config(&self) -> &FeatureConfig {
+        &self.config
+    }
+
+    // Built-in columns the features are computed from, the selected ones and whatever derived
+    // features refer to.
+    pub fn builtin_inputs(&self) -> Vec<String> {
+        let builtin = HeadersInfo::columns();
+        let mut used = self.selected.clone();
+        for (_, expr) in &self.derived {
+            expr.vars(&mut used);
+        }
+        used.retain(|&i| i < builtin.len());
+        used.sort();
+        used.dedup();
+
+        used.into_iter().map(|i| builtin[i].name.clone()).collect()
+    }
+
+    pub fn has_group(&self, group: Group) -> bool {
+        self.groups.contains(&group)
+    }
+
+    pub fn has_groups(&self) -> bool {
+        !self.groups.is_empty()
+    }
+
+    pub fn columns(&self) -> Vec<Column> {
+        let builtin = HeadersInfo::columns();
+        let selected = self.selected.iter().map(|&i| builtin[i].clone());
+        let groups = self.groups.iter().flat_map(Group::columns);
+        let derived = self
+            .derived
+            .iter()
+            .map(|(name, _)| Column::new(name, ColumnType::F64, Role::Feature));
+
+        selected.chain(groups).chain(derived).collect()
+    }
+
+    pub fn values(&self, info: &HeadersInfo, extras: &Extras) -> Vec<Value> {
+        let builtin = info.values();
+        let mut values: Vec<_> = self.selected.iter().map(|&i| builtin[i].clone()).collect();
+        let groups = self.group_values(extras);
+        values.extend(groups.iter().cloned());
+
+        let scope = self.scope_of(&builtin, &groups);
+        let derived = &scope[scope.len() - self.derived.len()..];
+        values.extend(derived.iter().map(|&v| Value::F64(v)));
+
+        values
+    }
+
+    // Names expressions can refer to: every built-in column, selected or not, then the group
+    // and derived columns.
+    pub(crate) fn names(&self) -> Vec<String> {
+        HeadersInfo::columns()
+            .into_iter()
+            .chain(self.groups.iter().flat_map(Group::columns))
+            .map(|c| c.name)
+            .chain(self.derived.iter().map(|(name, _)| name.clone()))
+            .collect()
+    }
+
+    // the value of everything in `names`, as expressions see it
+    pub(crate) fn scope(&self, info: &HeadersInfo, extras: &Extras) -> Vec<f64> {
+        self.scope_of(&info.values(), &self.group_values(extras))
+    }
+
+    fn group_values(&self, extras: &Extras) -> Vec<Value> {
+        let mut values = Vec::new();
+        for group in &self.groups {
+            let group = match group {
+                Group::Session => extras.session.as_ref().map(SessionFeatures::values),
+                Group::Payload => extras.payload.as_ref().map(PayloadFeatures::values),
+                Group::Window => extras.window.as_ref().map(WindowFeatures::values),
+            };
+            values.extend(group.expect("feature group enabled but not computed"));
+        }
+        values
+    }
+
+    fn scope_of(&self, builtin: &[Value], groups: &[Value]) -> Vec<f64> {
+        let mut scope: Vec<_> = builtin
+            .iter()
+            .chain(groups)
+            .map(|v| v.as_f64().unwrap_or(0.0))
+            .collect();
+        for (_, expr) in &self.derived {
+            let value = expr.eval(&scope);
+            scope.push(value);
+        }
+        scope
+    }
+}
+
+// feature group values of one packet, for the groups that are enabled
+#[derive(Debug, Clone, Default)]
+pub struct Extras {
+    pub session: Option<SessionFeatures>,
+    pub payload: Option<PayloadFeatures>,
+    pub window: Option<WindowFeatures>,
+}
+
+// State behind the feature groups. Window aggregates span every flow, so when packets are spread
+// over several extractors they are computed in capture order with `window` and the rest with
+// `packet` once a packet reaches its flow's extractor.
+#[derive(Serialize, Deserialize)]
+pub struct FeatureState {
+    time_unit: TimeUnit,
+    session: bool,
+    flows: FlowTable<FlowKey, Flow>,
+    payload: bool,
+    window: Option<Window>,
+}
+
+// packets of the last `WINDOW` nanoseconds, with running sums over them
+#[derive(Serialize, Deserialize, Default)]
+struct Window {
+    packets: VecDeque<(i64, u64, bool)>,
+    bytes: u64,
+    syns: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Flow {
+    first_ts: i64,
+    packets: u64,
+    bytes: u64,
+}
+
+impl FeatureState {
+    pub fn new(features: &Features, limits: FlowLimits) -> Self {
+        Self {
+            time_unit: features.time_unit,
+            session: features.has_group(Group::Session),
+            flows: FlowTable::new(limits),
+            payload: features.has_group(Group::Payload),
+            window: features.has_group(Group::Window).then(Window::default),
+        }
+    }
+
+    // both halves, for a single extractor
+    pub fn update(&mut self, ts: i64, packet_len: usize, parsed: &SlicedPacket) -> Extras {
+        let window = self.window(ts, packet_len, parsed);
+        Extras {
+            window,
+            ..self.packet(ts, packet_len, parsed)
+        }
+    }
+
+    pub fn window(
+        &mut self,
+        ts: i64,
+        packet_len: usize,
+        parsed: &SlicedPacket,
+    ) -> Option<WindowFeatures> {
+        let window = self.window.as_mut()?;
+        if !matches!(parsed.ip, Some(InternetSlice::Ipv4(..))) {
+            return None;
+        }
+
+        let syn = match &parsed.transport {
+            Some(TransportSlice::Tcp(header)) => header.syn() && !header.ack(),
+            _ => false,
+        };
+        window.packets.push_back((ts, packet_len as u64, syn));
+        window.bytes += packet_len as u64;
+        window.syns += syn as u64;
+        while let Some(&(first, len, syn)) = window.packets.front() {
+            if first > ts - WINDOW {
+                break;
+            }
+            window.packets.pop_front();
+            window.bytes -= len;
+            window.syns -= syn as u64;
+        }
+
+        Some(WindowFeatures {
+            window_packets: window.packets.len() as u64,
+            window_bytes: window.bytes,
+            window_syns: window.syns,
+        })
+    }
+
+    pub fn packet(&mut self, ts: i64, packet_len: usize, parsed: &SlicedPacket) -> Extras {
+        let mut extras = Extras::default();
+
+        if let (true, Some(InternetSlice::Ipv4(header, _))) = (self.session, &parsed.ip) {
+            let (src_port, dst_port, closes) = match &parsed.transport {
+                Some(TransportSlice::Tcp(tcp)) => (
+                    tcp.source_port(),
+                    tcp.destination_port(),
+                    tcp.fin() || tcp.rst(),
+                ),
+                _ => (0, 0, false),
+            };
+            let key = flow_key(
+                (header.source_addr(), src_port),
+                (header.destination_addr(), dst_port),
+            );
+            let flow = self.flows.get_or_insert_with(key, ts, || Flow {
+                first_ts: ts,
+                packets: 0,
+                bytes: 0,
+            });
+            flow.packets += 1;
+            flow.bytes += packet_len as u64;
+
+            extras.session = Some(SessionFeatures {
+                flow_packets: flow.packets,
+                flow_bytes: flow.bytes,
+                flow_duration: self.time_unit.from_nanos(ts - flow.first_ts),
+            });
+            if closes {
+                self.flows.close(&key, ts);
+            }
+        }
+
+        if self.payload {
+            extras.payload = Some(payload(parsed.payload));
+        }
+
+        extras
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.flows.evictions()
+    }
+}
+
+fn payload(data: &[u8]) -> PayloadFeatures {
+    if data.is_empty() {
+        return PayloadFeatures::default();
+    }
+
+    let mut counts = [0u32; 256];
+    for &byte in data {
+        counts[byte as usize] += 1;
+    }
+    let len = data.len() as f64;
+    let entropy = counts
+        .iter()
+        .filter(|&&n| n > 0)
+        .map(|&n| {
+            let p = n as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    let printable = data.iter().filter(|b| (0x20..0x7f).contains(*b)).count();
+
+    PayloadFeatures {
+        payload_len: data.len(),
+        payload_entropy: entropy,
+        payload_printable: printable as f64 / len,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Expr {
+    Num(f64),
+    // index into the columns computed so far
+    Var(usize),
+    Neg(Box<Expr>),
+    Op(char, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // division by zero gives 0 rather than a value models can't take
+    pub(crate) fn eval(&self, scope: &[f64]) -> f64 {
+        match self {
+            Expr::Num(v) => *v,
+            Expr::Var(i) => scope[*i],
+            Expr::Neg(e) => -e.eval(scope),
+            Expr::Op(op, a, b) => {
+                let (a, b) = (a.eval(scope), b.eval(scope));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    _ if b == 0.0 => 0.0,
+                    _ => a / b,
+                }
+            }
+        }
+    }
+
+    fn vars(&self, out: &mut Vec<usize>) {
+        match self {
+            Expr::Num(_) => {}
+            Expr::Var(i) => out.push(*i),
+            Expr::Neg(e) => e.vars(out),
+            Expr::Op(_, a, b) => {
+                a.vars(out);
+                b.vars(out);
+            }
+        }
+    }
+}
+
+// recursive descent over `expr := term (('+' | '-') term)*`, `term := factor (('*' | '/')
+// factor)*` and `factor := '-' factor | '(' expr ')' | number | name`
+pub(crate) struct Parser<'a> {
+    rest: &'a str,
+    names: &'a [String],
+}
+
+impl<'a> Parser<'a> {
+    pub(crate) fn new(expr: &'a str, names: &'a [String]) -> Self {
+        Self { rest: expr, names }
+    }
+
+    pub(crate) fn parse(mut self) -> anyhow::Result<Expr> {
+        let expr = self.expr()?;
+        match self.peek() {
+            None => Ok(expr),
+            Some(c) => Err(anyhow!("unexpected `{c}`")),
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.rest = self.rest.trim_start();
+        self.rest.chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let next = self.peek() == Some(c);
+        if next {
+            self.rest = &self.rest[1..];
+        }
+        next
+    }
+
+    fn expr(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.eat(op);
+            expr = Expr::Op(op, Box::new(expr), Box::new(self.term()?));
+        }
+        Ok(expr)
+    }
+
+    fn term(&mut self) -> anyhow::Result<Expr> {
+        let mut expr = self.factor()?;
+        while let Some(op @ ('*' | '/')) = self.peek() {
+            self.eat(op);
+            expr = Expr::Op(op, Box::new(expr), Box::new(self.factor()?));
+        }
+        Ok(expr)
+    }
+
+    fn factor(&mut self) -> anyhow::Result<Expr> {
+        if self.eat('-') {
+            return Ok(Expr::Neg(Box::new(self.factor()?)));
+        }
+        if self.eat('(') {
+            let expr = self.expr()?;
+            return match self.eat(')') {
+                true => Ok(expr),
+                false => Err(anyhow!("missing `)`")),
+            };
+        }
+
+        let token = self.token();
+        if token.is_empty() {
+            return Err(match self.peek() {
+                None => anyhow!("unexpected end of expression"),
+                Some(c) => anyhow!("unexpected `{c}`"),
+            });
+        }
+        // `inf` and `nan` would parse as numbers too, but read as misspelled names
+        if token.starts_with(|c: char| c.is_ascii_digit()) {
+            return match token.parse() {
+                Ok(v) => Ok(Expr::Num(v)),
+                Err(_) => Err(anyhow!("bad number `{token}`")),
+            };
+        }
+        match self.names.iter().position(|name| name == token) {
+            Some(i) => Ok(Expr::Var(i)),
+            None => Err(anyhow!("unknown column `{token}`")),
+        }
+    }
+
+    // a name or a number; numbers can have an exponent with a sign, like `1e-3`
+    fn token(&mut self) -> &'a str {
+        self.peek();
+        let number = self.rest.starts_with(|c: char| c.is_ascii_digit());
+        let mut prev = ' ';
+        let end = self
+            .rest
+            .find(|c: char| {
+                let exponent = number && matches!(c, '+' | '-') && matches!(prev, 'e' | 'E');
+                prev = c;
+                !(c.is_ascii_alphanumeric() || c == '_' || c == '.' || exponent)
+            })
+            .unwrap_or(self.rest.len());
+        let (token, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(expr: &str, scope: &[(&str, f64)]) -> anyhow::Result<f64> {
+        let names: Vec<_> = scope.iter().map(|(name, _)| name.to_string()).collect();
+        let values: Vec<_> = scope.iter().map(|&(_, value)| value).collect();
+        Ok(Parser::new(expr, &names).parse()?.eval(&values))
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[]).unwrap(), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", &[]).unwrap(), 9.0);
+        assert_eq!(eval("8 / 4 / 2", &[]).unwrap(), 1.0);
+        assert_eq!(eval("10 - 4 - 3", &[]).unwrap(), 3.0);
+        assert_eq!(eval("-2 * -3 + 0.5", &[]).unwrap(), 6.5);
+        assert_eq!(eval("a * b - a", &[("a", 2.0), ("b", 5.0)]).unwrap(), 8.0);
+    }
+
+    #[test]
+    fn division_by_zero_is_zero() {
+        assert_eq!(eval("1 / 0", &[]).unwrap(), 0.0);
+        assert_eq!(eval("a / (b - b)", &[("a", 3.0), ("b", 1.0)]).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn rejects_unknown_names_and_bad_numbers() {
+        assert!(eval("packet_len", &[("mqtt_len", 1.0)]).is_err());
+        for expr in [
+            "inf", "nan", "infinity", "NaN", "1x", "1.2.3", "1 +", "(1", "1 2", "*",
+        ] {
+            assert!(eval(expr, &[]).is_err(), "{expr}");
+        }
+        assert_eq!(eval("1e3", &[]).unwrap(), 1000.0);
+        assert_eq!(eval("1e-3 * 2", &[]).unwrap(), 0.002);
+        assert_eq!(eval("2.5E+2-1", &[]).unwrap(), 249.0);
+        assert_eq!(eval("a-1", &[("a", 3.0)]).unwrap(), 2.0);
+    }
+
+    #[test]
+    fn window_sums_follow_the_window() {
+        let features = Features::new(FeatureConfig {
+            groups: vec![Group::Window],
+            ..Default::default()
+        })
+        .unwrap();
+        let mut state = FeatureState::new(&features, FlowLimits::default());
+        let mut push = |ts: i64, syn: bool| {
+            let builder = etherparse::PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64);
+            let builder = match syn {
+                true => builder.tcp(40000, 1883, 1, 1024).syn(),
+                false => builder.tcp(40000, 1883, 1, 1024).ack(1),
+            };
+            let mut data = Vec::new();
+            builder.write(&mut data, &[0; 10]).unwrap();
+            let parsed = SlicedPacket::from_ip(&data).unwrap();
+            let window = state.window(ts, data.len(), &parsed).unwrap();
+            (
+                window.window_packets,
+                window.window_bytes,
+                window.window_syns,
+            )
+        };
+
+        assert_eq!(push(0, true), (1, 50, 1));
+        assert_eq!(push(WINDOW / 2, false), (2, 100, 1));
+        assert_eq!(push(WINDOW, false), (2, 100, 0));
+        assert_eq!(push(3 * WINDOW, true), (1, 50, 1));
+    }
+}
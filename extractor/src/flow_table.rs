@@ -0,0 +1,441 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    hash::Hash,
+    ops::{AddAssign, Sub},
+    str::FromStr,
+};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::secs_to_nanos;
+
+// How much per-flow state a `FlowTable` keeps. Timestamps are nanoseconds of capture time.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowLimits {
+    // entries untouched for this long are dropped
+    pub idle_timeout: Option<i64>,
+    // closed entries are kept this long for stragglers such as the last ACK
+    pub close_linger: i64,
+    // the least recently used entry makes room beyond this many
+    pub max_entries: Option<usize>,
+}
+
+impl Default for FlowLimits {
+    fn default() -> Self {
+        Self {
+            idle_timeout: None,
+            close_linger: 10_000_000_000,
+            max_entries: Some(1 << 20),
+        }
+    }
+}
+
+impl FlowLimits {
+    // `none` lifts the limit
+    pub fn idle_timeout(mut self, secs: Option<f64>) -> Self {
+        self.idle_timeout = secs.map(secs_to_nanos);
+        self
+    }
+
+    pub fn max_entries(mut self, max: Option<usize>) -> Self {
+        self.max_entries = max;
+        self
+    }
+}
+
+// a limit given on the command line, a number or `none`
+#[derive(Debug, Clone, Copy)]
+pub struct Limit<T>(pub Option<T>);
+
+impl<T: FromStr> FromStr for Limit<T>
+where
+    T::Err: std::fmt::Display,
+{
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Limit(None)),
+            s => s
+                .parse()
+                .map(|v| Limit(Some(v)))
+                .map_err(|e| anyhow!("{s}: {e}, expected a number or `none`")),
+        }
+    }
+}
+
+// entries dropped from a table, by reason
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Evictions {
+    pub idle: u64,
+    pub closed: u64,
+    pub lru: u64,
+}
+
+impl Evictions {
+    pub fn total(&self) -> u64 {
+        self.idle + self.closed + self.lru
+    }
+}
+
+impl AddAssign for Evictions {
+    fn add_assign(&mut self, other: Self) {
+        self.idle += other.idle;
+        self.closed += other.closed;
+        self.lru += other.lru;
+    }
+}
+
+impl Sub for Evictions {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            idle: self.idle - other.idle,
+            closed: self.closed - other.closed,
+            lru: self.lru - other.lru,
+        }
+    }
+}
+
+struct Entry<V> {
+    value: V,
+    last_seen: i64,
+    // position in `order`
+    tick: u64,
+    closed_at: Option<i64>,
+}
+
+// Per-flow state with bounded memory. Entries are dropped once idle for longer than the idle
+// timeout, a while after their connection closed, or least recently used first when the table
+// is full. Dropped entries are kept aside for owners that need to clean up after them, see
+// `take_evicted`.
+pub struct FlowTable<K, V> {
+    limits: FlowLimits,
+    entries: HashMap<K, Entry<V>>,
+    // keys by last use, oldest first
+    order: BTreeMap<u64, K>,
+    next_tick: u64,
+    // keys by close time, oldest first; stale once an entry is replaced or dropped
+    closing: VecDeque<(i64, K)>,
+    evictions: Evictions,
+    evicted: Vec<(K, V)>,
+    keep_evicted: bool,
+}
+
+impl<K: Hash + Eq + Clone, V> FlowTable<K, V> {
+    pub fn new(limits: FlowLimits) -> Self {
+        Self {
+            limits,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            next_tick: 0,
+            closing: VecDeque::new(),
+            evictions: Evictions::default(),
+            evicted: Vec::new(),
+            keep_evicted: false,
+        }
+    }
+
+    // hold on to dropped entries until `take_evicted`, instead of dropping them right away
+    pub fn keep_evicted(mut self) -> Self {
+        self.keep_evicted = true;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.evictions
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|entry| &entry.value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, entry)| (key, &entry.value))
+    }
+
+    // marks the entry as used at `now`
+    pub fn get_mut(&mut self, key: &K, now: i64) -> Option<&mut V> {
+        self.expire(now);
+        self.touch(key, now)
+    }
+
+    pub fn get_or_insert_with(&mut self, key: K, now: i64, value: impl FnOnce() -> V) -> &mut V {
+        self.expire(now);
+        if !self.entries.contains_key(&key) {
+            self.add(key.clone(), now, value());
+        }
+        self.touch(&key, now).unwrap()
+    }
+
+    // replaces and returns an entry already under `key`, which doesn't count as an eviction
+    pub fn insert(&mut self, key: K, now: i64, value: V) -> Option<V> {
+        self.expire(now);
+        let old = self.remove(&key);
+        self.add(key, now, value);
+        old
+    }
+
+    fn touch(&mut self, key: &K, now: i64) -> Option<&mut V> {
+        let tick = self.next_tick;
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.order.insert(tick, key.clone());
+        self.next_tick += 1;
+        entry.tick = tick;
+        entry.last_seen = now;
+        Some(&mut entry.value)
+    }
+
+    fn add(&mut self, key: K, now: i64, value: V) {
+        if let Some(max) = self.limits.max_entries {
+            while self.entries.len() >= max.max(1) {
+                let (_, oldest) = self.order.pop_first().unwrap();
+                let entry = self.entries.remove(&oldest).unwrap();
+                self.evictions.lru += 1;
+                self.evict(oldest, entry.value);
+            }
+        }
+
+        let tick = self.next_tick;
+        self.next_tick += 1;
+        self.order.insert(tick, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                last_seen: now,
+                tick,
+                closed_at: None,
+            },
+        );
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        Some(entry.value)
+    }
+
+    // The connection behind `key` closed, e.g. on a FIN or RST. The entry stays around for the
+    // close linger, so packets still in flight find it, unless it is replaced in the meantime.
+    pub fn close(&mut self, key: &K, now: i64) {
+        if let Some(entry) = self.entries.get_mut(key) {
+            if entry.closed_at.is_none() {
+                entry.closed_at = Some(now);
+                self.closing.push_back((now, key.clone()));
+            }
+        }
+    }
+
+    // drops entries past their idle timeout or close linger
+    pub fn expire(&mut self, now: i64) {
+        while let Some(&(closed_at, _)) = self.closing.front() {
+            if now - closed_at < self.limits.close_linger {
+                break;
+            }
+            let (_, key) = self.closing.pop_front().unwrap();
+            // replaced since, or already gone
+            if self.entries.get(&key).and_then(|e| e.closed_at) != Some(closed_at) {
+                continue;
+            }
+            let value = self.remove(&key).unwrap();
+            self.evictions.closed += 1;
+            self.evict(key, value);
+        }
+
+        let Some(timeout) = self.limits.idle_timeout else {
+            return;
+        };
+        while let Some((_, key)) = self.order.first_key_value() {
+            // captures aren't always in order, so the least recently used entry isn't
+            // necessarily the one seen longest ago, but it is close enough
+            if now - self.entries[key].last_seen < timeout {
+                break;
+            }
+            let (_, key) = self.order.pop_first().unwrap();
+            let entry = self.entries.remove(&key).unwrap();
+            self.evictions.idle += 1;
+            self.evict(key, entry.value);
+        }
+    }
+
+    // entries dropped since the last call, with `keep_evicted`
+    pub fn take_evicted(&mut self) -> Vec<(K, V)> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    fn evict(&mut self, key: K, value: V) {
+        if self.keep_evicted {
+            self.evicted.push((key, value));
+        }
+    }
+}
+
+// entries in least recently used order, so the order survives a round trip
+#[derive(Serialize, Deserialize)]
+struct Saved<K, V> {
+    limits: FlowLimits,
+    entries: Vec<(K, V, i64, Option<i64>)>,
+    evictions: Evictions,
+    keep_evicted: bool,
+}
+
+impl<K: Hash + Eq + Clone + Serialize, V: Serialize> Serialize for FlowTable<K, V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let entries = self
+            .order
+            .values()
+            .map(|key| {
+                let entry = &self.entries[key];
+                (key, &entry.value, entry.last_seen, entry.closed_at)
+            })
+            .collect();
+
+        Saved {
+            limits: self.limits,
+            entries,
+            evictions: self.evictions,
+            keep_evicted: self.keep_evicted,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de, K, V> Deserialize<'de> for FlowTable<K, V>
+where
+    K: Hash + Eq + Clone + Deserialize<'de>,
+    V: Deserialize<'de>,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let saved = Saved::<K, V>::deserialize(deserializer)?;
+
+        let mut table = Self::new(saved.limits);
+        table.evictions = saved.evictions;
+        table.keep_evicted = saved.keep_evicted;
+        for (key, value, last_seen, closed_at) in saved.entries {
+            let tick = table.next_tick;
+            table.next_tick += 1;
+            table.order.insert(tick, key.clone());
+            if let Some(closed_at) = closed_at {
+                table.closing.push_back((closed_at, key.clone()));
+            }
+            table.entries.insert(
+                key,
+                Entry {
+                    value,
+                    last_seen,
+                    tick,
+                    closed_at,
+                },
+            );
+        }
+        table.closing.make_contiguous().sort_by_key(|&(at, _)| at);
+
+        Ok(table)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(
+        idle_timeout: Option<i64>,
+        max_entries: Option<usize>,
+    ) -> FlowTable<u32, &'static str> {
+        FlowTable::new(FlowLimits {
+            idle_timeout,
+            close_linger: 10,
+            max_entries,
+        })
+        .keep_evicted()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut table = table(None, Some(2));
+        table.insert(1, 0, "a");
+        table.insert(2, 1, "b");
+        // 1 is now more recent than 2
+        table.get_mut(&1, 2);
+        table.insert(3, 3, "c");
+
+        assert_eq!(table.take_evicted(), [(2, "b")]);
+        assert!(table.get(&1).is_some() && table.get(&3).is_some());
+        table.get_or_insert_with(4, 4, || "d");
+        assert_eq!(table.take_evicted(), [(1, "a")]);
+        assert_eq!(table.evictions().lru, 2);
+        assert!(table.take_evicted().is_empty());
+    }
+
+    #[test]
+    fn evicts_idle_entries() {
+        let mut table = table(Some(100), None);
+        table.insert(1, 0, "a");
+        table.insert(2, 50, "b");
+        table.get_mut(&1, 60);
+
+        table.expire(149);
+        assert!(table.take_evicted().is_empty());
+        table.expire(150);
+        assert_eq!(table.take_evicted(), [(2, "b")]);
+        table.expire(160);
+        assert_eq!(table.take_evicted(), [(1, "a")]);
+        assert_eq!(table.evictions().idle, 2);
+        assert!(table.is_empty());
+    }
+
+    #[test]
+    fn keeps_closed_entries_for_the_linger() {
+        let mut table = table(None, None);
+        table.insert(1, 0, "a");
+        table.insert(2, 0, "b");
+        table.close(&1, 5);
+        table.close(&2, 5);
+        // a new connection under the same key isn't dropped with the old one
+        table.insert(2, 6, "c");
+
+        table.expire(14);
+        assert!(table.get(&1).is_some());
+        table.expire(15);
+        assert_eq!(table.take_evicted(), [(1, "a")]);
+        assert_eq!(table.get(&2), Some(&"c"));
+        assert_eq!(table.evictions().closed, 1);
+    }
+
+    #[test]
+    fn drops_evicted_entries_unless_kept() {
+        let mut table = FlowTable::new(FlowLimits {
+            max_entries: Some(1),
+            ..Default::default()
+        });
+        table.insert(1, 0, "a");
+        table.insert(2, 0, "b");
+        assert!(table.take_evicted().is_empty());
+        assert_eq!(table.evictions().lru, 1);
+    }
+
+    #[test]
+    fn round_trip_keeps_use_order() {
+        let mut table = table(None, Some(2));
+        table.insert(1, 0, "a");
+        table.insert(2, 1, "b");
+        table.get_mut(&1, 2);
+
+        let json = serde_json::to_string(&table).unwrap();
+        let mut table: FlowTable<u32, String> = serde_json::from_str(&json).unwrap();
+        table.insert(3, 3, "c".to_string());
+        assert_eq!(table.take_evicted(), [(2, "b".to_string())]);
+    }
+}
@@ -0,0 +1,171 @@
+use std::net::Ipv4Addr;
+
+use ring::hmac;
+use serde::{Deserialize, Serialize};
+
+use crate::{flow_key, Evictions, FlowKey, FlowLimits, FlowTable, RowKind};
+
+columns! {
+    role: Id,
+    // missing from older CSVs, where they read back empty
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    #[serde(default)]
+    pub struct RowId {
+        pub capture_id: String,
+        pub flow_id: u64,
+        // in the time unit of the feature config
+        pub ts: i64,
+        pub src_ip: String,
+        pub dst_ip: String,
+        // position of the packet within its flow, counting from 0
+        pub packet_index: u64,
+        pub row_kind: RowKind,
+    }
+}
+
+impl RowId {
+    // ports are 0 for packets that aren't TCP
+    pub fn new(
+        capture_id: &str,
+        ts: i64,
+        src: (Ipv4Addr, u16),
+        dst: (Ipv4Addr, u16),
+        packet_index: u64,
+        row_kind: RowKind,
+    ) -> Self {
+        Self {
+            capture_id: capture_id.to_string(),
+            flow_id: flow_id(&flow_key(src, dst)),
+            ts,
+            src_ip: src.0.to_string(),
+            dst_ip: dst.0.to_string(),
+            packet_index,
+            row_kind,
+        }
+    }
+
+    // Replaces both addresses and the flow id with a keyed hash, so rows of one host or flow
+    // still line up without telling who it is. IPv4 addresses are few enough to brute force, so
+    // the key has to stay secret. Rows whose addresses aren't addresses, such as already hashed
+    // ones, are left alone.
+    pub fn anonymize(&mut self, anonymizer: &Anonymizer) {
+        if self.src_ip.parse::<Ipv4Addr>().is_err() {
+            return;
+        }
+        for ip in [&mut self.src_ip, &mut self.dst_ip] {
+            if let Ok(addr) = ip.parse::<Ipv4Addr>() {
+                *ip = format!("{:016x}", anonymizer.hash(&addr.octets()));
+            }
+        }
+        // the plain flow id is a hash of both endpoints anyone can recompute
+        self.flow_id = anonymizer.hash(&self.flow_id.to_be_bytes());
+    }
+}
+
+// HMAC-SHA256 keyed with a secret, cut to 64 bits. Unlike a salted plain hash it can't be
+// inverted or recomputed without the secret.
+pub struct Anonymizer {
+    key: hmac::Key,
+}
+
+impl Anonymizer {
+    pub fn new(secret: &str) -> Self {
+        Self {
+            key: hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes()),
+        }
+    }
+
+    pub fn hash(&self, bytes: &[u8]) -> u64 {
+        let tag = hmac::sign(&self.key, bytes);
+        u64::from_be_bytes(tag.as_ref()[..8].try_into().unwrap())
+    }
+}
+
+// Numbers the packets of each flow in the order they are seen. Non-TCP packets are counted per
+// address pair, with both ports 0. A flow dropped from the table starts over at 0.
+#[derive(Serialize, Deserialize)]
+pub struct PacketIndex {
+    next: FlowTable<FlowKey, u64>,
+}
+
+impl PacketIndex {
+    pub fn new(limits: FlowLimits) -> Self {
+        Self {
+            next: FlowTable::new(limits),
+        }
+    }
+
+    // `closes` for a FIN or RST
+    pub fn next(
+        &mut self,
+        src: (Ipv4Addr, u16),
+        dst: (Ipv4Addr, u16),
+        now: i64,
+        closes: bool,
+    ) -> u64 {
+        let key = flow_key(src, dst);
+        let next = self.next.get_or_insert_with(key, now, || 0);
+        *next += 1;
+        let index = *next - 1;
+        if closes {
+            self.next.close(&key, now);
+        }
+        index
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.next.evictions()
+    }
+}
+
+// FNV-1a over both endpoints, so the id of a connection is the same in every run and build
+pub fn flow_id(key: &FlowKey) -> u64 {
+    let ((a, a_port), (b, b_port)) = key;
+    let bytes = a
+        .octets()
+        .into_iter()
+        .chain(a_port.to_be_bytes())
+        .chain(b.octets())
+        .chain(b_port.to_be_bytes());
+
+    fnv1a(bytes)
+}
+
+pub(crate) fn fnv1a(bytes: impl Iterator<Item = u8>) -> u64 {
+    bytes.fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row() -> RowId {
+        let src = ("10.0.0.1".parse().unwrap(), 40000);
+        let dst = ("10.0.0.2".parse().unwrap(), 1883);
+        RowId::new("a.pcap", 0, src, dst, 0, RowKind::Mqtt)
+    }
+
+    #[test]
+    fn anonymizing_depends_on_the_secret() {
+        let (mut a, mut b, mut c) = (row(), row(), row());
+        a.anonymize(&Anonymizer::new("secret"));
+        b.anonymize(&Anonymizer::new("secret"));
+        c.anonymize(&Anonymizer::new("other"));
+
+        assert_eq!(
+            (&a.src_ip, &a.dst_ip, a.flow_id),
+            (&b.src_ip, &b.dst_ip, b.flow_id)
+        );
+        assert_ne!(a.src_ip, c.src_ip);
+        assert_ne!(a.flow_id, c.flow_id);
+        assert_ne!(a.flow_id, row().flow_id);
+        assert_eq!(a.src_ip.len(), 16);
+
+        // already anonymized rows are left alone
+        let before = a.clone();
+        a.anonymize(&Anonymizer::new("secret"));
+        assert_eq!((a.src_ip, a.flow_id), (before.src_ip, before.flow_id));
+    }
+}
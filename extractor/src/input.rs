@@ -0,0 +1,589 @@
+use std::{
+    borrow::Cow,
+    fmt,
+    fs::File,
+    io::{self, BufReader, Cursor, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyhow::{anyhow, Context};
+use etherparse::{ReadError, SlicedPacket};
+use flate2::read::MultiGzDecoder;
+use pcap_file::{
+    pcapng::{InterfaceDescriptionOption, ParsedBlock},
+    Endianness, PcapNgReader,
+};
+use serde::Serialize;
+use xz2::read::XzDecoder;
+
+// link-layer header types, see https://www.tcpdump.org/linktypes.html
+pub const LINKTYPE_NULL: u32 = 0;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+pub const LINKTYPE_RAW: u32 = 101;
+pub const LINKTYPE_LOOP: u32 = 108;
+pub const LINKTYPE_LINUX_SLL: u32 = 113;
+pub const LINKTYPE_IPV4: u32 = 228;
+pub const LINKTYPE_LINUX_SLL2: u32 = 276;
+
+// the largest snapshot length libpcap captures with, for captures that don't say
+pub const MAX_SNAPLEN: u32 = 262_144;
+
+#[derive(Debug, Clone)]
+pub struct Packet {
+    // nanoseconds since the epoch
+    pub ts: i64,
+    // whether the capture recorded more than microseconds
+    pub nanos: bool,
+    // length on the wire, `data` may be shorter
+    pub len: u32,
+    pub link_type: u32,
+    // longest frame the capture kept, the rest was cut off
+    pub snaplen: u32,
+    pub data: Vec<u8>,
+}
+
+impl Packet {
+    pub fn parse(&self) -> Result<SlicedPacket<'_>, ParseError> {
+        parse_frame(self.link_type, &self.data)
+    }
+}
+
+// Why a frame couldn't be parsed, coarse enough to count by.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum ParseError {
+    // shorter than its link-layer header
+    Runt,
+    // ends inside a header
+    Truncated,
+    UnsupportedLinkType,
+    BadVlan,
+    BadIpVersion,
+    BadIpHeader,
+    BadTcpHeader,
+    Other,
+}
+
+impl ParseError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParseError::Runt => "runt",
+            ParseError::Truncated => "truncated",
+            ParseError::UnsupportedLinkType => "unsupported-link-type",
+            ParseError::BadVlan => "bad-vlan",
+            ParseError::BadIpVersion => "bad-ip-version",
+            ParseError::BadIpHeader => "bad-ip-header",
+            ParseError::BadTcpHeader => "bad-tcp-header",
+            ParseError::Other => "other",
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl From<ReadError> for ParseError {
+    fn from(e: ReadError) -> Self {
+        match e {
+            ReadError::UnexpectedEndOfSlice(_) | ReadError::UnexpectedLenOfSlice { .. } => {
+                ParseError::Truncated
+            }
+            ReadError::DoubleVlanOuterNonVlanEtherType(_) => ParseError::BadVlan,
+            ReadError::IpUnsupportedVersion(_)
+            | ReadError::Ipv4UnexpectedVersion(_)
+            | ReadError::Ipv6UnexpectedVersion(_) => ParseError::BadIpVersion,
+            ReadError::Ipv4HeaderLengthBad(_)
+            | ReadError::Ipv4TotalLengthTooSmall(_)
+            | ReadError::Ipv6TooManyHeaderExtensions
+            | ReadError::Ipv6HopByHopHeaderNotAtStart
+            | ReadError::IpAuthenticationHeaderTooSmallPayloadLength(_) => ParseError::BadIpHeader,
+            ReadError::TcpDataOffsetTooSmall(_) => ParseError::BadTcpHeader,
+            ReadError::IoError(_) | ReadError::Icmpv6PacketTooBig(_) => ParseError::Other,
+        }
+    }
+}
+
+// parses a frame starting at its link-layer header
+pub fn parse_frame(link_type: u32, data: &[u8]) -> Result<SlicedPacket<'_>, ParseError> {
+    let after = |header: usize| data.get(header..).ok_or(ParseError::Runt);
+    let parsed = match link_type {
+        LINKTYPE_ETHERNET => SlicedPacket::from_ethernet(data)?,
+        LINKTYPE_RAW | LINKTYPE_IPV4 => SlicedPacket::from_ip(data)?,
+        LINKTYPE_NULL | LINKTYPE_LOOP => SlicedPacket::from_ip(after(4)?)?,
+        LINKTYPE_LINUX_SLL => SlicedPacket::from_ip(after(16)?)?,
+        LINKTYPE_LINUX_SLL2 => SlicedPacket::from_ip(after(20)?)?,
+        _ => return Err(ParseError::UnsupportedLinkType),
+    };
+
+    Ok(parsed)
+}
+
+type Input = Box<dyn Read + Send>;
+
+// Reads packets out of a pcap or pcapng file, or stdin for `-`. gzip, zstd and xz compressed
+// input is recognized by its magic bytes and decompressed on the fly.
+pub struct PacketReader {
+    format: Format,
+    // bytes consumed from the underlying file, before decompression
+    read: Arc<AtomicU64>,
+}
+
+enum Format {
+    Pcap {
+        input: Input,
+        swapped: bool,
+        nanos: bool,
+        link_type: u32,
+        snaplen: u32,
+    },
+    PcapNg {
+        reader: PcapNgReader<Input>,
+        interfaces: Vec<Interface>,
+        last_ts: i64,
+    },
+}
+
+struct Interface {
+    link_type: u32,
+    snaplen: u32,
+    // timestamp units per second
+    units: u128,
+    offset: i64,
+}
+
+impl PacketReader {
+    pub fn open(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let read = Arc::new(AtomicU64::new(0));
+
+        let raw: Input = match path.to_str() {
+            Some("-") => Box::new(io::stdin()),
+            _ => Box::new(File::open(path).with_context(|| format!("opening {}", path.display()))?),
+        };
+        let raw = Counted {
+            inner: raw,
+            read: read.clone(),
+        };
+
+        let format = Self::format(BufReader::new(raw))
+            .with_context(|| format!("reading {}", path.display()))?;
+
+        Ok(Self { format, read })
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.read.load(Ordering::Relaxed)
+    }
+
+    fn format(input: impl Read + Send + 'static) -> anyhow::Result<Format> {
+        let (magic, input) = peek(input, 6)?;
+        let input: Input = match &magic[..] {
+            [0x1f, 0x8b, ..] => Box::new(MultiGzDecoder::new(input)),
+            [0x28, 0xb5, 0x2f, 0xfd, ..] => Box::new(zstd::Decoder::new(input)?),
+            [0xfd, b'7', b'z', b'X', b'Z', 0x00] => Box::new(XzDecoder::new(input)),
+            _ => Box::new(input),
+        };
+
+        let (magic, input) = peek(input, 4)?;
+        match magic[..] {
+            [0x0a, 0x0d, 0x0d, 0x0a] => Ok(Format::PcapNg {
+                reader: PcapNgReader::new(input)?,
+                interfaces: Vec::new(),
+                last_ts: 0,
+            }),
+            _ => pcap_header(input),
+        }
+    }
+
+    fn next_packet(&mut self) -> anyhow::Result<Option<Packet>> {
+        match &mut self.format {
+            Format::Pcap {
+                input,
+                swapped,
+                nanos,
+                link_type,
+                snaplen,
+            } => {
+                let mut header = [0; 16];
+                if !read_record(input, &mut header)? {
+                    return Ok(None);
+                }
+
+                let field = |i: usize| {
+                    let bytes = header[i * 4..i * 4 + 4].try_into().unwrap();
+                    match swapped {
+                        true => u32::from_be_bytes(bytes),
+                        false => u32::from_le_bytes(bytes),
+                    }
+                };
+                let frac = match nanos {
+                    true => field(1) as i64,
+                    false => field(1) as i64 * 1000,
+                };
+
+                // a corrupt record header could otherwise have us allocate gigabytes up front
+                let caplen = field(2);
+                if caplen > (*snaplen).max(MAX_SNAPLEN) {
+                    return Err(anyhow!(
+                        "packet of {caplen} bytes, more than the snaplen of {snaplen}"
+                    ));
+                }
+                let mut data = Vec::new();
+                input.take(caplen as u64).read_to_end(&mut data)?;
+                if data.len() < caplen as usize {
+                    return Err(anyhow!("truncated packet"));
+                }
+
+                Ok(Some(Packet {
+                    ts: field(0) as i64 * 1_000_000_000 + frac,
+                    nanos: *nanos,
+                    len: field(3),
+                    link_type: *link_type,
+                    snaplen: *snaplen,
+                    data,
+                }))
+            }
+            Format::PcapNg {
+                reader,
+                interfaces,
+                last_ts,
+            } => loop {
+                let block = match reader.next() {
+                    None => return Ok(None),
+                    Some(block) => block?,
+                };
+                let endianness = reader.section().endianness();
+
+                let (interface_id, timestamp, len, data) = match block.parsed()? {
+                    ParsedBlock::SectionHeader(_) => {
+                        interfaces.clear();
+                        continue;
+                    }
+                    ParsedBlock::InterfaceDescription(idb) => {
+                        interfaces.push(Interface::new(
+                            idb.linktype.into(),
+                            idb.snaplen,
+                            &idb.options,
+                        ));
+                        continue;
+                    }
+                    ParsedBlock::EnhancedPacket(epb) => (
+                        epb.interface_id,
+                        Some(epb.timestamp),
+                        epb.original_len,
+                        epb.data,
+                    ),
+                    ParsedBlock::Packet(pb) => (
+                        pb.interface_id as u32,
+                        Some(pb.timestamp),
+                        pb.original_len,
+                        pb.data,
+                    ),
+                    // simple packets carry no timestamp and always belong to the first interface
+                    ParsedBlock::SimplePacket(spb) => (0, None, spb.original_len, spb.data),
+                    _ => continue,
+                };
+
+                let interface = interfaces
+                    .get(interface_id as usize)
+                    .ok_or_else(|| anyhow!("packet from undeclared interface {interface_id}"))?;
+
+                if let Some(timestamp) = timestamp {
+                    // pcap-file reads the two timestamp halves as a single integer, which only
+                    // comes out right in big endian sections
+                    let timestamp = match endianness {
+                        Endianness::Big => timestamp,
+                        Endianness::Little => timestamp.rotate_left(32),
+                    };
+                    *last_ts = interface.nanos(timestamp);
+                }
+
+                return Ok(Some(Packet {
+                    ts: *last_ts,
+                    nanos: interface.units > 1_000_000,
+                    len,
+                    link_type: interface.link_type,
+                    snaplen: interface.snaplen,
+                    data: Cow::into_owned(data),
+                }));
+            },
+        }
+    }
+}
+
+impl Iterator for PacketReader {
+    type Item = anyhow::Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+impl Interface {
+    fn new(link_type: u32, snaplen: u32, options: &[InterfaceDescriptionOption]) -> Self {
+        let mut interface = Self {
+            link_type,
+            // 0 for no limit
+            snaplen: match snaplen {
+                0 => MAX_SNAPLEN,
+                snaplen => snaplen,
+            },
+            units: 1_000_000,
+            offset: 0,
+        };
+
+        for option in options {
+            match option {
+                InterfaceDescriptionOption::IfTsResol(resol) => {
+                    interface.units = match resol & 0x80 {
+                        0 => 10u128.pow(*resol as u32),
+                        _ => 1 << (resol & 0x7f),
+                    };
+                }
+                InterfaceDescriptionOption::IfTsOffset(offset) => interface.offset = *offset as i64,
+                _ => {}
+            }
+        }
+
+        interface
+    }
+
+    fn nanos(&self, timestamp: u64) -> i64 {
+        (timestamp as u128 * 1_000_000_000 / self.units) as i64 + self.offset * 1_000_000_000
+    }
+}
+
+fn pcap_header(mut input: Input) -> anyhow::Result<Format> {
+    let mut header = [0; 24];
+    input
+        .read_exact(&mut header)
+        .context("not a pcap or pcapng file")?;
+
+    let (swapped, nanos) = match header[..4] {
+        [0xd4, 0xc3, 0xb2, 0xa1] => (false, false),
+        [0x4d, 0x3c, 0xb2, 0xa1] => (false, true),
+        [0xa1, 0xb2, 0xc3, 0xd4] => (true, false),
+        [0xa1, 0xb2, 0x3c, 0x4d] => (true, true),
+        _ => return Err(anyhow!("not a pcap or pcapng file")),
+    };
+
+    let field = |i: usize| {
+        let bytes = header[i..i + 4].try_into().unwrap();
+        match swapped {
+            true => u32::from_be_bytes(bytes),
+            false => u32::from_le_bytes(bytes),
+        }
+    };
+    let network = field(20);
+
+    Ok(Format::Pcap {
+        input,
+        swapped,
+        nanos,
+        // the upper bits carry FCS information
+        link_type: network & 0x0fff_ffff,
+        snaplen: field(16),
+    })
+}
+
+// false on a clean end of file
+fn read_record(input: &mut Input, buf: &mut [u8]) -> anyhow::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match input.read(&mut buf[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(anyhow!("truncated packet header")),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(true)
+}
+
+// reads up to `n` bytes and puts them back in front of the stream
+fn peek(mut input: impl Read + Send + 'static, n: usize) -> io::Result<(Vec<u8>, Input)> {
+    let mut magic = Vec::with_capacity(n);
+    (&mut input).take(n as u64).read_to_end(&mut magic)?;
+
+    Ok((magic.clone(), Box::new(Cursor::new(magic).chain(input))))
+}
+
+struct Counted {
+    inner: Input,
+    read: Arc<AtomicU64>,
+}
+
+impl Read for Counted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::*;
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("input-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn packet(ts: i64, byte: u8) -> Packet {
+        Packet {
+            ts,
+            nanos: true,
+            len: 70,
+            link_type: LINKTYPE_ETHERNET,
+            snaplen: 65535,
+            data: vec![byte; 60],
+        }
+    }
+
+    fn read(path: &Path) -> Vec<Packet> {
+        PacketReader::open(path)
+            .unwrap()
+            .collect::<anyhow::Result<_>>()
+            .unwrap()
+    }
+
+    fn same(a: &Packet, b: &Packet) -> bool {
+        (a.ts, a.nanos, a.len, a.link_type, a.snaplen, &a.data)
+            == (b.ts, b.nanos, b.len, b.link_type, b.snaplen, &b.data)
+    }
+
+    #[test]
+    fn reads_compressed_pcaps() {
+        let dir = temp_dir("compressed");
+        let packets = [
+            packet(1_700_000_000_000_000_001, 1),
+            packet(1_700_000_001_000_000_500, 2),
+        ];
+        let mut writer = crate::pcap_writer(Vec::new(), &packets[0]).unwrap();
+        for packet in &packets {
+            crate::write_packet(&mut writer, packet).unwrap();
+        }
+        let pcap = writer.into_writer();
+
+        let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gz.write_all(&pcap).unwrap();
+        let mut xz = xz2::write::XzEncoder::new(Vec::new(), 6);
+        xz.write_all(&pcap).unwrap();
+        let files = [
+            ("x.pcap", pcap.clone()),
+            ("x.pcap.gz", gz.finish().unwrap()),
+            ("x.pcap.zst", zstd::encode_all(&pcap[..], 0).unwrap()),
+            ("x.pcap.xz", xz.finish().unwrap()),
+        ];
+        for (name, bytes) in files {
+            let path = dir.join(name);
+            fs::write(&path, &bytes).unwrap();
+            let read = read(&path);
+            assert_eq!(read.len(), packets.len(), "{name}");
+            assert!(read.iter().zip(&packets).all(|(a, b)| same(a, b)), "{name}");
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // a little endian pcapng block, padded, with its length on both ends
+    fn block(ty: u32, body: &[u8]) -> Vec<u8> {
+        let mut body = body.to_vec();
+        body.resize(body.len().next_multiple_of(4), 0);
+        let len = (body.len() as u32 + 12).to_le_bytes();
+        [&ty.to_le_bytes()[..], &len, &body, &len].concat()
+    }
+
+    fn idb(link_type: u16, snaplen: u32, tsresol: Option<u8>) -> Vec<u8> {
+        let mut body = [
+            &link_type.to_le_bytes()[..],
+            &[0, 0],
+            &snaplen.to_le_bytes(),
+        ]
+        .concat();
+        if let Some(resol) = tsresol {
+            body.extend([9, 0, 1, 0, resol, 0, 0, 0]);
+        }
+        body.extend([0; 4]);
+        block(1, &body)
+    }
+
+    fn epb(interface: u32, timestamp: u64, len: u32, data: &[u8]) -> Vec<u8> {
+        let body = [
+            &interface.to_le_bytes()[..],
+            &((timestamp >> 32) as u32).to_le_bytes(),
+            &(timestamp as u32).to_le_bytes(),
+            &(data.len() as u32).to_le_bytes(),
+            &len.to_le_bytes(),
+            data,
+        ]
+        .concat();
+        block(6, &body)
+    }
+
+    #[test]
+    fn reads_pcapng_with_several_interfaces() {
+        let dir = temp_dir("pcapng");
+        let shb = block(
+            0x0a0d0d0a,
+            &[&0x1a2b3c4du32.to_le_bytes()[..], &[1, 0, 0, 0], &[0xff; 8]].concat(),
+        );
+        let bytes = [
+            shb,
+            // microseconds by default, and no snaplen
+            idb(1, 0, None),
+            idb(101, 1500, Some(9)),
+            epb(0, 1_700_000_000_000_005, 80, &[1; 60]),
+            epb(1, 1_700_000_000_000_000_007, 40, &[2; 40]),
+            epb(0, 1_700_000_001_000_000, 62, &[3; 62]),
+        ]
+        .concat();
+        let path = dir.join("x.pcapng.zst");
+        fs::write(&path, zstd::encode_all(&bytes[..], 0).unwrap()).unwrap();
+
+        let read = read(&path);
+        let summary: Vec<_> = read
+            .iter()
+            .map(|p| (p.ts, p.nanos, p.len, p.link_type, p.snaplen, p.data.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (1_700_000_000_000_005_000, false, 80, 1, MAX_SNAPLEN, 60),
+                (1_700_000_000_000_000_007, true, 40, 101, 1500, 40),
+                (1_700_000_001_000_000_000, false, 62, 1, MAX_SNAPLEN, 62),
+            ]
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_packets_from_undeclared_interfaces() {
+        let dir = temp_dir("undeclared");
+        let shb = block(
+            0x0a0d0d0a,
+            &[&0x1a2b3c4du32.to_le_bytes()[..], &[1, 0, 0, 0], &[0xff; 8]].concat(),
+        );
+        let bytes = [shb, idb(1, 0, None), epb(1, 0, 60, &[0; 60])].concat();
+        let path = dir.join("x.pcapng");
+        fs::write(&path, bytes).unwrap();
+
+        let mut reader = PacketReader::open(&path).unwrap();
+        assert!(reader.next().unwrap().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
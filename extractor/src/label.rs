@@ -0,0 +1,328 @@
+use std::{fs::File, io::BufReader, net::Ipv4Addr, path::Path, str::FromStr};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::secs_to_nanos;
+
+columns! {
+    role: Label,
+    #[derive(Serialize, Debug, Clone)]
+    pub struct Label {
+        pub output: u8,
+        pub attack_class: String,
+    }
+}
+
+impl Label {
+    pub fn benign() -> Self {
+        Self {
+            output: 0,
+            attack_class: "benign".to_string(),
+        }
+    }
+}
+
+// `10.0.0.5`, `10.0.0.5:40000` or `*:1883`
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Endpoint {
+    pub ip: Option<Ipv4Addr>,
+    pub port: Option<u16>,
+}
+
+impl Endpoint {
+    fn matches(&self, (ip, port): (Ipv4Addr, u16)) -> bool {
+        self.ip.is_none_or(|v| v == ip) && self.port.is_none_or(|v| v == port)
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, port) = match s.split_once(':') {
+            Some((ip, port)) => (ip, Some(port.parse()?)),
+            None => (s, None),
+        };
+        let ip = match ip {
+            "*" | "" => None,
+            ip => Some(ip.parse()?),
+        };
+
+        Ok(Self { ip, port })
+    }
+}
+
+impl TryFrom<String> for Endpoint {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// `START..END` in unix seconds, fractions allowed
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct Window {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl Window {
+    pub fn contains(&self, ts: i64) -> bool {
+        self.start <= ts && ts < self.end
+    }
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once("..")
+            .ok_or_else(|| anyhow!("time window should look like START..END, got {s}"))?;
+
+        Ok(Self {
+            start: secs_to_nanos(start.parse()?),
+            end: secs_to_nanos(end.parse()?),
+        })
+    }
+}
+
+impl TryFrom<String> for Window {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+// one entry of a sidecar flows file; flows match in both directions and unset fields match anything
+#[derive(Deserialize, Debug, Clone)]
+pub struct FlowLabel {
+    pub src: Option<Ipv4Addr>,
+    pub src_port: Option<u16>,
+    pub dst: Option<Ipv4Addr>,
+    pub dst_port: Option<u16>,
+    pub start: Option<f64>,
+    pub end: Option<f64>,
+    pub label: u8,
+    pub attack_class: Option<String>,
+}
+
+impl FlowLabel {
+    fn matches(&self, ts: i64, src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) -> bool {
+        let a = Endpoint {
+            ip: self.src,
+            port: self.src_port,
+        };
+        let b = Endpoint {
+            ip: self.dst,
+            port: self.dst_port,
+        };
+
+        let in_time = self.start.is_none_or(|v| secs_to_nanos(v) <= ts)
+            && self.end.is_none_or(|v| ts < secs_to_nanos(v));
+
+        in_time && ((a.matches(src) && b.matches(dst)) || (a.matches(dst) && b.matches(src)))
+    }
+}
+
+// Rules are tried in order: a constant label, then sidecar flows, then attacker endpoints and
+// time windows. When both attackers and windows are given a packet has to match both.
+#[derive(Debug, Clone)]
+pub struct Labeler {
+    constant: Option<u8>,
+    class: String,
+    flows: Vec<FlowLabel>,
+    attackers: Vec<Endpoint>,
+    windows: Vec<Window>,
+}
+
+impl Default for Labeler {
+    fn default() -> Self {
+        Self {
+            constant: None,
+            class: "attack".to_string(),
+            flows: Vec::new(),
+            attackers: Vec::new(),
+            windows: Vec::new(),
+        }
+    }
+}
+
+impl Labeler {
+    pub fn constant(mut self, label: u8) -> Self {
+        self.constant = Some(label);
+        self
+    }
+
+    // attack_class given to malicious rows that don't get one from a flows file
+    pub fn class(mut self, class: impl Into<String>) -> Self {
+        self.class = class.into();
+        self
+    }
+
+    pub fn attacker(mut self, endpoint: Endpoint) -> Self {
+        self.attackers.push(endpoint);
+        self
+    }
+
+    pub fn window(mut self, window: Window) -> Self {
+        self.windows.push(window);
+        self
+    }
+
+    pub fn flows(mut self, flows: impl IntoIterator<Item = FlowLabel>) -> Self {
+        self.flows.extend(flows);
+        self
+    }
+
+    pub fn flows_file(self, path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let flows: Vec<FlowLabel> = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parsing {}", path.display()))?;
+
+        Ok(self.flows(flows))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.constant.is_none()
+            && self.flows.is_empty()
+            && self.attackers.is_empty()
+            && self.windows.is_empty()
+    }
+
+    pub fn label(&self, ts: i64, src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) -> Label {
+        if let Some(output) = self.constant {
+            return self.make(output, None);
+        }
+
+        if let Some(flow) = self.flows.iter().find(|f| f.matches(ts, src, dst)) {
+            return self.make(flow.label, flow.attack_class.as_deref());
+        }
+
+        if self.attackers.is_empty() && self.windows.is_empty() {
+            return Label::benign();
+        }
+
+        let by_addr = self.attackers.is_empty()
+            || self
+                .attackers
+                .iter()
+                .any(|a| a.matches(src) || a.matches(dst));
+        let by_time = self.windows.is_empty() || self.windows.iter().any(|w| w.contains(ts));
+
+        match by_addr && by_time {
+            true => self.make(1, None),
+            false => Label::benign(),
+        }
+    }
+
+    fn make(&self, output: u8, class: Option<&str>) -> Label {
+        match output {
+            0 => Label::benign(),
+            _ => Label {
+                output,
+                attack_class: class.unwrap_or(&self.class).to_string(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEC: i64 = 1_000_000_000;
+
+    fn ep(s: &str) -> (Ipv4Addr, u16) {
+        let (ip, port) = s.split_once(':').unwrap();
+        (ip.parse().unwrap(), port.parse().unwrap())
+    }
+
+    fn output(labeler: &Labeler, ts: i64, src: &str, dst: &str) -> (u8, String) {
+        let label = labeler.label(ts, ep(src), ep(dst));
+        (label.output, label.attack_class)
+    }
+
+    #[test]
+    fn parses_endpoints_and_windows() {
+        let endpoint: Endpoint = "10.0.0.5:1883".parse().unwrap();
+        assert_eq!(endpoint.ip, Some(Ipv4Addr::new(10, 0, 0, 5)));
+        assert_eq!(endpoint.port, Some(1883));
+        let endpoint: Endpoint = "*:1883".parse().unwrap();
+        assert_eq!((endpoint.ip, endpoint.port), (None, Some(1883)));
+        assert!("10.0.0.256".parse::<Endpoint>().is_err());
+
+        let window: Window = "100.5..200".parse().unwrap();
+        assert_eq!((window.start, window.end), (100 * SEC + SEC / 2, 200 * SEC));
+        assert!(window.contains(150 * SEC) && !window.contains(200 * SEC));
+        assert!("100-200".parse::<Window>().is_err());
+    }
+
+    #[test]
+    fn attackers_and_windows_both_have_to_match() {
+        let labeler = Labeler::default()
+            .class("dos")
+            .attacker("10.0.0.5".parse().unwrap())
+            .window("100..200".parse().unwrap());
+        let benign = (0, "benign".to_string());
+
+        // either direction
+        assert_eq!(
+            output(&labeler, 150 * SEC, "10.0.0.5:40000", "10.0.0.1:1883"),
+            (1, "dos".to_string())
+        );
+        assert_eq!(
+            output(&labeler, 150 * SEC, "10.0.0.1:1883", "10.0.0.5:40000"),
+            (1, "dos".to_string())
+        );
+        assert_eq!(
+            output(&labeler, 250 * SEC, "10.0.0.5:40000", "10.0.0.1:1883"),
+            benign
+        );
+        assert_eq!(
+            output(&labeler, 150 * SEC, "10.0.0.6:40000", "10.0.0.1:1883"),
+            benign
+        );
+    }
+
+    #[test]
+    fn constant_then_flows_then_attackers() {
+        let flows: Vec<FlowLabel> = serde_json::from_str(
+            r#"[
+                {"src": "10.0.0.7", "dst_port": 1883, "label": 1, "attack_class": "bruteforce"},
+                {"src": "10.0.0.8", "end": 100, "label": 0}
+            ]"#,
+        )
+        .unwrap();
+        let labeler = Labeler::default()
+            .flows(flows)
+            .attacker("*:1883".parse().unwrap());
+
+        assert_eq!(
+            output(&labeler, 0, "10.0.0.1:1883", "10.0.0.7:40000"),
+            (1, "bruteforce".to_string())
+        );
+        // the flow says benign before the attacker rule gets a say, but only until its end
+        assert_eq!(
+            output(&labeler, 50 * SEC, "10.0.0.8:40000", "10.0.0.1:1883"),
+            (0, "benign".to_string())
+        );
+        assert_eq!(
+            output(&labeler, 150 * SEC, "10.0.0.8:40000", "10.0.0.1:1883"),
+            (1, "attack".to_string())
+        );
+
+        let labeler = labeler.constant(0);
+        assert_eq!(
+            output(&labeler, 0, "10.0.0.1:1883", "10.0.0.7:40000"),
+            (0, "benign".to_string())
+        );
+        assert!(!labeler.is_empty() && Labeler::default().is_empty());
+    }
+}
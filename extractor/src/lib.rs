@@ -0,0 +1,412 @@
+use std::{collections::HashSet, net::Ipv4Addr};
+
+use bytes::BytesMut;
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use mqttbytes::v4::{self, SubscribeReasonCode};
+use serde::{Deserialize, Serialize};
+
+#[macro_use]
+mod schema;
+
+mod checkpoint;
+mod features;
+mod flow_table;
+mod id;
+mod input;
+mod label;
+mod offline;
+mod output;
+mod policy;
+mod reorder;
+mod rules;
+mod session;
+mod skipped;
+mod table;
+mod timing;
+mod topic;
+
+pub use checkpoint::{Checkpoint, Settings};
+pub use features::{
+    Derived, Extras, FeatureConfig, FeatureState, Features, Group, PayloadFeatures,
+    SessionFeatures, WindowFeatures,
+};
+pub use flow_table::{Evictions, FlowLimits, FlowTable, Limit};
+pub use id::{flow_id, Anonymizer, PacketIndex, RowId};
+pub use input::{
+    parse_frame, Packet, PacketReader, ParseError, LINKTYPE_ETHERNET, LINKTYPE_IPV4,
+    LINKTYPE_LINUX_SLL, LINKTYPE_LINUX_SLL2, LINKTYPE_LOOP, LINKTYPE_NULL, LINKTYPE_RAW,
+    MAX_SNAPLEN,
+};
+pub use label::{Endpoint, FlowLabel, Label, Labeler, Window};
+pub use offline::{extract_pcap, extract_pcap_resume, ExtractOptions, ExtractStats, Stream};
+pub use output::{
+    create_sink, pcap_writer, write_packet, Compression, Format, JsonRow, OutputOptions, Sink,
+};
+pub use policy::{RowKind, RowPolicy};
+pub use reorder::Reorder;
+pub use rules::{Condition, RuleConfig, Rules};
+pub use schema::{Column, ColumnType, FeatureSchema, Role, Value, SCHEMA_VERSION};
+pub use session::{flow_key, FlowKey, Session, Sessions};
+pub use skipped::{SkipCounts, Skipped};
+pub use table::TableReader;
+pub use timing::{secs_to_nanos, Delta, TimeUnit, Timing};
+
+columns! {
+    role: Feature,
+    // columns missing from older CSVs read back as their default
+    #[derive(Serialize, Deserialize, Debug, Clone, Default)]
+    #[serde(default)]
+    pub struct HeadersInfo {
+        pub packet_len: usize,
+        pub ip_len: u16,
+        pub ip_df: bool,
+        pub ip_mf: bool,
+        pub ip_ttl: u8,
+        pub tcp_len: usize,
+        pub tcp_pdu_size: u8,
+        pub tcp_ack: bool,
+        pub tcp_cwr: bool,
+        pub tcp_ece: bool,
+        pub tcp_fin: bool,
+        pub tcp_ns: bool,
+        pub tcp_push: bool,
+        pub tcp_reset: bool,
+        pub tcp_syn: bool,
+        pub tcp_urg: bool,
+        pub tcp_src_port: u16,
+        pub tcp_dst_port: u16,
+        pub tcp_tdelta: i64,
+        pub tcp_l20_avg: i64,
+        pub mqtt_len: usize,
+        pub mqtt_topic_len: usize,
+        pub mqtt_msg_type: u8,
+        pub mqtt_qos_lvl: u8,
+        pub mqtt_topic_levels: usize,
+        pub mqtt_topic_sys: bool,
+        pub mqtt_topic_wildcard: bool,
+        pub mqtt_topic_invalid: bool,
+        pub mqtt_topic_seen: bool,
+        pub mqtt_keep_alive: u16,
+        pub mqtt_idle: i64,
+        pub mqtt_idle_ratio: f64,
+        pub mqtt_src_sessions: usize,
+    }
+}
+
+pub enum Extracted {
+    NotIpv4,
+    NotTcp(HeadersInfo),
+    NotMqtt(RowKind, HeadersInfo),
+    Mqtt(Vec<HeadersInfo>),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Extractor {
+    timing: Timing,
+    unit: TimeUnit,
+    ignored_ports: Vec<u16>,
+    // topics each sending host has used so far
+    seen_topics: FlowTable<Ipv4Addr, HashSet<Vec<u8>>>,
+    sessions: Sessions,
+}
+
+impl Extractor {
+    pub fn new(first_ts: i64) -> Self {
+        Self {
+            timing: Timing::new(first_ts),
+            unit: TimeUnit::default(),
+            ignored_ports: Vec::new(),
+            seen_topics: FlowTable::new(FlowLimits::default()),
+            sessions: Sessions::default(),
+        }
+    }
+
+    // TCP segments to or from an ignored port are treated like non-TCP packets
+    pub fn ignore_port(mut self, port: u16) -> Self {
+        self.ignored_ports.push(port);
+        self
+    }
+
+    // timestamps are in nanoseconds, timing features are written in `unit`
+    pub fn time_unit(mut self, unit: TimeUnit) -> Self {
+        self.timing = self.timing.time_unit(unit);
+        self.unit = unit;
+        self
+    }
+
+    // bounds the sessions and per-host topics kept around
+    pub fn flow_limits(mut self, limits: FlowLimits) -> Self {
+        self.seen_topics = FlowTable::new(limits);
+        self.sessions = Sessions::new(limits);
+        self
+    }
+
+    // sessions and per-host topic sets dropped to stay within the flow limits
+    pub fn evictions(&self) -> (Evictions, Evictions) {
+        (self.sessions.evictions(), self.seen_topics.evictions())
+    }
+
+    // open MQTT sessions, keyed by connection
+    pub fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    pub fn extract(&mut self, ts: i64, packet_len: usize, parsed: &SlicedPacket) -> Extracted {
+        if !matches!(parsed.ip, Some(InternetSlice::Ipv4(..))) {
+            return Extracted::NotIpv4;
+        }
+
+        let delta = self.timing.update(ts);
+        let mut info = HeadersInfo {
+            packet_len,
+            ..Default::default()
+        };
+
+        let (src, dst) = match &parsed.ip {
+            Some(InternetSlice::Ipv4(header, _)) => {
+                info.ip_len = header.total_len();
+                info.ip_df = header.dont_fragment();
+                info.ip_mf = header.more_fragments();
+                info.ip_ttl = header.ttl();
+                (header.source_addr(), header.destination_addr())
+            }
+            _ => return Extracted::NotIpv4,
+        };
+
+        let key = match &parsed.transport {
+            Some(TransportSlice::Tcp(header))
+                if !self.ignored_ports.contains(&header.source_port())
+                    && !self.ignored_ports.contains(&header.destination_port()) =>
+            {
+                info.tcp_len = header.slice().len();
+                info.tcp_pdu_size = header.data_offset();
+                info.tcp_ack = header.ack();
+                info.tcp_cwr = header.cwr();
+                info.tcp_ece = header.ece();
+                info.tcp_fin = header.fin();
+                info.tcp_ns = header.ns();
+                info.tcp_push = header.psh();
+                info.tcp_reset = header.rst();
+                info.tcp_syn = header.syn();
+                info.tcp_urg = header.urg();
+                info.tcp_src_port = header.source_port();
+                info.tcp_dst_port = header.destination_port();
+                info.tcp_tdelta = delta.tdelta;
+                info.tcp_l20_avg = delta.l20_avg;
+
+                flow_key(
+                    (src, header.source_port()),
+                    (dst, header.destination_port()),
+                )
+            }
+            _ => return Extracted::NotTcp(info),
+        };
+
+        if let Some(session) = self.sessions.touch(&key, ts) {
+            info.mqtt_keep_alive = session.keep_alive;
+            info.mqtt_idle = self.unit.from_nanos(session.idle(ts));
+            info.mqtt_idle_ratio = session.idle_ratio(ts);
+            info.mqtt_src_sessions = self.sessions.open(session.client);
+        }
+
+        let buf = &mut BytesMut::from(parsed.payload);
+        let mut rows = Vec::new();
+
+        loop {
+            let raw_publish = topic::peek_publish(buf);
+
+            let mut info = info.clone();
+            match (mqttbytes::v4::read(buf, 1 << 30), raw_publish) {
+                (Ok(mqtt_packet), _) => {
+                    // nothing to describe, like a SUBSCRIBE without filters; the packet is
+                    // consumed all the same, so go on with the next one
+                    if self
+                        .fill_mqtt(&mut info, src, key, ts, mqtt_packet)
+                        .is_none()
+                    {
+                        continue;
+                    }
+                }
+                (Err(mqttbytes::Error::TopicNotUtf8), Some(publish)) => {
+                    info.mqtt_len = publish.remaining_len;
+                    info.mqtt_msg_type = 3;
+                    info.mqtt_qos_lvl = publish.qos;
+                    self.fill_topic(&mut info, src, ts, &publish.topic, true);
+                }
+                // mqttbytes takes the whole packet off the buffer before decoding strings, so
+                // only this one is lost, e.g. a SUBSCRIBE or UNSUBSCRIBE with a non-UTF8 filter
+                (Err(mqttbytes::Error::TopicNotUtf8), None) => continue,
+                _ => break,
+            };
+
+            rows.push(info);
+        }
+
+        if info.tcp_fin || info.tcp_reset {
+            self.sessions.close(&key);
+        }
+
+        if !rows.is_empty() {
+            return Extracted::Mqtt(rows);
+        }
+
+        let kind = match parsed.payload.is_empty() {
+            true if info.tcp_syn || info.tcp_fin || info.tcp_reset => RowKind::Handshake,
+            true => RowKind::Ack,
+            false => RowKind::DecodeError,
+        };
+        Extracted::NotMqtt(kind, info)
+    }
+
+    fn fill_topic(
+        &mut self,
+        info: &mut HeadersInfo,
+        src: Ipv4Addr,
+        ts: i64,
+        topic: &[u8],
+        publish: bool,
+    ) {
+        let shape = topic::shape(topic);
+
+        info.mqtt_topic_len = topic.len();
+        info.mqtt_topic_levels = shape.levels;
+        info.mqtt_topic_sys = shape.sys;
+        // wildcards are only legal in subscription filters
+        info.mqtt_topic_wildcard = publish && shape.wildcard;
+        info.mqtt_topic_invalid = shape.invalid;
+        info.mqtt_topic_seen = !self
+            .seen_topics
+            .get_or_insert_with(src, ts, HashSet::new)
+            .insert(topic.to_vec());
+    }
+
+    fn fill_mqtt(
+        &mut self,
+        info: &mut HeadersInfo,
+        src: Ipv4Addr,
+        key: FlowKey,
+        ts: i64,
+        mqtt_packet: v4::Packet,
+    ) -> Option<()> {
+        match mqtt_packet {
+            v4::Packet::Connect(conn) => {
+                info.mqtt_len = conn.len();
+                info.mqtt_msg_type = 1;
+
+                self.sessions.connect(
+                    key,
+                    Session {
+                        client: src,
+                        keep_alive: conn.keep_alive,
+                        last_seen: ts,
+                    },
+                );
+                info.mqtt_keep_alive = conn.keep_alive;
+                info.mqtt_idle = 0;
+                info.mqtt_idle_ratio = 0.0;
+                info.mqtt_src_sessions = self.sessions.open(src);
+            }
+            v4::Packet::ConnAck(_) => {
+                info.mqtt_len = 2;
+                info.mqtt_msg_type = 2;
+            }
+            v4::Packet::Publish(publish) => {
+                info.mqtt_len = publish.len();
+                info.mqtt_msg_type = 3;
+                info.mqtt_qos_lvl = publish.qos as u8;
+                self.fill_topic(info, src, ts, publish.topic.as_bytes(), true);
+            }
+            v4::Packet::PubAck(_) => {
+                info.mqtt_len = 2;
+                info.mqtt_msg_type = 4;
+            }
+            v4::Packet::PubRec(_) => {
+                info.mqtt_len = 2;
+                info.mqtt_msg_type = 5;
+            }
+            v4::Packet::PubRel(_) => {
+                info.mqtt_len = 2;
+                info.mqtt_msg_type = 6;
+            }
+            v4::Packet::PubComp(_) => {
+                info.mqtt_len = 2;
+                info.mqtt_msg_type = 7;
+            }
+            v4::Packet::Subscribe(subscribe) => {
+                info.mqtt_len = subscribe.len();
+                let filter = subscribe.filters.into_iter().next()?;
+                info.mqtt_msg_type = 8;
+                info.mqtt_qos_lvl = filter.qos as u8;
+                self.fill_topic(info, src, ts, filter.path.as_bytes(), false);
+            }
+            v4::Packet::SubAck(ack) => {
+                info.mqtt_len = 2 + ack.return_codes.len();
+                let filter = ack.return_codes.into_iter().next()?;
+                info.mqtt_msg_type = 9;
+                info.mqtt_qos_lvl = match filter {
+                    SubscribeReasonCode::Success(qos) => qos as u8,
+                    _ => 0,
+                };
+            }
+            v4::Packet::Unsubscribe(ubsub) => {
+                info.mqtt_len = 2 + ubsub.topics.iter().map(|s| s.len() + 2).sum::<usize>();
+                let filter = ubsub.topics.into_iter().next()?;
+                info.mqtt_msg_type = 10;
+                self.fill_topic(info, src, ts, filter.as_bytes(), false);
+            }
+            v4::Packet::UnsubAck(_) => {
+                info.mqtt_len = 2;
+                info.mqtt_msg_type = 11;
+            }
+            v4::Packet::PingReq => info.mqtt_msg_type = 12,
+            v4::Packet::PingResp => info.mqtt_msg_type = 13,
+            v4::Packet::Disconnect => {
+                info.mqtt_msg_type = 14;
+                self.sessions.close(&key);
+            }
+        };
+
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+    use mqttbytes::QoS;
+
+    use super::*;
+
+    fn segment(payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        PacketBuilder::ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64)
+            .tcp(40000, 1883, 1, 1024)
+            .write(&mut data, payload)
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn skips_only_packets_with_non_utf8_filters() {
+        // SUBSCRIBE, packet id 1, one filter of two bytes that aren't UTF-8, QoS 0
+        let mut payload = vec![0x82, 7, 0, 1, 0, 2, 0xff, 0xfe, 0];
+        // UNSUBSCRIBE of the same
+        payload.extend([0xa2, 6, 0, 2, 0, 2, 0xff, 0xfe]);
+        let mut publish = BytesMut::new();
+        v4::Publish::new("a/b", QoS::AtMostOnce, "x")
+            .write(&mut publish)
+            .unwrap();
+        payload.extend_from_slice(&publish);
+
+        let data = segment(&payload);
+        let parsed = SlicedPacket::from_ip(&data).unwrap();
+        match Extractor::new(0).extract(0, data.len(), &parsed) {
+            Extracted::Mqtt(rows) => {
+                assert_eq!(rows.len(), 1);
+                assert_eq!(rows[0].mqtt_msg_type, 3);
+                assert_eq!(rows[0].mqtt_topic_levels, 2);
+            }
+            _ => panic!("expected the PUBLISH row"),
+        }
+    }
+}
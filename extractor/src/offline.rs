@@ -0,0 +1,731 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fs,
+    hash::{Hash, Hasher},
+    net::Ipv4Addr,
+    ops::{AddAssign, Sub},
+    path::{Path, PathBuf},
+    sync::mpsc::{sync_channel, Receiver, SyncSender},
+    thread,
+};
+
+use anyhow::anyhow;
+use etherparse::{InternetSlice, SlicedPacket};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::parse_frame, Evictions, Extracted, Extractor, Extras, FeatureState, Features,
+    FlowLimits, Label, Labeler, PacketIndex, PacketReader, Reorder, RowId, RowPolicy, SkipCounts,
+    Skipped, Value, WindowFeatures,
+};
+
+#[derive(Default, Clone)]
+pub struct ExtractOptions<'a> {
+    // labels every row when set
+    pub labeler: Option<&'a Labeler>,
+    // 0 and 1 both extract on the calling thread
+    pub threads: usize,
+    pub rows: RowPolicy,
+    pub features: Features,
+    // how much per-flow state is kept, for sessions, topics, packet counts and flow features
+    pub flows: FlowLimits,
+    // nanoseconds packets are held to put them back in timestamp order, see `Reorder`
+    pub reorder: Option<i64>,
+    // pcap file frames that don't parse are written to, see `Skipped`
+    pub quarantine: Option<PathBuf>,
+}
+
+// What an extraction went through, and the per-flow state it dropped to stay within
+// `ExtractOptions::flows`. Rows of a flow seen again after its state was dropped start over, as
+// if it were a new flow.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractStats {
+    pub packets: u64,
+    // packets that came too late to be put in timestamp order
+    pub late: u64,
+    // frames that didn't parse, which make no rows
+    pub skipped: SkipCounts,
+    pub sessions: Evictions,
+    pub topics: Evictions,
+    pub packet_index: Evictions,
+    pub flow_features: Evictions,
+}
+
+impl ExtractStats {
+    pub fn evictions(&self) -> [(&'static str, Evictions); 4] {
+        [
+            ("sessions", self.sessions),
+            ("topics", self.topics),
+            ("packet index", self.packet_index),
+            ("flow features", self.flow_features),
+        ]
+    }
+}
+
+impl AddAssign for ExtractStats {
+    fn add_assign(&mut self, other: Self) {
+        self.packets += other.packets;
+        self.late += other.late;
+        self.skipped += &other.skipped;
+        self.sessions += other.sessions;
+        self.topics += other.topics;
+        self.packet_index += other.packet_index;
+        self.flow_features += other.flow_features;
+    }
+}
+
+impl Sub for ExtractStats {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        let mut skipped = self.skipped;
+        for (error, count) in other.skipped.0 {
+            *skipped.0.entry(error).or_default() -= count;
+        }
+
+        Self {
+            packets: self.packets - other.packets,
+            late: self.late - other.late,
+            skipped,
+            sessions: self.sessions - other.sessions,
+            topics: self.topics - other.topics,
+            packet_index: self.packet_index - other.packet_index,
+            flow_features: self.flow_features - other.flow_features,
+        }
+    }
+}
+
+// Runs every packet of a capture file (see `PacketReader`) through an `Extractor`, handing each
+// row `options.rows` keeps, where it came from and its label to `emit`, with the feature values
+// `options.features` asks for. With more than one thread, packets are extracted in capture
+// order on the reading thread, since timing, seen topics and open sessions span flows, and
+// sharded over workers by their address pair for per-flow features, labels and the feature
+// values; rows are handed to `emit` in the same order a single thread would.
+pub fn extract_pcap(
+    path: impl AsRef<Path>,
+    options: &ExtractOptions,
+    mut emit: impl FnMut(RowId, Vec<Value>, Option<Label>) -> anyhow::Result<()>,
+) -> anyhow::Result<ExtractStats> {
+    let path = path.as_ref();
+    let mut progress = Progress::new(path);
+    let capture_id = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => "stdin".into(),
+    };
+
+    let mut stats = match options.threads {
+        0 | 1 => extract_sequential(
+            path,
+            &capture_id,
+            options,
+            &mut None,
+            &mut progress,
+            &mut emit,
+        )?,
+        _ => extract_parallel(path, &capture_id, options, &mut progress, &mut emit)?,
+    };
+
+    progress.finish();
+    if progress.packets == 0 {
+        return Err(anyhow!("no packets in pcap file"));
+    }
+    stats.packets = progress.packets;
+    Ok(stats)
+}
+
+// Everything an extraction carries from one packet to the next: timing, sessions, seen topics,
+// packet counts and feature group state.
+#[derive(Serialize, Deserialize)]
+pub struct Stream {
+    extractor: Extractor,
+    packets: PacketIndex,
+    features: FeatureState,
+}
+
+// Like `extract_pcap`, continuing from the state an earlier file left in `stream`, so a capture
+// split over several files is extracted as if it were one. Only runs on one thread, since the
+// state of several workers can't be put back together. A file without packets is no error here,
+// a capture rolled over with nothing in it just adds no rows.
+pub fn extract_pcap_resume(
+    path: impl AsRef<Path>,
+    options: &ExtractOptions,
+    stream: &mut Option<Stream>,
+    mut emit: impl FnMut(RowId, Vec<Value>, Option<Label>) -> anyhow::Result<()>,
+) -> anyhow::Result<ExtractStats> {
+    if options.threads > 1 {
+        return Err(anyhow!("resuming extraction only works on one thread"));
+    }
+
+    let path = path.as_ref();
+    let mut progress = Progress::new(path);
+    let capture_id = match path.file_name() {
+        Some(name) => name.to_string_lossy(),
+        None => "stdin".into(),
+    };
+
+    let mut stats =
+        extract_sequential(path, &capture_id, options, stream, &mut progress, &mut emit)?;
+
+    progress.finish();
+    stats.packets = progress.packets;
+    Ok(stats)
+}
+
+fn addrs(parsed: &SlicedPacket) -> Option<(Ipv4Addr, Ipv4Addr)> {
+    match &parsed.ip {
+        Some(InternetSlice::Ipv4(header, _)) => {
+            Some((header.source_addr(), header.destination_addr()))
+        }
+        _ => None,
+    }
+}
+
+// turns what an extractor made of a packet into rows
+struct Rows<'a> {
+    capture_id: &'a str,
+    options: &'a ExtractOptions<'a>,
+    packets: PacketIndex,
+    features: FeatureState,
+}
+
+impl<'a> Rows<'a> {
+    fn new(capture_id: &'a str, options: &'a ExtractOptions<'a>) -> Self {
+        Self {
+            capture_id,
+            options,
+            packets: PacketIndex::new(options.flows),
+            features: FeatureState::new(&options.features, options.flows),
+        }
+    }
+
+    fn resume(
+        capture_id: &'a str,
+        options: &'a ExtractOptions<'a>,
+        stream: Stream,
+    ) -> (Extractor, Self) {
+        let rows = Self {
+            capture_id,
+            options,
+            packets: stream.packets,
+            features: stream.features,
+        };
+        (stream.extractor, rows)
+    }
+
+    fn suspend(self, extractor: Extractor) -> Stream {
+        Stream {
+            extractor,
+            packets: self.packets,
+            features: self.features,
+        }
+    }
+
+    fn stats(&self, extractor: Option<&Extractor>) -> ExtractStats {
+        let (sessions, topics) = extractor.map(Extractor::evictions).unwrap_or_default();
+        ExtractStats {
+            packets: 0,
+            late: 0,
+            skipped: SkipCounts::default(),
+            sessions,
+            topics,
+            packet_index: self.packets.evictions(),
+            flow_features: self.features.evictions(),
+        }
+    }
+
+    fn emit(
+        &mut self,
+        extracted: Extracted,
+        ts: i64,
+        (src, dst): (Ipv4Addr, Ipv4Addr),
+        extras: &Extras,
+        mut emit: impl FnMut(RowId, Vec<Value>, Option<Label>) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        // every row cut from one packet shares its index
+        let first = match &extracted {
+            Extracted::NotIpv4 => return Ok(()),
+            Extracted::NotTcp(info) | Extracted::NotMqtt(_, info) => info,
+            Extracted::Mqtt(rows) => &rows[0],
+        };
+        let index = self.packets.next(
+            (src, first.tcp_src_port),
+            (dst, first.tcp_dst_port),
+            ts,
+            first.tcp_fin || first.tcp_reset,
+        );
+
+        for (kind, info) in extracted.rows(self.options.rows) {
+            let src = (src, info.tcp_src_port);
+            let dst = (dst, info.tcp_dst_port);
+            let label = self
+                .options
+                .labeler
+                .map(|labeler| labeler.label(ts, src, dst));
+            emit(
+                RowId::new(
+                    self.capture_id,
+                    self.options.features.time_unit().from_nanos(ts),
+                    src,
+                    dst,
+                    index,
+                    kind,
+                ),
+                self.options.features.values(&info, extras),
+                label,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn extract_sequential(
+    path: &Path,
+    capture_id: &str,
+    options: &ExtractOptions,
+    stream: &mut Option<Stream>,
+    progress: &mut Progress,
+    emit: &mut impl FnMut(RowId, Vec<Value>, Option<Label>) -> anyhow::Result<()>,
+) -> anyhow::Result<ExtractStats> {
+    let mut reader = Reorder::new(PacketReader::open(path)?, options.reorder);
+    let mut skipped = Skipped::new(options.quarantine.clone());
+
+    let mut state = stream
+        .take()
+        .map(|stream| Rows::resume(capture_id, options, stream));
+    // evictions carried over from earlier files aren't this file's
+    let before = match &state {
+        Some((extractor, rows)) => rows.stats(Some(extractor)),
+        None => ExtractStats::default(),
+    };
+
+    while let Some(packet) = reader.next() {
+        let packet = packet?;
+        progress.packet(reader.get_ref().bytes_read());
+        let parsed_packet = match packet.parse() {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                skipped.skip(&packet, e)?;
+                continue;
+            }
+        };
+        let curr_ts = packet.ts;
+        // the first frame that parses starts the clock
+        let (extractor, rows) = state.get_or_insert_with(|| {
+            let extractor = Extractor::new(curr_ts)
+                .flow_limits(options.flows)
+                .time_unit(options.features.time_unit());
+            (extractor, Rows::new(capture_id, options))
+        });
+
+        let addrs = addrs(&parsed_packet).unwrap_or((Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED));
+        let extras = rows
+            .features
+            .update(curr_ts, packet.data.len(), &parsed_packet);
+        let extracted = extractor.extract(curr_ts, packet.data.len(), &parsed_packet);
+        rows.emit(extracted, curr_ts, addrs, &extras, &mut *emit)?;
+    }
+    let mut stats = match state {
+        Some((extractor, rows)) => {
+            let stats = rows.stats(Some(&extractor)) - before;
+            *stream = Some(rows.suspend(extractor));
+            stats
+        }
+        None => ExtractStats::default(),
+    };
+    stats.late = reader.late();
+    stats.skipped = skipped.finish()?;
+    Ok(stats)
+}
+
+// how many packets can be queued for each worker
+const QUEUE_LEN: usize = 1024;
+
+struct Job {
+    seq: u64,
+    ts: i64,
+    extracted: Extracted,
+    window: Option<WindowFeatures>,
+    link_type: u32,
+    data: Vec<u8>,
+}
+
+type Done = (u64, Vec<(RowId, Vec<Value>, Option<Label>)>);
+
+fn extract_parallel(
+    path: &Path,
+    capture_id: &str,
+    options: &ExtractOptions,
+    progress: &mut Progress,
+    emit: &mut impl FnMut(RowId, Vec<Value>, Option<Label>) -> anyhow::Result<()>,
+) -> anyhow::Result<ExtractStats> {
+    let threads = options.threads;
+    thread::scope(|s| {
+        let (done_tx, done_rx) = sync_channel::<Done>(threads * QUEUE_LEN);
+
+        let mut jobs = Vec::with_capacity(threads);
+        let mut workers = Vec::with_capacity(threads);
+        for _ in 0..threads {
+            let (job_tx, job_rx) = sync_channel::<Job>(QUEUE_LEN);
+            let done_tx = done_tx.clone();
+            workers.push(s.spawn(move || work(job_rx, done_tx, Rows::new(capture_id, options))));
+            jobs.push(job_tx);
+        }
+        drop(done_tx);
+
+        let features = FeatureState::new(&options.features, options.flows);
+        let reader = s.spawn(|| read(path, jobs, options, features, progress));
+        let merged = merge(done_rx, emit);
+
+        // a failed reader is the root cause of anything else going wrong
+        let mut stats = reader.join().unwrap()?;
+        merged?;
+
+        for worker in workers {
+            stats += worker.join().unwrap();
+        }
+        Ok(stats)
+    })
+}
+
+// Reads, extracts and dispatches packets. Timing, per-host state like seen topics and open
+// sessions, and window features span flows, so they are computed here in capture order before
+// packets are handed to the worker owning their address pair.
+fn read(
+    path: &Path,
+    jobs: Vec<SyncSender<Job>>,
+    options: &ExtractOptions,
+    mut features: FeatureState,
+    progress: &mut Progress,
+) -> anyhow::Result<ExtractStats> {
+    let mut reader = Reorder::new(PacketReader::open(path)?, options.reorder);
+    let mut skipped = Skipped::new(options.quarantine.clone());
+    let mut extractor = None;
+    let mut seq = 0;
+
+    while let Some(packet) = reader.next() {
+        let packet = packet?;
+        progress.packet(reader.get_ref().bytes_read());
+
+        let parsed_packet = match packet.parse() {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                skipped.skip(&packet, e)?;
+                continue;
+            }
+        };
+        let curr_ts = packet.ts;
+        // the first frame that parses starts the clock
+        let extractor = extractor.get_or_insert_with(|| {
+            Extractor::new(curr_ts)
+                .flow_limits(options.flows)
+                .time_unit(options.features.time_unit())
+        });
+
+        let (src, dst) = match addrs(&parsed_packet) {
+            Some(addrs) => addrs,
+            None => continue,
+        };
+        let window = features.window(curr_ts, packet.data.len(), &parsed_packet);
+        let extracted = extractor.extract(curr_ts, packet.data.len(), &parsed_packet);
+
+        let mut hasher = DefaultHasher::new();
+        (src.min(dst), src.max(dst)).hash(&mut hasher);
+        let shard = hasher.finish() as usize % jobs.len();
+
+        let job = Job {
+            seq,
+            ts: curr_ts,
+            extracted,
+            window,
+            link_type: packet.link_type,
+            data: packet.data,
+        };
+        seq += 1;
+
+        // workers only hang up when the merger has already failed
+        if jobs[shard].send(job).is_err() {
+            break;
+        }
+    }
+    let (sessions, topics) = extractor
+        .as_ref()
+        .map(Extractor::evictions)
+        .unwrap_or_default();
+    Ok(ExtractStats {
+        late: reader.late(),
+        skipped: skipped.finish()?,
+        sessions,
+        topics,
+        ..Default::default()
+    })
+}
+
+// flows never span workers, so neither do packet counts and per-flow features
+fn work(jobs: Receiver<Job>, done: SyncSender<Done>, mut rows: Rows) -> ExtractStats {
+    for job in jobs {
+        let parsed_packet =
+            parse_frame(job.link_type, &job.data).expect("packet already parsed by the reader");
+        let addrs = addrs(&parsed_packet).expect("reader only sends IPv4 packets");
+        let extras = Extras {
+            window: job.window,
+            ..rows.features.packet(job.ts, job.data.len(), &parsed_packet)
+        };
+        let mut out = Vec::new();
+        let _ = rows.emit(
+            job.extracted,
+            job.ts,
+            addrs,
+            &extras,
+            |id, values, label| {
+                out.push((id, values, label));
+                Ok(())
+            },
+        );
+
+        if done.send((job.seq, out)).is_err() {
+            break;
+        }
+    }
+
+    rows.stats(None)
+}
+
+// puts rows coming back from the workers back in capture order
+fn merge(
+    done: Receiver<Done>,
+    emit: &mut impl FnMut(RowId, Vec<Value>, Option<Label>) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut pending = BTreeMap::new();
+    let mut next = 0;
+
+    for (seq, rows) in done {
+        pending.insert(seq, rows);
+
+        while let Some(rows) = pending.remove(&next) {
+            for (id, values, label) in rows {
+                emit(id, values, label)?;
+            }
+            next += 1;
+        }
+    }
+
+    Ok(())
+}
+
+struct Progress {
+    bar: ProgressBar,
+    packets: u64,
+}
+
+impl Progress {
+    fn new(path: &Path) -> Self {
+        // unknown for stdin
+        let len = fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+        let bar = ProgressBar::new(len);
+        bar.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] {bar:40} {bytes}/{total_bytes} ({bytes_per_sec}) {msg}",
+            )
+            .unwrap(),
+        );
+
+        Self { bar, packets: 0 }
+    }
+
+    fn packet(&mut self, bytes_read: u64) {
+        self.bar.set_position(bytes_read);
+        self.packets += 1;
+
+        if self.packets.is_multiple_of(4096) {
+            self.update();
+        }
+    }
+
+    fn update(&self) {
+        let secs = self.bar.elapsed().as_secs_f64().max(f64::EPSILON);
+        self.bar.set_message(format!(
+            "{} packets ({:.0} packets/s)",
+            self.packets,
+            self.packets as f64 / secs
+        ));
+    }
+
+    fn finish(&self) {
+        self.update();
+        self.bar.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::BufWriter};
+
+    use bytes::BytesMut;
+    use etherparse::PacketBuilder;
+    use mqttbytes::{v4, QoS};
+
+    use super::*;
+    use crate::{pcap_writer, write_packet, FeatureConfig, Features, Group, Packet, MAX_SNAPLEN};
+
+    fn frame(src: [u8; 4], dst: [u8; 4], ports: (u16, u16), payload: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4(src, dst, 64)
+            .tcp(ports.0, ports.1, 1, 1024)
+            .write(&mut data, payload)
+            .unwrap();
+        data
+    }
+
+    // a client talking to two brokers, and another client sharing one of them
+    fn packets() -> Vec<Packet> {
+        let conversations = [
+            ([10, 0, 0, 1], [10, 0, 0, 100], 40000),
+            ([10, 0, 0, 1], [10, 0, 0, 101], 40001),
+            ([10, 0, 0, 2], [10, 0, 0, 100], 40002),
+        ];
+        let mut packets = Vec::new();
+        for (i, &(client, broker, port)) in conversations.iter().enumerate() {
+            let mut buf = BytesMut::new();
+            v4::Connect::new(format!("c{i}")).write(&mut buf).unwrap();
+            packets.push(frame(client, broker, (port, 1883), &buf));
+        }
+        for i in 0..30 {
+            let (client, broker, port) = conversations[i % 3];
+            let mut buf = BytesMut::new();
+            v4::Publish::new(format!("t/{}", i % 4), QoS::AtMostOnce, "x")
+                .write(&mut buf)
+                .unwrap();
+            packets.push(frame(client, broker, (port, 1883), &buf));
+            packets.push(frame(broker, client, (1883, port), &buf));
+        }
+
+        packets
+            .into_iter()
+            .enumerate()
+            .map(|(i, data)| Packet {
+                ts: 1_700_000_000_000_000_000 + i as i64 * 1_000_000_000,
+                nanos: false,
+                len: data.len() as u32,
+                link_type: 1,
+                snaplen: MAX_SNAPLEN,
+                data,
+            })
+            .collect()
+    }
+
+    // `header` decides the link type, even with no packets to write
+    fn write(path: &Path, header: &Packet, packets: &[Packet]) {
+        let file = BufWriter::new(File::create(path).unwrap());
+        let mut writer = pcap_writer(file, header).unwrap();
+        for packet in packets {
+            write_packet(&mut writer, packet).unwrap();
+        }
+    }
+
+    fn capture(path: &Path) {
+        let packets = packets();
+        write(path, &packets[0], &packets);
+    }
+
+    fn options(threads: usize) -> ExtractOptions<'static> {
+        let features = Features::new(FeatureConfig {
+            groups: vec![Group::Session, Group::Payload, Group::Window],
+            ..Default::default()
+        })
+        .unwrap();
+        ExtractOptions {
+            threads,
+            features,
+            ..Default::default()
+        }
+    }
+
+    fn rows(path: &Path, threads: usize) -> Vec<String> {
+        let mut rows = Vec::new();
+        extract_pcap(path, &options(threads), |id, values, _| {
+            rows.push(format!("{id:?} {values:?}"));
+            Ok(())
+        })
+        .unwrap();
+        rows
+    }
+
+    #[test]
+    fn parallel_rows_match_sequential() {
+        let path = std::env::temp_dir().join(format!("offline-{}.pcap", std::process::id()));
+        capture(&path);
+
+        let sequential = rows(&path, 1);
+        assert_eq!(sequential.len(), 63);
+        for threads in [2, 3, 4] {
+            assert_eq!(rows(&path, threads), sequential, "{threads} threads");
+        }
+
+        // sessions are counted per client, over both brokers
+        let options = ExtractOptions {
+            threads: 4,
+            ..Default::default()
+        };
+        let column = options
+            .features
+            .names()
+            .iter()
+            .position(|n| n == "mqtt_src_sessions");
+        let mut most = 0.0;
+        extract_pcap(&path, &options, |id, values, _| {
+            if id.src_ip == "10.0.0.1" {
+                most = values[column.unwrap()].as_f64().unwrap().max(most);
+            }
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(most, 2.0);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn resuming_over_files_matches_one_capture() {
+        let dir = std::env::temp_dir().join(format!("offline-resume-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let packets = packets();
+        let whole = dir.join("whole.pcap");
+        write(&whole, &packets[0], &packets);
+        // a capture rolled over with nothing in it in between
+        let parts = [&packets[..20], &[], &packets[20..]];
+
+        let options = options(1);
+        // capture ids differ between the runs
+        let row = |id: RowId, values: Vec<Value>| {
+            format!("{} {} {} {values:?}", id.ts, id.flow_id, id.packet_index)
+        };
+        let mut expected = Vec::new();
+        extract_pcap(&whole, &options, |id, values, _| {
+            expected.push(row(id, values));
+            Ok(())
+        })
+        .unwrap();
+
+        let mut resumed = Vec::new();
+        let mut stream = None;
+        for (i, part) in parts.iter().enumerate() {
+            let path = dir.join(format!("{i}.pcap"));
+            write(&path, &packets[0], part);
+            extract_pcap_resume(&path, &options, &mut stream, |id, values, _| {
+                resumed.push(row(id, values));
+                Ok(())
+            })
+            .unwrap();
+            // through a checkpoint file and back
+            let json = serde_json::to_string(&stream).unwrap();
+            stream = serde_json::from_str(&json).unwrap();
+        }
+
+        assert_eq!(resumed.len(), 63);
+        assert_eq!(resumed, expected);
+        assert!(extract_pcap(dir.join("1.pcap"), &options, |_, _, _| Ok(())).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
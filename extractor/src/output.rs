@@ -0,0 +1,452 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, Context};
+use arrow_array::{
+    builder::{
+        BooleanBuilder, Float64Builder, Int64Builder, StringBuilder, UInt16Builder, UInt64Builder,
+        UInt8Builder,
+    },
+    ArrayRef, RecordBatch,
+};
+use arrow_ipc::writer::StreamWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use parquet::{
+    arrow::ArrowWriter,
+    basic::{Compression as ParquetCompression, GzipLevel, ZstdLevel},
+    file::properties::WriterProperties,
+};
+use pcap_file::{pcap::PcapHeader, DataLink, Endianness, PcapWriter, TsResolution};
+use serde::{ser::SerializeMap, Deserialize, Serialize, Serializer};
+
+use crate::{Column, ColumnType, FeatureConfig, FeatureSchema, Packet, Value};
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Csv,
+    Parquet,
+    Jsonl,
+    // arrow IPC streaming format
+    Arrow,
+}
+
+impl Format {
+    // anything without a known extension stays CSV, like it always was
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("parquet" | "pq") => Format::Parquet,
+            Some("jsonl" | "ndjson") => Format::Jsonl,
+            Some("arrow" | "arrows") => Format::Arrow,
+            _ => Format::Csv,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            "jsonl" => Ok(Format::Jsonl),
+            "arrow" => Ok(Format::Arrow),
+            _ => Err(anyhow!(
+                "unknown output format {s}, expected csv, parquet, jsonl or arrow"
+            )),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    None,
+    Snappy,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "snappy" => Ok(Compression::Snappy),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(anyhow!(
+                "unknown compression {s}, expected none, snappy, gzip or zstd"
+            )),
+        }
+    }
+}
+
+impl From<Compression> for ParquetCompression {
+    fn from(compression: Compression) -> Self {
+        match compression {
+            Compression::None => ParquetCompression::UNCOMPRESSED,
+            Compression::Snappy => ParquetCompression::SNAPPY,
+            Compression::Gzip => ParquetCompression::GZIP(GzipLevel::default()),
+            Compression::Zstd => ParquetCompression::ZSTD(ZstdLevel::default()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputOptions {
+    // picked from the file extension when not set
+    pub format: Option<Format>,
+    // parquet only
+    pub row_group_size: usize,
+    pub compression: Compression,
+    // recorded in the schema next to or inside the output, see `FeatureSchema`
+    pub features: Option<FeatureConfig>,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            format: None,
+            row_group_size: 1 << 20,
+            compression: Compression::Snappy,
+            features: None,
+        }
+    }
+}
+
+pub trait Sink {
+    fn write(&mut self, row: &[Value]) -> anyhow::Result<()>;
+
+    fn finish(self: Box<Self>) -> anyhow::Result<()>;
+}
+
+type Output = Box<dyn Write + Send>;
+
+// `-` writes to stdout, anything else also gets a schema file written next to it
+pub fn create_sink(
+    path: impl AsRef<Path>,
+    columns: &[Column],
+    options: &OutputOptions,
+) -> anyhow::Result<Box<dyn Sink>> {
+    let path = path.as_ref();
+    let output: Output = match path.to_str() {
+        Some("-") => Box::new(BufWriter::new(io::stdout())),
+        _ => {
+            FeatureSchema::new(columns, options.features.as_ref()).write_sidecar(path)?;
+            Box::new(BufWriter::new(
+                File::create(path).with_context(|| format!("creating {}", path.display()))?,
+            ))
+        }
+    };
+
+    match options.format.unwrap_or_else(|| Format::from_path(path)) {
+        Format::Csv => Ok(Box::new(CsvSink::new(output, columns)?)),
+        Format::Parquet => Ok(Box::new(ParquetSink::new(output, columns, options)?)),
+        Format::Jsonl => Ok(Box::new(JsonlSink::new(output, columns))),
+        Format::Arrow => Ok(Box::new(ArrowSink::new(output, columns, options)?)),
+    }
+}
+
+// Starts a pcap file for frames like `packet`: its link type, snapshot length, and nanosecond
+// timestamps if the capture had them. Timestamps are written as nanoseconds either way, see
+// `write_packet`.
+pub fn pcap_writer<W: Write>(writer: W, packet: &Packet) -> anyhow::Result<PcapWriter<W>> {
+    let mut header = PcapHeader {
+        datalink: DataLink::from(packet.link_type),
+        snaplen: packet.snaplen,
+        ..Default::default()
+    };
+    // the byte order most tools write
+    header.set_endianness(Endianness::Little);
+    if packet.nanos {
+        header.set_ts_resolution(TsResolution::NanoSecond);
+    }
+
+    Ok(PcapWriter::with_header(header, writer)?)
+}
+
+// Writes `packet` as it was captured, with its length on the wire besides the bytes kept.
+pub fn write_packet<W: Write>(writer: &mut PcapWriter<W>, packet: &Packet) -> anyhow::Result<()> {
+    let secs = packet.ts.div_euclid(1_000_000_000);
+    let nanos = packet.ts.rem_euclid(1_000_000_000);
+    writer.write(secs as u32, nanos as u32, &packet.data, packet.len)?;
+    Ok(())
+}
+
+// A row as a JSON object keyed by column name, in column order. This is the same shape capture
+// posts to the scorer, so JSONL output can be replayed into it line by line.
+pub struct JsonRow<'a> {
+    pub columns: &'a [Column],
+    pub values: &'a [Value],
+}
+
+impl Serialize for JsonRow<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.columns.len()))?;
+        for (column, value) in self.columns.iter().zip(self.values) {
+            map.serialize_entry(&column.name, value)?;
+        }
+        map.end()
+    }
+}
+
+struct CsvSink {
+    writer: csv::Writer<Output>,
+}
+
+impl CsvSink {
+    fn new(output: Output, columns: &[Column]) -> anyhow::Result<Self> {
+        let mut writer = csv::Writer::from_writer(output);
+        writer.write_record(columns.iter().map(|c| &c.name))?;
+
+        Ok(Self { writer })
+    }
+}
+
+impl Sink for CsvSink {
+    fn write(&mut self, row: &[Value]) -> anyhow::Result<()> {
+        self.writer
+            .write_record(row.iter().map(|v| v.to_string()))?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+struct JsonlSink {
+    output: Output,
+    columns: Vec<Column>,
+}
+
+impl JsonlSink {
+    fn new(output: Output, columns: &[Column]) -> Self {
+        Self {
+            output,
+            columns: columns.to_vec(),
+        }
+    }
+}
+
+impl Sink for JsonlSink {
+    fn write(&mut self, row: &[Value]) -> anyhow::Result<()> {
+        let row = JsonRow {
+            columns: &self.columns,
+            values: row,
+        };
+        serde_json::to_writer(&mut self.output, &row)?;
+        self.output.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+// rows are buffered into arrow arrays and handed to the arrow writers this many at a time
+const BATCH_ROWS: usize = 8192;
+
+struct Batches {
+    schema: SchemaRef,
+    builders: Vec<Builder>,
+    rows: usize,
+}
+
+impl Batches {
+    fn new(columns: &[Column], options: &OutputOptions) -> Self {
+        // stdout has no sidecar, so the schema also travels inside the arrow/parquet metadata
+        let schema = FeatureSchema::new(columns, options.features.as_ref());
+        let metadata = HashMap::from([(
+            "feature_schema".to_string(),
+            serde_json::to_string(&schema).unwrap(),
+        )]);
+        let schema = Arc::new(
+            Schema::new(
+                columns
+                    .iter()
+                    .map(|c| Field::new(&c.name, data_type(c.ty), false))
+                    .collect::<Vec<_>>(),
+            )
+            .with_metadata(metadata),
+        );
+
+        Self {
+            schema,
+            builders: columns.iter().map(|c| Builder::new(c.ty)).collect(),
+            rows: 0,
+        }
+    }
+
+    // hands back a batch once enough rows are buffered
+    fn push(&mut self, row: &[Value]) -> anyhow::Result<Option<RecordBatch>> {
+        for (builder, value) in self.builders.iter_mut().zip(row) {
+            builder.append(value)?;
+        }
+
+        self.rows += 1;
+        match self.rows == BATCH_ROWS {
+            true => self.take(),
+            false => Ok(None),
+        }
+    }
+
+    fn take(&mut self) -> anyhow::Result<Option<RecordBatch>> {
+        if self.rows == 0 {
+            return Ok(None);
+        }
+
+        let arrays = self.builders.iter_mut().map(Builder::finish).collect();
+        self.rows = 0;
+
+        Ok(Some(RecordBatch::try_new(self.schema.clone(), arrays)?))
+    }
+}
+
+struct ParquetSink {
+    writer: ArrowWriter<Output>,
+    batches: Batches,
+}
+
+impl ParquetSink {
+    fn new(output: Output, columns: &[Column], options: &OutputOptions) -> anyhow::Result<Self> {
+        let batches = Batches::new(columns, options);
+        let props = WriterProperties::builder()
+            .set_max_row_group_size(options.row_group_size)
+            .set_compression(options.compression.into())
+            .build();
+
+        Ok(Self {
+            writer: ArrowWriter::try_new(output, batches.schema.clone(), Some(props))?,
+            batches,
+        })
+    }
+}
+
+impl Sink for ParquetSink {
+    fn write(&mut self, row: &[Value]) -> anyhow::Result<()> {
+        if let Some(batch) = self.batches.push(row)? {
+            self.writer.write(&batch)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        if let Some(batch) = self.batches.take()? {
+            self.writer.write(&batch)?;
+        }
+        self.writer.close()?;
+        Ok(())
+    }
+}
+
+struct ArrowSink {
+    writer: StreamWriter<Output>,
+    batches: Batches,
+}
+
+impl ArrowSink {
+    fn new(output: Output, columns: &[Column], options: &OutputOptions) -> anyhow::Result<Self> {
+        let batches = Batches::new(columns, options);
+
+        Ok(Self {
+            writer: StreamWriter::try_new(output, &batches.schema)?,
+            batches,
+        })
+    }
+}
+
+impl Sink for ArrowSink {
+    fn write(&mut self, row: &[Value]) -> anyhow::Result<()> {
+        if let Some(batch) = self.batches.push(row)? {
+            self.writer.write(&batch)?;
+            // readers on the other end of a pipe get every batch as soon as it's full
+            self.writer.flush()?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> anyhow::Result<()> {
+        if let Some(batch) = self.batches.take()? {
+            self.writer.write(&batch)?;
+        }
+        self.writer.finish()?;
+        self.writer.get_mut().flush()?;
+        Ok(())
+    }
+}
+
+fn data_type(ty: ColumnType) -> DataType {
+    match ty {
+        ColumnType::Bool => DataType::Boolean,
+        ColumnType::U8 => DataType::UInt8,
+        ColumnType::U16 => DataType::UInt16,
+        ColumnType::U64 => DataType::UInt64,
+        ColumnType::I64 => DataType::Int64,
+        ColumnType::F64 => DataType::Float64,
+        ColumnType::Str => DataType::Utf8,
+    }
+}
+
+enum Builder {
+    Bool(BooleanBuilder),
+    U8(UInt8Builder),
+    U16(UInt16Builder),
+    U64(UInt64Builder),
+    I64(Int64Builder),
+    F64(Float64Builder),
+    Str(StringBuilder),
+}
+
+impl Builder {
+    fn new(ty: ColumnType) -> Self {
+        match ty {
+            ColumnType::Bool => Builder::Bool(BooleanBuilder::new()),
+            ColumnType::U8 => Builder::U8(UInt8Builder::new()),
+            ColumnType::U16 => Builder::U16(UInt16Builder::new()),
+            ColumnType::U64 => Builder::U64(UInt64Builder::new()),
+            ColumnType::I64 => Builder::I64(Int64Builder::new()),
+            ColumnType::F64 => Builder::F64(Float64Builder::new()),
+            ColumnType::Str => Builder::Str(StringBuilder::new()),
+        }
+    }
+
+    fn append(&mut self, value: &Value) -> anyhow::Result<()> {
+        match (self, value) {
+            (Builder::Bool(b), Value::Bool(v)) => b.append_value(*v),
+            (Builder::U8(b), Value::U8(v)) => b.append_value(*v),
+            (Builder::U16(b), Value::U16(v)) => b.append_value(*v),
+            (Builder::U64(b), Value::U64(v)) => b.append_value(*v),
+            (Builder::I64(b), Value::I64(v)) => b.append_value(*v),
+            (Builder::F64(b), Value::F64(v)) => b.append_value(*v),
+            (Builder::Str(b), Value::Str(v)) => b.append_value(v),
+            (_, value) => return Err(anyhow!("column got a {:?} value", value.ty())),
+        }
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Builder::Bool(b) => Arc::new(b.finish()),
+            Builder::U8(b) => Arc::new(b.finish()),
+            Builder::U16(b) => Arc::new(b.finish()),
+            Builder::U64(b) => Arc::new(b.finish()),
+            Builder::I64(b) => Arc::new(b.finish()),
+            Builder::F64(b) => Arc::new(b.finish()),
+            Builder::Str(b) => Arc::new(b.finish()),
+        }
+    }
+}
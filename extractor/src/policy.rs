@@ -0,0 +1,116 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+use crate::{schema::ColumnValue, ColumnType, Extracted, HeadersInfo, Value};
+
+// Which packets become rows. The live detector and the tools building its training data should
+// agree on this, or the model is trained on rows it never gets to score.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum RowPolicy {
+    // MQTT control packets only
+    Mqtt,
+    // every TCP segment, including handshakes, pure ACKs and payloads that aren't MQTT; what the
+    // extraction always wrote, so the default
+    #[default]
+    Tcp,
+    // every IPv4 packet
+    Ip,
+}
+
+impl FromStr for RowPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mqtt" => Ok(RowPolicy::Mqtt),
+            "tcp" => Ok(RowPolicy::Tcp),
+            "ip" => Ok(RowPolicy::Ip),
+            _ => Err(anyhow!("unknown row policy {s}, expected mqtt, tcp or ip")),
+        }
+    }
+}
+
+impl RowPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RowPolicy::Mqtt => "mqtt",
+            RowPolicy::Tcp => "tcp",
+            RowPolicy::Ip => "ip",
+        }
+    }
+
+    // whether a row of `kind` read back from a file would have been written under this policy;
+    // rows of unknown kind are kept
+    pub fn keeps(&self, kind: RowKind) -> bool {
+        match self {
+            RowPolicy::Mqtt => matches!(kind, RowKind::Mqtt | RowKind::Unknown),
+            RowPolicy::Tcp => kind != RowKind::Other,
+            RowPolicy::Ip => true,
+        }
+    }
+}
+
+impl fmt::Display for RowPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RowKind {
+    Mqtt,
+    // TCP segment with SYN, FIN or RST set and no payload
+    Handshake,
+    // TCP segment with no payload and no other flags
+    Ack,
+    // TCP payload that doesn't decode as MQTT
+    DecodeError,
+    // IPv4 packet that isn't TCP, or TCP to an ignored port
+    Other,
+    // read back from a file written before rows had a kind
+    #[default]
+    Unknown,
+}
+
+impl RowKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RowKind::Mqtt => "mqtt",
+            RowKind::Handshake => "handshake",
+            RowKind::Ack => "ack",
+            RowKind::DecodeError => "decode-error",
+            RowKind::Other => "other",
+            RowKind::Unknown => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for RowKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl ColumnValue for RowKind {
+    const TYPE: ColumnType = ColumnType::Str;
+
+    fn value(&self) -> Value {
+        Value::Str(self.as_str().to_string())
+    }
+}
+
+impl Extracted {
+    // the rows `policy` keeps out of this packet
+    pub fn rows(self, policy: RowPolicy) -> Vec<(RowKind, HeadersInfo)> {
+        match (self, policy) {
+            (Extracted::Mqtt(rows), _) => rows.into_iter().map(|r| (RowKind::Mqtt, r)).collect(),
+            (Extracted::NotMqtt(kind, info), RowPolicy::Tcp | RowPolicy::Ip) => vec![(kind, info)],
+            (Extracted::NotTcp(info), RowPolicy::Ip) => vec![(RowKind::Other, info)],
+            _ => Vec::new(),
+        }
+    }
+}
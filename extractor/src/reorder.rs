@@ -0,0 +1,226 @@
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+};
+
+use crate::Packet;
+
+// packets held at most, however wide the window
+const MAX_HELD: usize = 1 << 16;
+
+// Puts packets back in timestamp order. Captures merged from several interfaces or written by
+// several capture queues are only roughly ordered, and inter-arrival times go negative where
+// they aren't. A packet is held until one at least `window` nanoseconds newer has been read, or
+// the buffer is full. Packets older than one already handed on are too late to be put in their
+// place; they are handed on right away with the timestamp of the last packet, or their own
+// with `keep_late_ts`, and counted. Without a window packets are passed through as they are.
+pub struct Reorder<I> {
+    inner: I,
+    window: Option<i64>,
+    held: BinaryHeap<Reverse<Held>>,
+    // arrival order, so packets with the same timestamp keep theirs
+    seq: u64,
+    newest: i64,
+    released: Option<i64>,
+    late: u64,
+    keep_late_ts: bool,
+    done: bool,
+}
+
+struct Held {
+    ts: i64,
+    seq: u64,
+    packet: Packet,
+}
+
+impl PartialEq for Held {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Held {}
+
+impl PartialOrd for Held {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Held {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.ts, self.seq).cmp(&(other.ts, other.seq))
+    }
+}
+
+impl<I> Reorder<I> {
+    pub fn new(inner: I, window: Option<i64>) -> Self {
+        Self {
+            inner,
+            window,
+            held: BinaryHeap::new(),
+            seq: 0,
+            newest: i64::MIN,
+            released: None,
+            late: 0,
+            keep_late_ts: false,
+            done: false,
+        }
+    }
+
+    // hand late packets on with their own timestamp, out of order, rather than raising it to
+    // keep order
+    pub fn keep_late_ts(mut self) -> Self {
+        self.keep_late_ts = true;
+        self
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+
+    // packets that arrived after a newer one was handed on
+    pub fn late(&self) -> u64 {
+        self.late
+    }
+
+    fn release(&mut self) -> Option<anyhow::Result<Packet>> {
+        let Reverse(held) = self.held.pop()?;
+        self.released = Some(held.ts);
+        Some(Ok(held.packet))
+    }
+}
+
+impl<I: Iterator<Item = anyhow::Result<Packet>>> Iterator for Reorder<I> {
+    type Item = anyhow::Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let window = match self.window {
+            Some(window) => window,
+            None => return self.inner.next(),
+        };
+
+        loop {
+            if let Some(Reverse(oldest)) = self.held.peek() {
+                if self.done
+                    || self.held.len() >= MAX_HELD
+                    || oldest.ts <= self.newest.saturating_sub(window)
+                {
+                    return self.release();
+                }
+            }
+            if self.done {
+                return None;
+            }
+
+            let mut packet = match self.inner.next() {
+                None => {
+                    self.done = true;
+                    continue;
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                Some(Ok(packet)) => packet,
+            };
+
+            match self.released {
+                Some(released) if packet.ts < released => {
+                    self.late += 1;
+                    if !self.keep_late_ts {
+                        packet.ts = released;
+                    }
+                    return Some(Ok(packet));
+                }
+                _ => {}
+            }
+
+            self.newest = self.newest.max(packet.ts);
+            self.held.push(Reverse(Held {
+                ts: packet.ts,
+                seq: self.seq,
+                packet,
+            }));
+            self.seq += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    // the first data byte tells packets apart
+    fn packets(ts: &[i64]) -> Vec<anyhow::Result<Packet>> {
+        ts.iter()
+            .enumerate()
+            .map(|(i, &ts)| {
+                Ok(Packet {
+                    ts,
+                    nanos: true,
+                    len: 1,
+                    link_type: 1,
+                    snaplen: 1,
+                    data: vec![i as u8],
+                })
+            })
+            .collect()
+    }
+
+    fn run(reorder: &mut Reorder<impl Iterator<Item = anyhow::Result<Packet>>>) -> Vec<(i64, u8)> {
+        reorder
+            .map(|packet| {
+                let packet = packet.unwrap();
+                (packet.ts, packet.data[0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sorts_within_the_window() {
+        let mut reorder = Reorder::new(packets(&[10, 30, 20, 20, 40, 35]).into_iter(), Some(15));
+        assert_eq!(
+            run(&mut reorder),
+            [(10, 0), (20, 2), (20, 3), (30, 1), (35, 5), (40, 4)]
+        );
+        assert_eq!(reorder.late(), 0);
+    }
+
+    #[test]
+    fn clamps_late_packets() {
+        let mut reorder = Reorder::new(packets(&[100, 105, 120, 90, 130]).into_iter(), Some(10));
+        assert_eq!(
+            run(&mut reorder),
+            [(100, 0), (105, 1), (105, 3), (120, 2), (130, 4)]
+        );
+        assert_eq!(reorder.late(), 1);
+    }
+
+    #[test]
+    fn keeps_late_timestamps_when_asked() {
+        let mut reorder =
+            Reorder::new(packets(&[100, 105, 120, 90, 130]).into_iter(), Some(10)).keep_late_ts();
+        assert_eq!(
+            run(&mut reorder),
+            [(100, 0), (105, 1), (90, 3), (120, 2), (130, 4)]
+        );
+        assert_eq!(reorder.late(), 1);
+    }
+
+    #[test]
+    fn passes_through_without_a_window() {
+        let mut reorder = Reorder::new(packets(&[30, 10, 20]).into_iter(), None);
+        assert_eq!(run(&mut reorder), [(30, 0), (10, 1), (20, 2)]);
+        assert_eq!(reorder.late(), 0);
+    }
+
+    #[test]
+    fn passes_errors_on() {
+        let mut input = packets(&[10]);
+        input.push(Err(anyhow!("truncated packet")));
+        let mut reorder = Reorder::new(input.into_iter(), Some(100));
+        assert!(reorder.next().unwrap().is_err());
+        assert_eq!(reorder.next().unwrap().unwrap().ts, 10);
+        assert!(reorder.next().is_none());
+    }
+}
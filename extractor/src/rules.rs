@@ -0,0 +1,153 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::{anyhow, Context};
+use serde::Deserialize;
+
+use crate::{
+    features::{Expr, Parser},
+    Extras, Features, HeadersInfo,
+};
+
+// A rules file, e.g.
+//
+// [
+//   {
+//     "name": "slow-dos",
+//     "when": [
+//       { "expr": "mqtt_idle_ratio", "above": 0.9 },
+//       { "expr": "mqtt_src_sessions", "above": 50 }
+//     ]
+//   },
+//   { "name": "sys-probe", "when": [{ "expr": "mqtt_topic_sys", "above": 0 }] }
+// ]
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct RuleConfig {
+    pub name: String,
+    // every condition has to hold for the rule to fire
+    pub when: Vec<Condition>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    // over every built-in column, selected or not, and the group and derived columns of the
+    // feature config, like a derived feature; booleans are 0 or 1
+    pub expr: String,
+    // exclusive bounds, at least one of them
+    pub above: Option<f64>,
+    pub below: Option<f64>,
+}
+
+// an expression and its bounds
+type Check = (Expr, Option<f64>, Option<f64>);
+
+// Fixed rules checked against every row, next to or instead of a model.
+pub struct Rules {
+    rules: Vec<(String, Vec<Check>)>,
+}
+
+impl Rules {
+    pub fn load(path: impl AsRef<Path>, features: &Features) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+        let config: Vec<RuleConfig> = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parsing {}", path.display()))?;
+
+        Self::new(config, features).with_context(|| format!("rules {}", path.display()))
+    }
+
+    pub fn new(config: Vec<RuleConfig>, features: &Features) -> anyhow::Result<Self> {
+        let names = features.names();
+        let mut rules = Vec::new();
+        for RuleConfig { name, when } in config {
+            if when.is_empty() {
+                return Err(anyhow!("rule `{name}` has no conditions"));
+            }
+            let mut conditions = Vec::new();
+            for Condition { expr, above, below } in when {
+                if above.is_none() && below.is_none() {
+                    return Err(anyhow!(
+                        "rule `{name}`: `{expr}` needs an `above` or `below`"
+                    ));
+                }
+                let parsed = Parser::new(&expr, &names)
+                    .parse()
+                    .with_context(|| format!("in rule `{name}`"))?;
+                conditions.push((parsed, above, below));
+            }
+            rules.push((name, conditions));
+        }
+
+        Ok(Self { rules })
+    }
+
+    // names of the rules a row sets off, in file order
+    pub fn fired(&self, features: &Features, info: &HeadersInfo, extras: &Extras) -> Vec<&str> {
+        let scope = features.scope(info, extras);
+        self.rules
+            .iter()
+            .filter(|(_, conditions)| {
+                conditions.iter().all(|(expr, above, below)| {
+                    let value = expr.eval(&scope);
+                    above.is_none_or(|above| value > above)
+                        && below.is_none_or(|below| value < below)
+                })
+            })
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(json: &str) -> anyhow::Result<Rules> {
+        Rules::new(serde_json::from_str(json)?, &Features::default())
+    }
+
+    #[test]
+    fn fires_when_every_condition_holds() {
+        let rules = rules(
+            r#"[
+                { "name": "slow-dos", "when": [
+                    { "expr": "mqtt_idle_ratio", "above": 0.9 },
+                    { "expr": "mqtt_src_sessions", "above": 50 }
+                ] },
+                { "name": "short-keep-alive", "when": [
+                    { "expr": "mqtt_keep_alive", "above": 0, "below": 5 }
+                ] }
+            ]"#,
+        )
+        .unwrap();
+        let mut info = HeadersInfo {
+            mqtt_idle_ratio: 0.95,
+            mqtt_src_sessions: 60,
+            mqtt_keep_alive: 600,
+            ..Default::default()
+        };
+        let extras = Extras::default();
+        let features = Features::default();
+
+        assert_eq!(rules.fired(&features, &info, &extras), ["slow-dos"]);
+        info.mqtt_src_sessions = 50;
+        assert!(rules.fired(&features, &info, &extras).is_empty());
+        info.mqtt_keep_alive = 2;
+        assert_eq!(rules.fired(&features, &info, &extras), ["short-keep-alive"]);
+    }
+
+    #[test]
+    fn rejects_bad_rules() {
+        assert!(rules(r#"[{ "name": "a", "when": [] }]"#).is_err());
+        assert!(rules(r#"[{ "name": "a", "when": [{ "expr": "mqtt_len" }] }]"#).is_err());
+        assert!(
+            rules(r#"[{ "name": "a", "when": [{ "expr": "mqtt_lne", "above": 1 }] }]"#).is_err()
+        );
+        // group columns need the group enabled
+        assert!(
+            rules(r#"[{ "name": "a", "when": [{ "expr": "flow_packets", "above": 1 }] }]"#)
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,231 @@
+use std::{fs::File, io::BufReader, path::Path};
+
+use anyhow::{anyhow, Context};
+use serde::{Deserialize, Serialize};
+
+use crate::FeatureConfig;
+
+// Bump whenever a feature column is added, removed, reordered or changes meaning. Models are
+// trained against one version and the scorer refuses rows from any other.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ColumnType {
+    Bool,
+    U8,
+    U16,
+    U64,
+    I64,
+    F64,
+    Str,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Value {
+    Bool(bool),
+    U8(u8),
+    U16(u16),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Str(String),
+}
+
+impl Value {
+    pub fn ty(&self) -> ColumnType {
+        match self {
+            Value::Bool(_) => ColumnType::Bool,
+            Value::U8(_) => ColumnType::U8,
+            Value::U16(_) => ColumnType::U16,
+            Value::U64(_) => ColumnType::U64,
+            Value::I64(_) => ColumnType::I64,
+            Value::F64(_) => ColumnType::F64,
+            Value::Str(_) => ColumnType::Str,
+        }
+    }
+
+    // numbers and bools as a float, for statistics
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Bool(v) => Some(*v as u8 as f64),
+            Value::U8(v) => Some(*v as f64),
+            Value::U16(v) => Some(*v as f64),
+            Value::U64(v) => Some(*v as f64),
+            Value::I64(v) => Some(*v as f64),
+            Value::F64(v) => Some(*v),
+            Value::Str(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Bool(v) => write!(f, "{v}"),
+            Value::U8(v) => write!(f, "{v}"),
+            Value::U16(v) => write!(f, "{v}"),
+            Value::U64(v) => write!(f, "{v}"),
+            Value::I64(v) => write!(f, "{v}"),
+            // `{:?}` keeps the trailing `.0` the csv serializer used to write
+            Value::F64(v) => write!(f, "{v:?}"),
+            Value::Str(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+// what a column is for; only features are fed to models
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Feature,
+    Label,
+    // where a row came from, for splitting and joining
+    Id,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: ColumnType,
+    pub role: Role,
+}
+
+impl Column {
+    pub fn new(name: impl Into<String>, ty: ColumnType, role: Role) -> Self {
+        Self {
+            name: name.into(),
+            ty,
+            role,
+        }
+    }
+}
+
+// Written next to every output file as `<file>.schema.json`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FeatureSchema {
+    pub version: u32,
+    pub columns: Vec<Column>,
+    // how the feature columns were computed, time unit and derived expressions included; left
+    // out when rows are only copied from a file that doesn't say
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub features: Option<FeatureConfig>,
+}
+
+impl FeatureSchema {
+    pub fn new(columns: &[Column], features: Option<&FeatureConfig>) -> Self {
+        Self {
+            version: SCHEMA_VERSION,
+            columns: columns.to_vec(),
+            features: features.cloned(),
+        }
+    }
+
+    pub fn sidecar_path(path: &Path) -> std::path::PathBuf {
+        let mut name = path.as_os_str().to_owned();
+        name.push(".schema.json");
+        name.into()
+    }
+
+    pub fn write_sidecar(&self, path: &Path) -> anyhow::Result<()> {
+        let sidecar = Self::sidecar_path(path);
+        let file =
+            File::create(&sidecar).with_context(|| format!("creating {}", sidecar.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        Ok(())
+    }
+
+    // errors out when the file next to `path` was written with another schema version
+    pub fn check_sidecar(path: &Path) -> anyhow::Result<Option<Self>> {
+        let sidecar = Self::sidecar_path(path);
+        let file = match File::open(&sidecar) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("opening {}", sidecar.display())),
+        };
+        let schema: Self = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("parsing {}", sidecar.display()))?;
+
+        match schema.version == SCHEMA_VERSION {
+            true => Ok(Some(schema)),
+            false => Err(anyhow!(
+                "{} was extracted with feature schema v{}, this build uses v{SCHEMA_VERSION}",
+                path.display(),
+                schema.version
+            )),
+        }
+    }
+}
+
+// rust types that can be written out as a column
+pub trait ColumnValue {
+    const TYPE: ColumnType;
+
+    fn value(&self) -> Value;
+}
+
+macro_rules! column_value {
+    ($($ty:ty => $variant:ident $(as $cast:ty)?,)*) => {
+        $(
+            impl ColumnValue for $ty {
+                const TYPE: ColumnType = ColumnType::$variant;
+
+                fn value(&self) -> Value {
+                    Value::$variant(*self $(as $cast)?)
+                }
+            }
+        )*
+    };
+}
+
+column_value! {
+    bool => Bool,
+    u8 => U8,
+    u16 => U16,
+    usize => U64 as u64,
+    u64 => U64,
+    i64 => I64,
+    f64 => F64,
+}
+
+impl ColumnValue for String {
+    const TYPE: ColumnType = ColumnType::Str;
+
+    fn value(&self) -> Value {
+        Value::Str(self.clone())
+    }
+}
+
+// Declares a struct whose fields double as output columns, in declaration order.
+macro_rules! columns {
+    (
+        role: $role:ident,
+        $(#[$meta:meta])*
+        pub struct $name:ident {
+            $(pub $field:ident: $ty:ty,)*
+        }
+    ) => {
+        $(#[$meta])*
+        pub struct $name {
+            $(pub $field: $ty,)*
+        }
+
+        impl $name {
+            pub fn columns() -> Vec<$crate::Column> {
+                vec![$(
+                    $crate::Column::new(
+                        stringify!($field),
+                        <$ty as $crate::schema::ColumnValue>::TYPE,
+                        $crate::Role::$role,
+                    ),
+                )*]
+            }
+
+            pub fn values(&self) -> Vec<$crate::Value> {
+                vec![$($crate::schema::ColumnValue::value(&self.$field),)*]
+            }
+        }
+    };
+}
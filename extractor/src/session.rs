@@ -0,0 +1,118 @@
+use std::{collections::HashMap, net::Ipv4Addr};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Evictions, FlowLimits, FlowTable};
+
+// both directions of a TCP connection map to the same key
+pub type FlowKey = ((Ipv4Addr, u16), (Ipv4Addr, u16));
+
+pub fn flow_key(src: (Ipv4Addr, u16), dst: (Ipv4Addr, u16)) -> FlowKey {
+    match src <= dst {
+        true => (src, dst),
+        false => (dst, src),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Session {
+    // host that sent the CONNECT
+    pub client: Ipv4Addr,
+    // seconds, as negotiated in CONNECT
+    pub keep_alive: u16,
+    pub last_seen: i64,
+}
+
+impl Session {
+    pub fn idle(&self, now: i64) -> i64 {
+        now - self.last_seen
+    }
+
+    // how much of the keep-alive interval has elapsed without activity, 0 when keep-alive is off
+    pub fn idle_ratio(&self, now: i64) -> f64 {
+        match self.keep_alive {
+            0 => 0.0,
+            keep_alive => self.idle(now) as f64 / (keep_alive as f64 * 1_000_000_000.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Sessions {
+    sessions: FlowTable<FlowKey, Session>,
+    per_client: HashMap<Ipv4Addr, usize>,
+}
+
+impl Default for Sessions {
+    fn default() -> Self {
+        Self::new(FlowLimits::default())
+    }
+}
+
+impl Sessions {
+    pub fn new(limits: FlowLimits) -> Self {
+        Self {
+            sessions: FlowTable::new(limits).keep_evicted(),
+            per_client: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, key: &FlowKey) -> Option<&Session> {
+        self.sessions.get(key)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&FlowKey, &Session)> {
+        self.sessions.iter()
+    }
+
+    pub fn evictions(&self) -> Evictions {
+        self.sessions.evictions()
+    }
+
+    // number of MQTT sessions currently open from `client`
+    pub fn open(&self, client: Ipv4Addr) -> usize {
+        self.per_client.get(&client).copied().unwrap_or(0)
+    }
+
+    pub(crate) fn touch(&mut self, key: &FlowKey, now: i64) -> Option<Session> {
+        let session = self.sessions.get_mut(key, now);
+        let before = session.map(|session| {
+            let before = session.clone();
+            session.last_seen = now;
+            before
+        });
+        self.release_evicted();
+        before
+    }
+
+    pub(crate) fn connect(&mut self, key: FlowKey, session: Session) {
+        *self.per_client.entry(session.client).or_default() += 1;
+        let now = session.last_seen;
+        if let Some(old) = self.sessions.insert(key, now, session) {
+            self.release(old.client);
+        }
+        self.release_evicted();
+    }
+
+    pub(crate) fn close(&mut self, key: &FlowKey) {
+        if let Some(old) = self.sessions.remove(key) {
+            self.release(old.client);
+        }
+    }
+
+    // sessions the table dropped no longer count as open
+    fn release_evicted(&mut self) {
+        for (_, session) in self.sessions.take_evicted() {
+            self.release(session.client);
+        }
+    }
+
+    fn release(&mut self, client: Ipv4Addr) {
+        if let Some(count) = self.per_client.get_mut(&client) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_client.remove(&client);
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    ops::AddAssign,
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use pcap_file::PcapWriter;
+use serde::Serialize;
+
+use crate::{pcap_writer, write_packet, Packet, ParseError};
+
+// frames skipped, by why they couldn't be parsed
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct SkipCounts(pub BTreeMap<ParseError, u64>);
+
+impl SkipCounts {
+    pub fn total(&self) -> u64 {
+        self.0.values().sum()
+    }
+}
+
+impl AddAssign<&SkipCounts> for SkipCounts {
+    fn add_assign(&mut self, other: &SkipCounts) {
+        for (&error, &count) in &other.0 {
+            *self.0.entry(error).or_default() += count;
+        }
+    }
+}
+
+// `3 truncated, 1 runt`
+impl fmt::Display for SkipCounts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (error, count)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{count} {error}")?;
+        }
+        Ok(())
+    }
+}
+
+// Frames that couldn't be parsed, counted and optionally kept in a quarantine pcap for a closer
+// look. The quarantine file is only created once there is something to put in it. A pcap file
+// has a single link type, so it only gets the frames of the first one skipped.
+pub struct Skipped {
+    counts: SkipCounts,
+    quarantine: Option<PathBuf>,
+    writer: Option<(PcapWriter<BufWriter<File>>, u32)>,
+}
+
+impl Skipped {
+    pub fn new(quarantine: Option<PathBuf>) -> Self {
+        Self {
+            counts: SkipCounts::default(),
+            quarantine,
+            writer: None,
+        }
+    }
+
+    pub fn skip(&mut self, packet: &Packet, error: ParseError) -> anyhow::Result<()> {
+        *self.counts.0.entry(error).or_default() += 1;
+
+        let path = match &self.quarantine {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let writer = match &mut self.writer {
+            Some((writer, link_type)) if *link_type == packet.link_type => writer,
+            Some(_) => return Ok(()),
+            None => {
+                let file =
+                    File::create(path).with_context(|| format!("creating {}", path.display()))?;
+                let writer = pcap_writer(BufWriter::new(file), packet)?;
+                &mut self.writer.insert((writer, packet.link_type)).0
+            }
+        };
+        write_packet(writer, packet)
+    }
+
+    pub fn counts(&self) -> &SkipCounts {
+        &self.counts
+    }
+
+    pub fn finish(self) -> anyhow::Result<SkipCounts> {
+        if let Some((writer, _)) = self.writer {
+            writer.into_writer().flush()?;
+        }
+        Ok(self.counts)
+    }
+}
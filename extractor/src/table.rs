@@ -0,0 +1,441 @@
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context};
+use arrow_array::{
+    cast::AsArray,
+    types::{Float64Type, Int64Type, UInt16Type, UInt64Type, UInt8Type},
+    Array, RecordBatch,
+};
+use arrow_ipc::reader::StreamReader;
+use arrow_schema::{ArrowError, DataType, Schema};
+use bytes::Bytes;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+use crate::{
+    Column, ColumnType, FeatureConfig, FeatureSchema, Format, HeadersInfo, Label, Role, RowId,
+    Value,
+};
+
+// a row read back from extractor output; cells can be missing in CSV and JSONL files
+pub type Row = Vec<Option<Value>>;
+
+type Batches = Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>>>;
+
+// Reads back any file `create_sink` writes, `-` for stdin. Column types come from the schema
+// sidecar or the arrow metadata, and fall back to the extractor's own columns for older CSVs.
+pub struct TableReader {
+    columns: Vec<Column>,
+    // how the feature columns were computed, when the schema says
+    features: Option<FeatureConfig>,
+    rows: Rows,
+}
+
+enum Rows {
+    Csv(csv::StringRecordsIntoIter<Box<dyn Read>>),
+    Jsonl(io::Lines<Box<dyn BufRead>>),
+    Arrow {
+        batches: Batches,
+        batch: Option<RecordBatch>,
+        next: usize,
+    },
+}
+
+impl TableReader {
+    pub fn open(path: impl AsRef<Path>, format: Option<Format>) -> anyhow::Result<Self> {
+        let path = path.as_ref();
+        let stdin = path.to_str() == Some("-");
+        let sidecar = match stdin {
+            true => None,
+            false => FeatureSchema::check_sidecar(path)?,
+        };
+        let mut features = sidecar.as_ref().and_then(|schema| schema.features.clone());
+        let input: Box<dyn Read> = match stdin {
+            true => Box::new(io::stdin()),
+            false => {
+                Box::new(File::open(path).with_context(|| format!("opening {}", path.display()))?)
+            }
+        };
+
+        let (columns, rows) = match format.unwrap_or_else(|| Format::from_path(path)) {
+            Format::Csv => {
+                let mut reader = csv::Reader::from_reader(input);
+                let headers = reader.headers()?.clone();
+                let columns = columns(sidecar, headers.iter())?;
+                (columns, Rows::Csv(reader.into_records()))
+            }
+            Format::Jsonl => {
+                let mut lines: Box<dyn BufRead> = Box::new(BufReader::new(input));
+                // without a sidecar the columns are whatever keys the first row has
+                let columns = match sidecar {
+                    Some(schema) => schema.columns,
+                    None => {
+                        let mut first = String::new();
+                        lines.read_line(&mut first)?;
+                        let row: serde_json::Map<String, serde_json::Value> =
+                            serde_json::from_str(&first)?;
+                        let columns = columns(None, row.keys().map(String::as_str))?;
+                        lines = Box::new(io::Cursor::new(first).chain(lines));
+                        columns
+                    }
+                };
+                (columns, Rows::Jsonl(lines.lines()))
+            }
+            Format::Parquet => {
+                let mut data = Vec::new();
+                BufReader::new(input).read_to_end(&mut data)?;
+                let builder = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(data))?;
+                // the reader's own schema leaves out the metadata
+                let schema = builder.schema().clone();
+                let reader = builder.build()?;
+                let columns = arrow_columns(sidecar, &schema, &mut features)?;
+                (columns, Rows::arrow(Box::new(reader)))
+            }
+            Format::Arrow => {
+                let reader = StreamReader::try_new(BufReader::new(input), None)?;
+                let columns = arrow_columns(sidecar, &reader.schema(), &mut features)?;
+                (columns, Rows::arrow(Box::new(reader)))
+            }
+        };
+
+        Ok(Self {
+            columns,
+            features,
+            rows,
+        })
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    pub fn features(&self) -> Option<&FeatureConfig> {
+        self.features.as_ref()
+    }
+
+    fn next_row(&mut self) -> anyhow::Result<Option<Row>> {
+        match &mut self.rows {
+            Rows::Csv(records) => {
+                let record = match records.next() {
+                    None => return Ok(None),
+                    Some(record) => record?,
+                };
+                self.columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, column)| match record.get(i) {
+                        None | Some("") => Ok(None),
+                        Some(s) => parse(column, s).map(Some),
+                    })
+                    .collect::<anyhow::Result<_>>()
+                    .map(Some)
+            }
+            Rows::Jsonl(lines) => {
+                let line = loop {
+                    match lines.next() {
+                        None => return Ok(None),
+                        Some(line) => match line? {
+                            line if line.trim().is_empty() => continue,
+                            line => break line,
+                        },
+                    }
+                };
+                let mut row: serde_json::Map<String, serde_json::Value> =
+                    serde_json::from_str(&line)?;
+                self.columns
+                    .iter()
+                    .map(|column| match row.remove(&column.name) {
+                        None | Some(serde_json::Value::Null) => Ok(None),
+                        Some(serde_json::Value::String(s)) => parse(column, &s).map(Some),
+                        Some(v) => parse(column, &v.to_string()).map(Some),
+                    })
+                    .collect::<anyhow::Result<_>>()
+                    .map(Some)
+            }
+            Rows::Arrow {
+                batches,
+                batch,
+                next,
+            } => loop {
+                if let Some(current) = batch {
+                    if *next < current.num_rows() {
+                        let row = current
+                            .columns()
+                            .iter()
+                            .zip(&self.columns)
+                            .map(|(array, column)| cell(array.as_ref(), column.ty, *next))
+                            .collect();
+                        *next += 1;
+                        return Ok(Some(row));
+                    }
+                }
+
+                match batches.next() {
+                    None => return Ok(None),
+                    Some(next_batch) => {
+                        *batch = Some(next_batch?);
+                        *next = 0;
+                    }
+                }
+            },
+        }
+    }
+}
+
+impl Iterator for TableReader {
+    type Item = anyhow::Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_row().transpose()
+    }
+}
+
+impl Rows {
+    fn arrow(batches: Batches) -> Self {
+        Rows::Arrow {
+            batches,
+            batch: None,
+            next: 0,
+        }
+    }
+}
+
+// what a column written by this crate looks like, by name
+fn known_column(name: &str) -> Option<Column> {
+    HeadersInfo::columns()
+        .into_iter()
+        .chain(Label::columns())
+        .chain(RowId::columns())
+        .find(|c| c.name == name)
+}
+
+fn columns<'a>(
+    sidecar: Option<FeatureSchema>,
+    names: impl Iterator<Item = &'a str>,
+) -> anyhow::Result<Vec<Column>> {
+    let names: Vec<_> = names.collect();
+    if let Some(schema) = sidecar {
+        let expected: Vec<_> = schema.columns.iter().map(|c| c.name.as_str()).collect();
+        return match expected == names {
+            true => Ok(schema.columns),
+            false => Err(anyhow!("columns don't match the schema file next to them")),
+        };
+    }
+
+    Ok(names
+        .into_iter()
+        .map(|name| {
+            known_column(name).unwrap_or_else(|| Column::new(name, ColumnType::Str, Role::Feature))
+        })
+        .collect())
+}
+
+// the metadata the writer embedded goes before the sidecar, and also gives `features`
+fn arrow_columns(
+    sidecar: Option<FeatureSchema>,
+    schema: &Schema,
+    features: &mut Option<FeatureConfig>,
+) -> anyhow::Result<Vec<Column>> {
+    if let Some(embedded) = schema.metadata().get("feature_schema") {
+        let embedded: FeatureSchema = serde_json::from_str(embedded)?;
+        if embedded.version != crate::SCHEMA_VERSION {
+            return Err(anyhow!(
+                "data was extracted with feature schema v{}, this build uses v{}",
+                embedded.version,
+                crate::SCHEMA_VERSION
+            ));
+        }
+        *features = embedded.features;
+        return Ok(embedded.columns);
+    }
+
+    let names = schema.fields().iter().map(|f| f.name().as_str());
+    let mut columns = columns(sidecar, names)?;
+    for (column, field) in columns.iter_mut().zip(schema.fields()) {
+        column.ty = match field.data_type() {
+            DataType::Boolean => ColumnType::Bool,
+            DataType::UInt8 => ColumnType::U8,
+            DataType::UInt16 => ColumnType::U16,
+            DataType::UInt64 => ColumnType::U64,
+            DataType::Int64 => ColumnType::I64,
+            DataType::Float64 => ColumnType::F64,
+            DataType::Utf8 => ColumnType::Str,
+            ty => return Err(anyhow!("column {} has unsupported type {ty}", column.name)),
+        };
+    }
+
+    Ok(columns)
+}
+
+fn parse(column: &Column, s: &str) -> anyhow::Result<Value> {
+    let value = match column.ty {
+        ColumnType::Bool => Value::Bool(s.parse()?),
+        ColumnType::U8 => Value::U8(s.parse()?),
+        ColumnType::U16 => Value::U16(s.parse()?),
+        ColumnType::U64 => Value::U64(s.parse()?),
+        ColumnType::I64 => Value::I64(s.parse()?),
+        ColumnType::F64 => Value::F64(s.parse()?),
+        ColumnType::Str => Value::Str(s.to_string()),
+    };
+
+    Ok(value)
+}
+
+fn cell(array: &dyn Array, ty: ColumnType, i: usize) -> Option<Value> {
+    if array.is_null(i) {
+        return None;
+    }
+
+    let value = match ty {
+        ColumnType::Bool => Value::Bool(array.as_boolean().value(i)),
+        ColumnType::U8 => Value::U8(array.as_primitive::<UInt8Type>().value(i)),
+        ColumnType::U16 => Value::U16(array.as_primitive::<UInt16Type>().value(i)),
+        ColumnType::U64 => Value::U64(array.as_primitive::<UInt64Type>().value(i)),
+        ColumnType::I64 => Value::I64(array.as_primitive::<Int64Type>().value(i)),
+        ColumnType::F64 => Value::F64(array.as_primitive::<Float64Type>().value(i)),
+        ColumnType::Str => Value::Str(array.as_string::<i32>().value(i).to_string()),
+    };
+
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{Features, OutputOptions, TimeUnit};
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("table-{name}-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // a column of every type
+    fn columns() -> Vec<Column> {
+        vec![
+            Column::new("syn", ColumnType::Bool, Role::Feature),
+            Column::new("ttl", ColumnType::U8, Role::Feature),
+            Column::new("port", ColumnType::U16, Role::Feature),
+            Column::new("len", ColumnType::U64, Role::Feature),
+            Column::new("ts", ColumnType::I64, Role::Id),
+            Column::new("avg", ColumnType::F64, Role::Feature),
+            Column::new("attack_class", ColumnType::Str, Role::Label),
+        ]
+    }
+
+    fn rows(count: u64) -> Vec<Vec<Value>> {
+        (0..count)
+            .map(|i| {
+                vec![
+                    Value::Bool(i % 2 == 0),
+                    Value::U8(i as u8),
+                    Value::U16(65535 - i as u16),
+                    Value::U64(u64::MAX - i),
+                    Value::I64(-1_700_000_000_000_000_000 - i as i64),
+                    Value::F64(i as f64 / 3.0),
+                    Value::Str(format!("class {i}")),
+                ]
+            })
+            .collect()
+    }
+
+    fn round_trip(path: &Path, options: &OutputOptions, rows: &[Vec<Value>]) -> TableReader {
+        let mut sink = crate::create_sink(path, &columns(), options).unwrap();
+        for row in rows {
+            sink.write(row).unwrap();
+        }
+        sink.finish().unwrap();
+
+        TableReader::open(path, None).unwrap()
+    }
+
+    fn read_all(reader: TableReader) -> Vec<Vec<Value>> {
+        reader
+            .map(|row| row.unwrap().into_iter().map(Option::unwrap).collect())
+            .collect()
+    }
+
+    #[test]
+    fn parquet_round_trips_typed_columns() {
+        let dir = temp_dir("parquet");
+        let path = dir.join("x.parquet");
+        let options = OutputOptions {
+            row_group_size: 7,
+            compression: crate::Compression::Zstd,
+            ..Default::default()
+        };
+        let rows = rows(50);
+
+        let reader = round_trip(&path, &options, &rows);
+        assert_eq!(reader.columns(), columns());
+        assert_eq!(read_all(reader), rows);
+
+        // the types also come from the file itself
+        fs::remove_file(FeatureSchema::sidecar_path(&path)).unwrap();
+        let reader = TableReader::open(&path, None).unwrap();
+        assert_eq!(reader.columns(), columns());
+        assert_eq!(read_all(reader), rows);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn jsonl_and_arrow_round_trip() {
+        let dir = temp_dir("jsonl-arrow");
+        let rows = rows(20);
+        for name in ["x.jsonl", "x.arrow"] {
+            let path = dir.join(name);
+            let reader = round_trip(&path, &OutputOptions::default(), &rows);
+            assert_eq!(reader.columns(), columns(), "{name}");
+            assert_eq!(read_all(reader), rows, "{name}");
+        }
+
+        // every line is what capture posts to the scorer
+        let columns = columns();
+        let lines = fs::read_to_string(dir.join("x.jsonl")).unwrap();
+        for (line, values) in lines.lines().zip(&rows) {
+            let posted = crate::JsonRow {
+                columns: &columns,
+                values,
+            };
+            assert_eq!(line, serde_json::to_string(&posted).unwrap());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reads_back_the_feature_config() {
+        let dir = temp_dir("features");
+        let config = FeatureConfig {
+            features: Some(vec!["packet_len".into(), "tcp_tdelta".into()]),
+            time_unit: TimeUnit::Millis,
+            ..Default::default()
+        };
+        let features = Features::new(config).unwrap();
+        let options = OutputOptions {
+            features: Some(features.config().clone()),
+            ..Default::default()
+        };
+
+        for name in ["x.csv", "x.jsonl", "x.arrow", "x.parquet"] {
+            let path = dir.join(name);
+            let mut sink = crate::create_sink(&path, &features.columns(), &options).unwrap();
+            sink.write(&[Value::U64(60), Value::I64(2)]).unwrap();
+            sink.finish().unwrap();
+
+            let reader = TableReader::open(&path, None).unwrap();
+            assert_eq!(reader.features(), Some(features.config()), "{name}");
+            // the arrow formats carry it themselves
+            if name.ends_with(".arrow") || name.ends_with(".parquet") {
+                fs::remove_file(FeatureSchema::sidecar_path(&path)).unwrap();
+                let reader = TableReader::open(&path, None).unwrap();
+                assert_eq!(reader.features(), Some(features.config()), "{name}");
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,112 @@
+use std::{collections::VecDeque, str::FromStr};
+
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+
+// The unit timing features and row timestamps are written in. Timestamps are nanoseconds
+// everywhere else, however precise the capture.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TimeUnit {
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "ms")]
+    Millis,
+    // what the extractor always wrote
+    #[default]
+    #[serde(rename = "us")]
+    Micros,
+    #[serde(rename = "ns")]
+    Nanos,
+}
+
+impl TimeUnit {
+    fn nanos(&self) -> i64 {
+        match self {
+            TimeUnit::Seconds => 1_000_000_000,
+            TimeUnit::Millis => 1_000_000,
+            TimeUnit::Micros => 1_000,
+            TimeUnit::Nanos => 1,
+        }
+    }
+
+    // a timestamp or duration in nanoseconds in this unit, rounded down
+    pub fn from_nanos(&self, nanos: i64) -> i64 {
+        nanos.div_euclid(self.nanos())
+    }
+}
+
+impl FromStr for TimeUnit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "s" => Ok(TimeUnit::Seconds),
+            "ms" => Ok(TimeUnit::Millis),
+            "us" => Ok(TimeUnit::Micros),
+            "ns" => Ok(TimeUnit::Nanos),
+            _ => Err(anyhow!("unknown time unit {s}, expected s, ms, us or ns")),
+        }
+    }
+}
+
+// An f64 holds unix time to about a microsecond, so anything finer would be rounding noise and
+// could move a window boundary past a packet right on it.
+pub fn secs_to_nanos(secs: f64) -> i64 {
+    (secs * 1_000_000.0).round() as i64 * 1000
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Delta {
+    pub tdelta: i64,
+    pub l20_avg: i64,
+}
+
+// Inter-arrival time of IPv4 packets and its average over the last 20, across the whole capture,
+// in `unit`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Timing {
+    unit: TimeUnit,
+    prev_ts: i64,
+    tcp_l20_avg: i64,
+    l20_diffs: VecDeque<i64>,
+}
+
+impl Timing {
+    pub fn new(first_ts: i64) -> Self {
+        let mut l20_diffs = VecDeque::with_capacity(20);
+        l20_diffs.push_back(0);
+
+        Self {
+            unit: TimeUnit::default(),
+            prev_ts: first_ts,
+            tcp_l20_avg: 0,
+            l20_diffs,
+        }
+    }
+
+    pub fn time_unit(mut self, unit: TimeUnit) -> Self {
+        self.unit = unit;
+        self
+    }
+
+    pub fn update(&mut self, ts: i64) -> Delta {
+        // in whole units, so a capture keeps its timing features when its precision goes up
+        let diff = self.unit.from_nanos(ts) - self.unit.from_nanos(self.prev_ts);
+
+        let len = self.l20_diffs.len() as i64;
+        if len < 20 {
+            self.tcp_l20_avg = (self.tcp_l20_avg * len + diff) / len;
+        } else {
+            self.tcp_l20_avg =
+                (self.tcp_l20_avg * 20 - self.l20_diffs.pop_front().unwrap() + diff) / 20;
+        }
+
+        self.l20_diffs.push_back(diff);
+        self.prev_ts = ts;
+
+        Delta {
+            tdelta: diff,
+            l20_avg: self.tcp_l20_avg,
+        }
+    }
+}
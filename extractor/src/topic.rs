@@ -0,0 +1,68 @@
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct TopicShape {
+    pub levels: usize,
+    pub sys: bool,
+    pub wildcard: bool,
+    pub invalid: bool,
+}
+
+// works on raw bytes so that topics mqttbytes refuses to decode still get described
+pub(crate) fn shape(topic: &[u8]) -> TopicShape {
+    let levels = match topic.is_empty() {
+        true => 0,
+        false => topic.iter().filter(|&&b| b == b'/').count() + 1,
+    };
+
+    let invalid = match std::str::from_utf8(topic) {
+        Ok(s) => s.chars().any(char::is_control),
+        Err(_) => true,
+    };
+
+    TopicShape {
+        levels,
+        // `$SYS/` and any other `$` prefix are reserved for the broker
+        sys: topic.first() == Some(&b'$'),
+        wildcard: topic.iter().any(|&b| b == b'+' || b == b'#'),
+        invalid,
+    }
+}
+
+pub(crate) struct RawPublish {
+    pub qos: u8,
+    pub remaining_len: usize,
+    pub topic: Vec<u8>,
+}
+
+// mqttbytes consumes the frame before validating the topic, so PUBLISH headers are
+// peeked beforehand to still be able to report on non-UTF8 topics
+pub(crate) fn peek_publish(stream: &[u8]) -> Option<RawPublish> {
+    let byte1 = *stream.first()?;
+    if byte1 >> 4 != 3 {
+        return None;
+    }
+
+    let mut remaining_len = 0;
+    let mut header_len = 1;
+    loop {
+        let byte = *stream.get(header_len)? as usize;
+        remaining_len += (byte & 0x7F) << (7 * (header_len - 1));
+        header_len += 1;
+
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if header_len > 4 {
+            return None;
+        }
+    }
+
+    let len_bytes = stream.get(header_len..header_len + 2)?;
+    let topic_len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
+    let topic = stream.get(header_len + 2..header_len + 2 + topic_len)?;
+
+    Some(RawPublish {
+        qos: (byte1 & 0b0110) >> 1,
+        remaining_len,
+        topic: topic.to_vec(),
+    })
+}
@@ -1,263 +1,267 @@
-use std::{collections::VecDeque, env};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
 
-use anyhow::anyhow;
-use bytes::BytesMut;
-use etherparse::{InternetSlice, SlicedPacket};
-use mqttbytes::v4::{Packet, SubscribeReasonCode};
-use pcap::Capture;
-use serde::Serialize;
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use extractor::{
+    secs_to_nanos, Anonymizer, Checkpoint, Compression, Endpoint, ExtractOptions, ExtractStats,
+    Features, FlowLimits, Format, Label, Labeler, Limit, OutputOptions, RowId, RowPolicy, Settings,
+    SkipCounts, Stream, Window,
+};
 
-#[derive(Serialize, Debug, Clone, Default)]
-struct HeadersInfo {
-    packet_len: usize,
-    ip_len: u16,
-    ip_df: bool,
-    ip_mf: bool,
-    ip_ttl: u8,
-    tcp_len: usize,
-    tcp_pdu_size: u8,
-    tcp_ack: bool,
-    tcp_cwr: bool,
-    tcp_ece: bool,
-    tcp_fin: bool,
-    tcp_ns: bool,
-    tcp_push: bool,
-    tcp_reset: bool,
-    tcp_syn: bool,
-    tcp_urg: bool,
-    tcp_src_port: u16,
-    tcp_dst_port: u16,
-    tcp_tdelta: i64,
-    tcp_l20_avg: i64,
-    mqtt_len: usize,
-    mqtt_topic_len: usize,
-    mqtt_msg_type: u8,
-    mqtt_qos_lvl: u8,
-}
-
-fn main() -> anyhow::Result<()> {
-    let mut args = env::args();
-    args.next().expect("no binary name passed");
+#[derive(Parser)]
+struct Args {
+    /// pcap or pcapng file to extract features from, optionally gzip, zstd or xz compressed;
+    /// `-` for stdin
+    pcap_file_path: PathBuf,
+    /// file to write features to, `-` for stdout; the format follows the extension
+    /// (`.csv`, `.parquet`, `.jsonl`, `.arrow`)
+    csv_file_path: PathBuf,
 
-    let pcap_file_path = args.next().expect("pass in pcap file path");
-    let csv_file_path = args.next().expect("pass in csv output file path");
+    /// output format, overrides the one picked from the file extension
+    #[arg(long)]
+    format: Option<Format>,
+    /// maximum rows per parquet row group
+    #[arg(long, default_value_t = 1 << 20)]
+    row_group_size: usize,
+    /// parquet compression: none, snappy, gzip or zstd
+    #[arg(long, default_value = "snappy")]
+    compression: Compression,
 
-    let mut capture = Capture::from_file(pcap_file_path)?;
+    /// label every row with this value of `output`
+    #[arg(long)]
+    label: Option<u8>,
+    /// `attack_class` written for malicious rows
+    #[arg(long, default_value = "attack")]
+    class: String,
+    /// traffic to or from this endpoint (`IP`, `IP:PORT` or `*:PORT`) is malicious
+    #[arg(long = "attacker")]
+    attackers: Vec<Endpoint>,
+    /// traffic inside this `START..END` window (unix seconds) is malicious
+    #[arg(long = "attack-window")]
+    windows: Vec<Window>,
+    /// JSON file with a list of labeled flows
+    #[arg(long)]
+    flows: Option<PathBuf>,
 
-    let mut writer = csv::Writer::from_path(csv_file_path)?;
+    /// JSON feature config selecting built-in features, feature groups and derived features;
+    /// every built-in feature when not set
+    #[arg(long)]
+    features: Option<PathBuf>,
+    /// which packets become rows: mqtt, tcp or ip; match what `capture` runs with
+    #[arg(long, default_value_t)]
+    rows: RowPolicy,
+    /// leave out the columns identifying each row (capture, flow, timestamp, addresses, packet
+    /// index and row kind)
+    #[arg(long)]
+    no_ids: bool,
+    /// replace IP addresses and flow ids in the id columns with a hash keyed with this secret
+    #[arg(long)]
+    anonymize: Option<String>,
 
-    let mut packet = match capture.next_packet() {
-        Err(pcap::Error::NoMorePackets) => return Err(anyhow!("no packets in pcap file")),
-        v => v?,
-    };
+    /// worker threads; packets are sharded between them by host pair
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    /// forget sessions, topics and per-flow features of flows idle for this many seconds of
+    /// capture time, or `none`
+    #[arg(long, default_value = "none")]
+    flow_idle_timeout: Limit<f64>,
+    /// keep state for at most this many flows, forgetting the least recently used, or `none`
+    #[arg(long, default_value = "1048576")]
+    max_flows: Limit<usize>,
+    /// hold packets for this many seconds to put them back in timestamp order, for captures
+    /// merged from several interfaces or capture queues
+    #[arg(long)]
+    reorder_window: Option<f64>,
+    /// write frames that don't parse to this pcap file, a directory with --checkpoint; they are
+    /// skipped and counted either way
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
 
-    let mut ts = packet.header.ts;
-    let mut prev_ts = ts.tv_sec * 1_000_000 + ts.tv_usec;
+    /// extract incrementally: the pcap path is a directory of captures and the output path a
+    /// directory getting one file per capture. Captures done so far and the state the last one
+    /// ended with are kept in this file, so each run picks up new captures where the previous
+    /// one stopped, with timing features running on across files. Only `.pcap`, `.pcapng` and
+    /// `.cap` files are read, optionally with a `.gz`, `.zst` or `.xz` extension after that;
+    /// takes one thread
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// with --checkpoint, leave captures modified in the last this many seconds for the next
+    /// run, as they may still be written to
+    #[arg(long, default_value_t = 60)]
+    settle: u64,
+}
 
-    let mut tcp_l20_avg = 0;
-    let mut l20_diffs = VecDeque::with_capacity(20);
-    l20_diffs.push_back(0);
+impl Args {
+    fn labeler(&self) -> anyhow::Result<Labeler> {
+        let mut labeler = Labeler::default().class(&self.class);
+        if let Some(label) = self.label {
+            labeler = labeler.constant(label);
+        }
+        for &attacker in &self.attackers {
+            labeler = labeler.attacker(attacker);
+        }
+        for &window in &self.windows {
+            labeler = labeler.window(window);
+        }
+        if let Some(path) = &self.flows {
+            labeler = labeler.flows_file(path)?;
+        }
 
-    loop {
-        let mut info = HeadersInfo::default();
-        info.packet_len = packet.data.len();
+        Ok(labeler)
+    }
+}
 
-        let parsed_packet = SlicedPacket::from_ethernet(&packet.data[16..])?;
+// flows forgotten early may have gotten rows with features starting over, so say so
+fn report_evictions(pcap: &Path, stats: &ExtractStats) {
+    let evictions = stats.evictions();
+    if evictions.iter().all(|(_, e)| e.lru == 0 && e.idle == 0) {
+        return;
+    }
 
-        match parsed_packet.ip {
-            Some(InternetSlice::Ipv4(header, _)) => {
-                info.ip_len = header.total_len();
-                info.ip_df = header.dont_fragment();
-                info.ip_mf = header.more_fragments();
-                info.ip_ttl = header.ttl();
-            }
-            _ => {
-                packet = match capture.next_packet() {
-                    Err(pcap::Error::NoMorePackets) => break,
-                    v => v?,
-                };
+    eprintln!("{}: flow state dropped early", pcap.display());
+    for (table, e) in evictions {
+        eprintln!(
+            "{table:>16} {} idle, {} over --max-flows, {} closed",
+            e.idle, e.lru, e.closed
+        );
+    }
+}
 
-                continue;
-            }
-        };
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    // resuming carries one extractor's state from file to file, workers each have their own
+    if args.checkpoint.is_some() && args.threads > 1 {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--checkpoint only works with --threads 1",
+            )
+            .exit();
+    }
 
-        let ts2 = packet.header.ts;
-        ts = ts2;
-        let curr_ts = ts.tv_sec * 1_000_000 + ts.tv_usec;
-        let diff = curr_ts - prev_ts;
+    let labeler = args.labeler()?;
+    let labeler = (!labeler.is_empty()).then_some(&labeler);
 
-        let len = l20_diffs.len() as i64;
-        if len < 20 {
-            tcp_l20_avg = (tcp_l20_avg * len + diff) / len;
-        } else {
-            tcp_l20_avg = (tcp_l20_avg * 20 - l20_diffs.pop_front().unwrap() + diff) / 20;
-        }
+    let features = match &args.features {
+        Some(path) => Features::load(path)?,
+        None => Features::default(),
+    };
 
-        l20_diffs.push_back(diff);
-        prev_ts = curr_ts;
+    let mut columns = features.columns();
+    if labeler.is_some() {
+        columns.extend(Label::columns());
+    }
+    if !args.no_ids {
+        columns.extend(RowId::columns());
+    }
+    let options = OutputOptions {
+        format: args.format,
+        row_group_size: args.row_group_size,
+        compression: args.compression,
+        features: Some(features.config().clone()),
+    };
+    let extract = ExtractOptions {
+        labeler,
+        threads: args.threads,
+        rows: args.rows,
+        features,
+        flows: FlowLimits::default()
+            .idle_timeout(args.flow_idle_timeout.0)
+            .max_entries(args.max_flows.0),
+        reorder: args.reorder_window.map(secs_to_nanos),
+        quarantine: None,
+    };
 
-        match parsed_packet.transport {
-            Some(etherparse::TransportSlice::Tcp(header)) => {
-                info.tcp_len = header.slice().len();
-                info.tcp_pdu_size = header.data_offset();
-                info.tcp_ack = header.ack();
-                info.tcp_cwr = header.cwr();
-                info.tcp_ece = header.ece();
-                info.tcp_fin = header.fin();
-                info.tcp_ns = header.ns();
-                info.tcp_push = header.psh();
-                info.tcp_reset = header.rst();
-                info.tcp_syn = header.syn();
-                info.tcp_urg = header.urg();
-                info.tcp_src_port = header.source_port();
-                info.tcp_dst_port = header.destination_port();
-                info.tcp_tdelta = diff;
-                info.tcp_l20_avg = tcp_l20_avg;
+    let anonymizer = args.anonymize.as_deref().map(Anonymizer::new);
+    let extract_to = |pcap: &Path,
+                      out: &Path,
+                      quarantine: Option<PathBuf>,
+                      stream: Option<&mut Option<Stream>>| {
+        let extract = ExtractOptions {
+            quarantine,
+            ..extract.clone()
+        };
+        let mut sink = extractor::create_sink(out, &columns, &options)?;
+        let emit = |mut id: RowId, mut row: Vec<_>, label: Option<Label>| {
+            if let Some(label) = label {
+                row.extend(label.values());
             }
-            _ => {
-                packet = match capture.next_packet() {
-                    Err(pcap::Error::NoMorePackets) => break,
-                    v => v?,
-                };
-
-                writer.serialize(info)?;
-
-                continue;
+            if !args.no_ids {
+                if let Some(anonymizer) = &anonymizer {
+                    id.anonymize(anonymizer);
+                }
+                row.extend(id.values());
             }
+            sink.write(&row)
         };
-
-        let buf = &mut BytesMut::from(parsed_packet.payload);
-        let mut mqtt_packet = match mqttbytes::v4::read(buf, 1 << 30) {
-            Ok(p) => p,
-            Err(_) => {
-                writer.serialize(info)?;
-
-                packet = match capture.next_packet() {
-                    Err(pcap::Error::NoMorePackets) => break,
-                    v => v?,
-                };
-
-                continue;
-            }
+        let stats = match stream {
+            Some(stream) => extractor::extract_pcap_resume(pcap, &extract, stream, emit)?,
+            None => extractor::extract_pcap(pcap, &extract, emit)?,
         };
+        if stats.late > 0 {
+            eprintln!(
+                "{}: {} packets too late for --reorder-window, timestamps raised to keep order",
+                pcap.display(),
+                stats.late
+            );
+        }
+        if stats.skipped.total() > 0 {
+            eprintln!(
+                "{}: skipped {} frames that don't parse ({})",
+                pcap.display(),
+                stats.skipped.total(),
+                stats.skipped
+            );
+        }
+        report_evictions(pcap, &stats);
+        sink.finish()?;
+        Ok::<_, anyhow::Error>(stats.skipped)
+    };
 
-        loop {
-            let mut info = info.clone();
-
-            match mqtt_packet {
-                Packet::Connect(conn) => {
-                    info.mqtt_len = conn.len();
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 1;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::ConnAck(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 2;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::Publish(publish) => {
-                    info.mqtt_len = publish.len();
-                    info.mqtt_topic_len = publish.topic.len();
-                    info.mqtt_msg_type = 3;
-                    info.mqtt_qos_lvl = publish.qos as u8;
-                }
-                Packet::PubAck(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 4;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PubRec(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 5;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PubRel(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 6;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PubComp(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 7;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::Subscribe(subscribe) => {
-                    let mqtt_len = subscribe.len();
-                    let filter = match subscribe.filters.into_iter().next() {
-                        Some(v) => v,
-                        None => break,
-                    };
-                    info.mqtt_len = mqtt_len;
-                    info.mqtt_topic_len = filter.path.len();
-                    info.mqtt_msg_type = 8;
-                    info.mqtt_qos_lvl = filter.qos as u8;
-                }
-                Packet::SubAck(ack) => {
-                    let mqtt_len = 2 + ack.return_codes.len();
-                    let filter = ack.return_codes.into_iter().next().unwrap();
-                    let mqtt_qos_lvl = match filter {
-                        SubscribeReasonCode::Success(qos) => qos as u8,
-                        _ => 0,
-                    };
-                    info.mqtt_len = mqtt_len;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 9;
-                    info.mqtt_qos_lvl = mqtt_qos_lvl;
-                }
-                Packet::Unsubscribe(ubsub) => {
-                    let mqtt_len = 2 + ubsub.topics.iter().map(|s| s.len() + 2).sum::<usize>();
-                    let filter = ubsub.topics.into_iter().next().unwrap();
-                    info.mqtt_len = mqtt_len;
-                    info.mqtt_topic_len = filter.len();
-                    info.mqtt_msg_type = 10;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::UnsubAck(_) => {
-                    info.mqtt_len = 2;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 11;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PingReq => {
-                    info.mqtt_len = 0;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 12;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::PingResp => {
-                    info.mqtt_len = 0;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 13;
-                    info.mqtt_qos_lvl = 0;
-                }
-                Packet::Disconnect => {
-                    info.mqtt_len = 0;
-                    info.mqtt_topic_len = 0;
-                    info.mqtt_msg_type = 14;
-                    info.mqtt_qos_lvl = 0;
-                }
-            };
+    let checkpoint_path = match &args.checkpoint {
+        Some(path) => path,
+        None => {
+            let quarantine = args.quarantine.clone();
+            extract_to(&args.pcap_file_path, &args.csv_file_path, quarantine, None)?;
+            return Ok(());
+        }
+    };
 
-            writer.serialize(info)?;
+    let settings = Settings::new(
+        columns.clone(),
+        extract.features.time_unit(),
+        args.rows,
+        labeler,
+        anonymizer.as_ref(),
+    );
+    let mut checkpoint = Checkpoint::load(checkpoint_path, settings)?;
+    let pending = checkpoint.pending(&args.pcap_file_path, Duration::from_secs(args.settle))?;
+    let ext = format!("{:?}", args.format.unwrap_or(Format::Csv)).to_lowercase();
+    fs::create_dir_all(&args.csv_file_path)?;
+    if let Some(dir) = &args.quarantine {
+        fs::create_dir_all(dir)?;
+    }
 
-            mqtt_packet = match mqttbytes::v4::read(buf, 1 << 30) {
-                Ok(p) => p,
-                Err(_) => {
-                    break;
-                }
-            };
-        }
+    let mut skipped = SkipCounts::default();
+    for pcap in &pending {
+        let name = pcap.file_name().unwrap().to_string_lossy();
+        let out = args.csv_file_path.join(format!("{name}.{ext}"));
+        let quarantine = args.quarantine.as_ref().map(|dir| dir.join(&*name));
+        skipped += &extract_to(pcap, &out, quarantine, Some(&mut checkpoint.stream))?;
 
-        packet = match capture.next_packet() {
-            Err(pcap::Error::NoMorePackets) => break,
-            v => v?,
-        };
+        checkpoint.done(pcap);
+        checkpoint.save(checkpoint_path)?;
+    }
+    eprintln!("extracted {} new captures", pending.len());
+    if skipped.total() > 0 {
+        eprintln!(
+            "skipped {} frames that don't parse ({})",
+            skipped.total(),
+            skipped
+        );
     }
 
     Ok(())
@@ -1,21 +1,27 @@
 use std::{
     env::args,
+    io::Write,
+    net::{Ipv4Addr, TcpStream},
     thread::sleep,
     time::{Duration, SystemTime},
-    net::TcpStream,
 };
 
-use etherparse::SlicedPacket;
+use etherparse::TransportSlice;
+use extractor::Packet;
 
-fn send_right(stream: &mut TcpStream, data: &[u8]) -> anyhow::Result<()> {
-    let paarsed_packet = SlicedPacket::from_ethernet(data)?;
+const BROKER_PORT: u16 = 1883;
 
-
-    todo!();
-
-
-   #[allow(unreachable_code)]
-    Ok(())
+// Sends what a client sent the broker in the capture, the payload of TCP segments to the broker
+// port, over `stream`. Gives the bytes sent.
+fn send_right(stream: &mut TcpStream, packet: &Packet) -> anyhow::Result<usize> {
+    let parsed_packet = packet.parse()?;
+    match &parsed_packet.transport {
+        Some(TransportSlice::Tcp(tcp)) if tcp.destination_port() == BROKER_PORT => {
+            stream.write_all(parsed_packet.payload)?;
+            Ok(parsed_packet.payload.len())
+        }
+        _ => Ok(0),
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -25,34 +31,27 @@ fn main() -> anyhow::Result<()> {
         .next()
         .expect("1st argument should be a valid pcap file path");
 
-    let mut pcap_file = pcap::Capture::from_file(file_name).unwrap();
+    // pcap or pcapng, optionally compressed, `-` for stdin
+    let mut pcap_file = extractor::PacketReader::open(file_name)?;
 
     let mut timeout = SystemTime::now() + Duration::from_secs(5);
-    let packet1 = pcap_file.next_packet().unwrap();
-    let mut prev = packet1.header.ts;
+    let packet1 = pcap_file.next().expect("pcap file has no packets")?;
+    let mut prev = packet1.ts;
 
-    let stream = TcpStream::connect(([127, 0, 0, 1], 1883).into())?;
+    let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, BROKER_PORT))?;
 
-    capture.sendpacket(packet1.data).unwrap();
+    let mut bytes = send_right(&mut stream, &packet1)?;
     let mut last_sent = SystemTime::now();
 
     let mut i: u128 = 0;
 
-
-    while let Ok(packet) = pcap_file.next_packet() {
-        let curts = packet.header.ts;
-        let mut sd = curts.tv_sec - prev.tv_sec;
-        let ud = if sd == 0 {
-            curts.tv_usec - prev.tv_usec
-        } else if curts.tv_usec > prev.tv_usec {
-            curts.tv_usec - prev.tv_usec
-        } else {
-            sd -= 1;
-            prev.tv_usec - curts.tv_usec
-        };
-        if sd > 0 || (sd == 0 && ud > 0) {
-            let dur = Duration::from_secs(sd as u64) + Duration::from_micros(ud as u64);
-            // print!("sd {sd:10} ud {ud:10} {dur:10?}");
+    for packet in pcap_file {
+        let packet = packet?;
+        let curts = packet.ts;
+        // nanoseconds since the previous packet; packets out of timestamp order go out right away
+        let gap = u64::try_from(curts - prev).unwrap_or(0);
+        if gap > 0 {
+            let dur = Duration::from_nanos(gap);
             let new_time = last_sent + dur;
             let now = SystemTime::now();
             if new_time > now {
@@ -61,7 +60,7 @@ fn main() -> anyhow::Result<()> {
                 sleep(d);
             }
         }
-        capture.sendpacket(packet.data).unwrap();
+        bytes += send_right(&mut stream, &packet)?;
         last_sent = SystemTime::now();
         prev = curts;
         println!("sent {}", i);
@@ -69,7 +68,7 @@ fn main() -> anyhow::Result<()> {
 
         let now = SystemTime::now();
         if timeout <= now {
-            println!("{:#?}", capture.stats().unwrap());
+            println!("{bytes} bytes sent to the broker so far");
             timeout = now + Duration::from_secs(5);
         }
     }
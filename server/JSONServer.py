@@ -1,25 +1,52 @@
 from http.server import BaseHTTPRequestHandler, HTTPServer
 import json
+import sys
 import joblib
 import pandas as pd
 
 PORT_NUMBER = 8000
 
+# feature schema version and columns random_forest.pkl was trained on, written by train.ipynb
+with open("./schema.json") as f:
+    SCHEMA = json.load(f)
+FEATURES = SCHEMA["features"]
+
 class handler(BaseHTTPRequestHandler):
 
+    def reply(self, code, body):
+        self.send_response(code)
+        self.send_header('Content-type', 'application/json')
+        self.end_headers()
+        self.wfile.write(bytes(json.dumps(body), "utf8"))
+
+    def refuse(self, code, error):
+        print("refusing request: " + error, file=sys.stderr)
+        self.reply(code, {"error": error})
+
     def run(self, data):
+        version = self.headers.get('x-feature-schema-version')
+        if version != str(SCHEMA["version"]):
+            return self.refuse(409, "feature schema v{} does not match model schema v{}".format(
+                version, SCHEMA["version"]))
+        # features, derived features and time unit the training data was extracted with
+        if SCHEMA.get("config") is not None:
+            try:
+                config = json.loads(self.headers.get('x-feature-config') or "null")
+            except ValueError:
+                config = None
+            if config != SCHEMA["config"]:
+                return self.refuse(409, "feature config does not match the model's")
+
+        missing = [name for name in FEATURES if name not in data]
+        if missing:
+            return self.refuse(422, "missing features: {}".format(", ".join(missing)))
+
         df = pd.DataFrame([data])
         df.replace(False, 0, inplace=True)
         df.replace(True, 1, inplace=True)
-        self.send_response(200)
-        self.send_header('Content-type', 'application/json')
-        self.end_headers()
         # the dataframe from json
-        inp = df.iloc[0].to_numpy().reshape((1, 24))
-        # pls figure out data dimensions pls :|
-        json_object = json.dumps(
-            {"random_forest": int(clf.predict(inp)[0])})
-        self.wfile.write(bytes(json_object, "utf8"))
+        inp = df[FEATURES].iloc[0].to_numpy().reshape((1, len(FEATURES)))
+        self.reply(200, {"random_forest": int(clf.predict(inp)[0])})
 
     def log_message(self, format, *args):
         return
@@ -0,0 +1,95 @@
+use std::{
+    fs::File,
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use extractor::{secs_to_nanos, Packet, PacketReader, Reorder};
+use pcap_file::PcapWriter;
+
+// Writes a capture back out in timestamp order, as a pcap file.
+#[derive(Parser)]
+struct Args {
+    /// pcap or pcapng, optionally compressed, `-` for stdin
+    input: PathBuf,
+    /// pcap file to write
+    output: PathBuf,
+    /// only hold packets for this many seconds instead of sorting the whole capture in memory;
+    /// packets more out of order than that are written where they arrived, with their own
+    /// timestamp
+    #[arg(long)]
+    window: Option<f64>,
+}
+
+struct Output<'a> {
+    path: &'a Path,
+    // created on the first packet, which decides the link type and timestamp precision
+    writer: Option<(PcapWriter<BufWriter<File>>, u32)>,
+    count: u64,
+}
+
+impl Output<'_> {
+    fn write(&mut self, packet: Packet) -> anyhow::Result<()> {
+        let writer = match &mut self.writer {
+            Some((writer, link_type)) if *link_type == packet.link_type => writer,
+            Some((_, link_type)) => {
+                return Err(anyhow!(
+                    "packets with link types {link_type} and {}, a pcap file only holds one",
+                    packet.link_type
+                ))
+            }
+            None => {
+                let file = File::create(self.path)
+                    .with_context(|| format!("creating {}", self.path.display()))?;
+                let pcap = extractor::pcap_writer(BufWriter::new(file), &packet)?;
+                &mut self.writer.insert((pcap, packet.link_type)).0
+            }
+        };
+
+        extractor::write_packet(writer, &packet)?;
+        self.count += 1;
+        Ok(())
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let reader = PacketReader::open(&args.input)?;
+    let mut output = Output {
+        path: &args.output,
+        writer: None,
+        count: 0,
+    };
+
+    match args.window {
+        Some(secs) => {
+            let mut reorder = Reorder::new(reader, Some(secs_to_nanos(secs))).keep_late_ts();
+            for packet in &mut reorder {
+                output.write(packet?)?;
+            }
+            if reorder.late() > 0 {
+                eprintln!(
+                    "warning: {} packets too late for --window, left out of order",
+                    reorder.late()
+                );
+            }
+        }
+        None => {
+            let mut packets = reader.collect::<anyhow::Result<Vec<_>>>()?;
+            // stable, so packets with the same timestamp keep their order
+            packets.sort_by_key(|packet| packet.ts);
+            for packet in packets {
+                output.write(packet)?;
+            }
+        }
+    }
+
+    eprintln!(
+        "wrote {} packets to {}",
+        output.count,
+        args.output.display()
+    );
+    Ok(())
+}
@@ -0,0 +1,280 @@
+use anyhow::anyhow;
+use chrono::DateTime;
+use extractor::{secs_to_nanos, Packet};
+use serde::Serialize;
+
+use crate::key::{SplitBy, SplitKey};
+
+// Cuts the packets of a key into several outputs, by capture time or size, and names them.
+pub struct Chunker {
+    // output file names, with `{key}`, `{start}` and `{seq}` replaced
+    template: String,
+    // nanoseconds, outputs start on multiples of it
+    interval: Option<i64>,
+    // bytes an output may grow to, the file header included
+    max_size: Option<u64>,
+}
+
+// The output the packets of a key currently go to.
+pub struct Chunk {
+    pub name: String,
+    // what the first packet decided, a pcap file has one of each for all its packets
+    pub link_type: u32,
+    pub nanos: bool,
+    // which interval the output covers
+    window: Option<i64>,
+    seq: u64,
+    size: u64,
+    packets: u64,
+    // earliest and latest packet
+    first_ts: i64,
+    last_ts: i64,
+}
+
+// An output as listed in the index.
+#[derive(Serialize)]
+pub struct Summary {
+    pub file: String,
+    pub key: SplitKey,
+    pub seq: u64,
+    pub packets: u64,
+    // size of the file
+    pub bytes: u64,
+    // nanoseconds since the epoch
+    pub first_ts: i64,
+    pub last_ts: i64,
+}
+
+impl Chunker {
+    // `interval` in seconds and `max_size` in megabytes
+    pub fn new(
+        template: Option<String>,
+        by: SplitBy,
+        interval: Option<f64>,
+        max_size: Option<f64>,
+    ) -> anyhow::Result<Self> {
+        let interval = interval.map(secs_to_nanos);
+        if interval.is_some_and(|interval| interval <= 0) {
+            return Err(anyhow!("--rotate-interval has to be positive"));
+        }
+        if max_size.is_some_and(|mb| mb.is_nan() || mb <= 0.0) {
+            return Err(anyhow!("--rotate-size has to be positive"));
+        }
+        let max_size = max_size.map(|mb| (mb * 1e6) as u64);
+        let template = match template {
+            Some(template) => template,
+            None if interval.is_some() || max_size.is_some() => {
+                "{key}-{start}-{seq}.pcap".to_string()
+            }
+            None => "{key}.pcap".to_string(),
+        };
+
+        // outputs that would get the same name would overwrite each other
+        if by != SplitBy::All && !template.contains("{key}") {
+            return Err(anyhow!("--name needs a {{key}} unless splitting --by all"));
+        }
+        if max_size.is_some() && !template.contains("{seq}") {
+            return Err(anyhow!("--name needs a {{seq}} with --rotate-size"));
+        }
+        if interval.is_some() && !template.contains("{start}") && !template.contains("{seq}") {
+            return Err(anyhow!(
+                "--name needs a {{start}} or {{seq}} with --rotate-interval"
+            ));
+        }
+
+        Ok(Self {
+            template,
+            interval,
+            max_size,
+        })
+    }
+
+    pub fn first(&self, key: &SplitKey, packet: &Packet) -> Chunk {
+        self.chunk(key, packet, 0)
+    }
+
+    // the output to go on with once `packet` doesn't belong in `chunk` anymore, none while it does
+    pub fn next(&self, key: &SplitKey, chunk: &Chunk, packet: &Packet) -> Option<Chunk> {
+        // packets out of timestamp order stay where they are rather than reopen an earlier output
+        let later = self.window(packet) > chunk.window;
+        let full = match self.max_size {
+            Some(max) => chunk.packets > 0 && chunk.size + record_size(packet) > max,
+            None => false,
+        };
+
+        (later || full).then(|| self.chunk(key, packet, chunk.seq + 1))
+    }
+
+    fn window(&self, packet: &Packet) -> Option<i64> {
+        self.interval.map(|interval| packet.ts.div_euclid(interval))
+    }
+
+    fn chunk(&self, key: &SplitKey, packet: &Packet, seq: u64) -> Chunk {
+        let window = self.window(packet);
+        let start = match (window, self.interval) {
+            (Some(window), Some(interval)) => window * interval,
+            _ => packet.ts,
+        };
+        let start = DateTime::from_timestamp(start.div_euclid(1_000_000_000), 0)
+            .map(|start| start.format("%Y%m%dT%H%M%SZ").to_string())
+            .unwrap_or_default();
+        let name = self
+            .template
+            .replace("{key}", &key.to_string())
+            .replace("{start}", &start)
+            .replace("{seq}", &seq.to_string());
+
+        Chunk {
+            name,
+            link_type: packet.link_type,
+            nanos: packet.nanos,
+            window,
+            seq,
+            size: 24,
+            packets: 0,
+            first_ts: packet.ts,
+            last_ts: packet.ts,
+        }
+    }
+}
+
+impl Chunk {
+    pub fn wrote(&mut self, packet: &Packet) {
+        self.size += record_size(packet);
+        self.packets += 1;
+        self.first_ts = self.first_ts.min(packet.ts);
+        self.last_ts = self.last_ts.max(packet.ts);
+    }
+
+    pub fn summary(self, key: SplitKey) -> Summary {
+        Summary {
+            file: self.name,
+            key,
+            seq: self.seq,
+            packets: self.packets,
+            bytes: self.size,
+            first_ts: self.first_ts,
+            last_ts: self.last_ts,
+        }
+    }
+}
+
+// bytes a packet takes in a pcap file
+fn record_size(packet: &Packet) -> u64 {
+    16 + packet.data.len() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    const HOUR: i64 = 3600 * 1_000_000_000;
+    // 2023-11-14T22:00:00Z
+    const START: i64 = 1_699_999_200 * 1_000_000_000;
+
+    fn packet(ts: i64, size: usize) -> Packet {
+        Packet {
+            ts,
+            nanos: true,
+            len: size as u32,
+            link_type: 1,
+            snaplen: 65535,
+            data: vec![0; size],
+        }
+    }
+
+    fn key() -> SplitKey {
+        SplitKey::Source(Ipv4Addr::new(10, 0, 0, 5))
+    }
+
+    // names of the outputs the packets go to, in order
+    fn names(chunker: &Chunker, packets: &[Packet]) -> Vec<String> {
+        let mut chunk = chunker.first(&key(), &packets[0]);
+        let mut names = Vec::new();
+        for packet in packets {
+            if let Some(next) = chunker.next(&key(), &chunk, packet) {
+                chunk = next;
+            }
+            chunk.wrote(packet);
+            names.push(chunk.name.clone());
+        }
+        names
+    }
+
+    #[test]
+    fn rotates_on_interval_boundaries() {
+        let chunker = Chunker::new(None, SplitBy::Source, Some(3600.0), None).unwrap();
+        let packets = [
+            packet(START + 10, 60),
+            packet(START + HOUR - 1, 60),
+            packet(START + HOUR, 60),
+            // late, stays where it is
+            packet(START + 5, 60),
+            packet(START + 3 * HOUR + 1, 60),
+        ];
+        assert_eq!(
+            names(&chunker, &packets),
+            [
+                "10.0.0.5-20231114T220000Z-0.pcap",
+                "10.0.0.5-20231114T220000Z-0.pcap",
+                "10.0.0.5-20231114T230000Z-1.pcap",
+                "10.0.0.5-20231114T230000Z-1.pcap",
+                "10.0.0.5-20231115T010000Z-2.pcap",
+            ]
+        );
+    }
+
+    #[test]
+    fn rotates_before_going_over_the_size() {
+        // a header and two 484 byte records make 992 bytes, a third would go over 1000
+        let chunker = Chunker::new(
+            Some("{key}/{seq}.pcap".into()),
+            SplitBy::Source,
+            None,
+            Some(0.001),
+        )
+        .unwrap();
+        let packets = [
+            packet(START, 468),
+            packet(START + 1, 468),
+            packet(START + 2, 468),
+            // a packet too big for any output still gets one of its own
+            packet(START + 3, 2000),
+            packet(START + 4, 60),
+        ];
+        assert_eq!(
+            names(&chunker, &packets),
+            [
+                "10.0.0.5/0.pcap",
+                "10.0.0.5/0.pcap",
+                "10.0.0.5/1.pcap",
+                "10.0.0.5/2.pcap",
+                "10.0.0.5/3.pcap",
+            ]
+        );
+
+        let mut chunk = chunker.first(&key(), &packets[0]);
+        chunk.wrote(&packets[0]);
+        chunk.wrote(&packets[1]);
+        let summary = chunk.summary(key());
+        assert_eq!((summary.packets, summary.bytes), (2, 992));
+        assert_eq!((summary.first_ts, summary.last_ts), (START, START + 1));
+    }
+
+    #[test]
+    fn refuses_templates_that_would_overwrite_outputs() {
+        let new = |template: &str, by, interval, size| {
+            Chunker::new(Some(template.into()), by, interval, size).is_ok()
+        };
+        assert!(!new("out.pcap", SplitBy::Source, None, None));
+        assert!(new("out.pcap", SplitBy::All, None, None));
+        assert!(!new("{key}-{start}.pcap", SplitBy::Source, None, Some(1.0)));
+        assert!(new("{key}-{seq}.pcap", SplitBy::Source, None, Some(1.0)));
+        assert!(!new("{key}.pcap", SplitBy::Source, Some(60.0), None));
+        assert!(new("{key}-{start}.pcap", SplitBy::Source, Some(60.0), None));
+        assert!(!new("{key}-{seq}.pcap", SplitBy::Source, None, Some(0.0)));
+        assert!(!new("{key}-{seq}.pcap", SplitBy::Source, Some(0.0), None));
+    }
+}
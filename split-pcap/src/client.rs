@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+
+use bytes::BytesMut;
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use extractor::{flow_key, FlowKey, FlowLimits, FlowTable, Packet};
+use mqttbytes::{v4, v5};
+
+use crate::key::SplitKey;
+
+// packets of a connection held back at most while waiting for its CONNECT
+const MAX_HELD: usize = 64;
+// packets waiting at most behind those of a connection without a CONNECT yet
+const MAX_QUEUED: usize = 1 << 16;
+// seconds of capture time a connection can go quiet before its CONNECT
+const PENDING_TIMEOUT: f64 = 10.0;
+
+// What a TCP segment tells about its connection.
+pub struct Segment {
+    pub flow: FlowKey,
+    // the client id, if the segment carries a CONNECT
+    pub connect: Option<String>,
+    pub payload: bool,
+    pub closing: bool,
+}
+
+impl Segment {
+    // None for packets that aren't TCP over IPv4
+    pub fn of(packet: &SlicedPacket) -> Option<Self> {
+        let header = match &packet.ip {
+            Some(InternetSlice::Ipv4(header, _ext)) => header,
+            _ => return None,
+        };
+        let tcp = match &packet.transport {
+            Some(TransportSlice::Tcp(tcp)) => tcp,
+            _ => return None,
+        };
+
+        Some(Self {
+            flow: flow_key(
+                (header.source_addr(), tcp.source_port()),
+                (header.destination_addr(), tcp.destination_port()),
+            ),
+            connect: client_id(packet.payload),
+            payload: !packet.payload.is_empty(),
+            closing: tcp.fin() || tcp.rst(),
+        })
+    }
+}
+
+fn client_id(payload: &[u8]) -> Option<String> {
+    // not a CONNECT, no need to parse it
+    if payload.first()? >> 4 != 1 {
+        return None;
+    }
+    if let Ok(v4::Packet::Connect(connect)) = v4::read(&mut BytesMut::from(payload), 1 << 30) {
+        return Some(connect.client_id);
+    }
+    match v5::read(&mut BytesMut::from(payload), 1 << 30) {
+        Ok(v5::Packet::Connect(connect)) => Some(connect.client_id),
+        _ => None,
+    }
+}
+
+// a packet in arrival order, and where it goes once that's known
+struct Queued {
+    flow: FlowKey,
+    key: Option<SplitKey>,
+    packet: Packet,
+}
+
+// Follows TCP connections to the client id in their CONNECT, so every packet of a connection,
+// both directions and the handshake before the CONNECT too, goes to the output of its client.
+// A client sends its CONNECT before anything else, so connections that send something else
+// first, close, go quiet, or go on for too long without one are given up on as unidentified.
+// Packets come out in the order they went in: those of other connections wait behind the ones
+// held back.
+pub struct Clients {
+    known: FlowTable<FlowKey, SplitKey>,
+    // connections waiting for their CONNECT, with the sequence numbers of their packets
+    pending: FlowTable<FlowKey, Vec<u64>>,
+    queue: VecDeque<Queued>,
+    // sequence number of the front of `queue`
+    front: u64,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Self {
+            known: FlowTable::new(FlowLimits::default()),
+            pending: FlowTable::new(FlowLimits::default().idle_timeout(Some(PENDING_TIMEOUT)))
+                .keep_evicted(),
+            queue: VecDeque::new(),
+            front: 0,
+        }
+    }
+
+    // The packets that can be written now and where to, `packet` among them unless it's held
+    // back until its connection is known, or waits behind one that is.
+    pub fn route(&mut self, segment: Segment, packet: Packet) -> Vec<(SplitKey, Packet)> {
+        let (flow, ts) = (segment.flow, packet.ts);
+        self.known.expire(ts);
+        self.pending.expire(ts);
+        for (_, held) in self.pending.take_evicted() {
+            self.resolve(&held, &SplitKey::Unidentified);
+        }
+
+        let key = match self.known.get_mut(&flow, ts) {
+            Some(key) => Some(key.clone()),
+            None => {
+                let held = self.pending.get_or_insert_with(flow, ts, Vec::new);
+                match segment.connect {
+                    Some(id) => Some(SplitKey::Client(id)),
+                    None if segment.payload || segment.closing || held.len() >= MAX_HELD => {
+                        Some(SplitKey::Unidentified)
+                    }
+                    None => {
+                        held.push(self.front + self.queue.len() as u64);
+                        None
+                    }
+                }
+            }
+        };
+        if let Some(key) = &key {
+            if let Some(held) = self.pending.remove(&flow) {
+                self.resolve(&held, key);
+                self.known.insert(flow, ts, key.clone());
+            }
+        }
+        self.queue.push_back(Queued { flow, key, packet });
+        if segment.closing {
+            self.known.close(&flow, ts);
+        }
+
+        // a connection holding up too many packets is given up on
+        while self.queue.len() > MAX_QUEUED {
+            let flow = self.queue[0].flow;
+            match self.pending.remove(&flow) {
+                Some(held) => {
+                    self.resolve(&held, &SplitKey::Unidentified);
+                    self.known.insert(flow, ts, SplitKey::Unidentified);
+                }
+                None => break,
+            }
+        }
+
+        self.ready()
+    }
+
+    // the packets still held back, once there are no more to come
+    pub fn finish(mut self) -> Vec<(SplitKey, Packet)> {
+        let flows: Vec<_> = self.pending.iter().map(|(&flow, _)| flow).collect();
+        for flow in flows {
+            if let Some(held) = self.pending.remove(&flow) {
+                self.resolve(&held, &SplitKey::Unidentified);
+            }
+        }
+        for (_, held) in self.pending.take_evicted() {
+            self.resolve(&held, &SplitKey::Unidentified);
+        }
+        self.ready()
+    }
+
+    fn resolve(&mut self, held: &[u64], key: &SplitKey) {
+        for &seq in held {
+            self.queue[(seq - self.front) as usize].key = Some(key.clone());
+        }
+    }
+
+    // packets at the front of the queue whose connection is known
+    fn ready(&mut self) -> Vec<(SplitKey, Packet)> {
+        let mut routed = Vec::new();
+        while let Some(Queued { key: Some(_), .. }) = self.queue.front() {
+            let queued = self.queue.pop_front().unwrap();
+            routed.push((queued.key.unwrap(), queued.packet));
+            self.front += 1;
+        }
+        routed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+
+    use super::*;
+
+    const SEC: i64 = 1_000_000_000;
+
+    // a segment from a client port to the broker, a SYN when there's no payload
+    fn segment(port: u16, ts: i64, payload: &[u8]) -> (Segment, Packet) {
+        let builder =
+            PacketBuilder::ethernet2([1; 6], [2; 6]).ipv4([10, 0, 0, 1], [10, 0, 0, 2], 64);
+        let builder = match payload.is_empty() {
+            true => builder.tcp(port, 1883, 1, 1024).syn(),
+            false => builder.tcp(port, 1883, 1, 1024).ack(1),
+        };
+        let mut data = Vec::new();
+        builder.write(&mut data, payload).unwrap();
+        let packet = Packet {
+            ts,
+            nanos: true,
+            len: data.len() as u32,
+            link_type: 1,
+            snaplen: 65535,
+            data,
+        };
+        let segment = Segment::of(&packet.parse().unwrap()).unwrap();
+        (segment, packet)
+    }
+
+    fn connect(id: &str) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        v4::Connect::new(id).write(&mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    fn route(clients: &mut Clients, port: u16, ts: i64, payload: &[u8]) -> Vec<(SplitKey, i64)> {
+        let (segment, packet) = segment(port, ts, payload);
+        keys(clients.route(segment, packet))
+    }
+
+    fn keys(routed: Vec<(SplitKey, Packet)>) -> Vec<(SplitKey, i64)> {
+        routed
+            .into_iter()
+            .map(|(key, packet)| (key, packet.ts))
+            .collect()
+    }
+
+    fn client(id: &str) -> SplitKey {
+        SplitKey::Client(id.to_string())
+    }
+
+    #[test]
+    fn keeps_arrival_order_behind_held_packets() {
+        let mut clients = Clients::new();
+        assert!(route(&mut clients, 40000, 1, &[]).is_empty());
+        // known right away, but behind the SYN of the first connection
+        assert!(route(&mut clients, 40001, 2, &connect("b")).is_empty());
+        assert_eq!(
+            route(&mut clients, 40000, 3, &connect("a")),
+            [(client("a"), 1), (client("b"), 2), (client("a"), 3)]
+        );
+        assert_eq!(route(&mut clients, 40001, 4, b"x"), [(client("b"), 4)]);
+    }
+
+    #[test]
+    fn gives_up_on_connections_without_a_connect() {
+        let mut clients = Clients::new();
+        route(&mut clients, 40000, 0, &[]);
+        route(&mut clients, 40001, 1, &[]);
+        assert_eq!(
+            route(&mut clients, 40000, 2, b"not mqtt"),
+            [(SplitKey::Unidentified, 0)]
+        );
+        // quiet for too long
+        assert_eq!(
+            route(&mut clients, 40002, 20 * SEC, &connect("c")),
+            [
+                (SplitKey::Unidentified, 1),
+                (SplitKey::Unidentified, 2),
+                (client("c"), 20 * SEC)
+            ]
+        );
+        route(&mut clients, 40003, 21 * SEC, &[]);
+        assert_eq!(keys(clients.finish()), [(SplitKey::Unidentified, 21 * SEC)]);
+    }
+}
@@ -0,0 +1,432 @@
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context};
+use bytes::BytesMut;
+use etherparse::SlicedPacket;
+use extractor::{FlowKey, FlowLimits, FlowTable, Packet, PacketReader, Window};
+use mqttbytes::{v4, v5};
+use pcap::{BpfProgram, Capture, Linktype};
+
+use crate::client::Segment;
+
+const MQTT_TYPES: [&str; 14] = [
+    "connect",
+    "connack",
+    "publish",
+    "puback",
+    "pubrec",
+    "pubrel",
+    "pubcomp",
+    "subscribe",
+    "suback",
+    "unsubscribe",
+    "unsuback",
+    "pingreq",
+    "pingresp",
+    "disconnect",
+];
+
+// An MQTT control packet type, by name or number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MqttType(u8);
+
+impl FromStr for MqttType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(i) = MQTT_TYPES
+            .iter()
+            .position(|name| s.eq_ignore_ascii_case(name))
+        {
+            return Ok(MqttType(i as u8 + 1));
+        }
+        match s.parse() {
+            Ok(n @ 1..=14) => Ok(MqttType(n)),
+            _ => Err(anyhow!(
+                "unknown MQTT packet type {s}, expected one of {} or 1 to 14",
+                MQTT_TYPES.join(", ")
+            )),
+        }
+    }
+}
+
+#[derive(clap::Args, Clone)]
+pub struct FilterArgs {
+    /// only keep packets this BPF expression matches, as tcpdump takes it
+    #[arg(long)]
+    bpf: Option<String>,
+    /// only keep packets within `START..END`, in unix seconds
+    #[arg(long)]
+    time: Option<Window>,
+    /// only keep TCP segments carrying an MQTT packet of this type, like `publish` or `3`
+    #[arg(long)]
+    mqtt_type: Option<MqttType>,
+    /// only keep TCP segments carrying an MQTT publish, subscribe or unsubscribe with a topic
+    /// or filter starting with this
+    #[arg(long)]
+    topic_prefix: Option<String>,
+    /// only keep TCP segments carrying an MQTT publish or subscription with this QoS
+    #[arg(long)]
+    qos: Option<u8>,
+    /// only keep packets of TCP connections that sent a CONNECT with this client id, from the
+    /// CONNECT on
+    #[arg(long)]
+    client_id: Option<String>,
+    /// keep every packet of a TCP connection with a packet that matches, from its handshake
+    /// on; reads the input twice
+    #[arg(long)]
+    pub whole_connections: bool,
+}
+
+// One MQTT packet, or one topic of it, as far as the filter is concerned.
+struct Item<'a> {
+    msg_type: u8,
+    topic: Option<&'a str>,
+    qos: Option<u8>,
+}
+
+// Which packets to keep; a packet has to match everything given.
+pub struct Filter {
+    args: FilterArgs,
+    // BPF programs only work for the link type they were compiled for
+    programs: HashMap<u32, BpfProgram>,
+    // client ids of connections that sent a CONNECT
+    clients: FlowTable<FlowKey, String>,
+}
+
+impl Filter {
+    pub fn new(args: FilterArgs) -> Self {
+        Self {
+            args,
+            programs: HashMap::new(),
+            clients: FlowTable::new(FlowLimits::default()),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let args = &self.args;
+        args.bpf.is_none()
+            && args.time.is_none()
+            && args.mqtt_type.is_none()
+            && args.topic_prefix.is_none()
+            && args.qos.is_none()
+            && args.client_id.is_none()
+    }
+
+    // whether `matches` needs packets parsed, rather than only their timestamp and bytes
+    pub fn needs_parse(&self) -> bool {
+        let args = &self.args;
+        args.mqtt_type.is_some()
+            || args.topic_prefix.is_some()
+            || args.qos.is_some()
+            || args.client_id.is_some()
+    }
+
+    // `parsed` can only be left out when not `needs_parse`
+    pub fn matches(
+        &mut self,
+        packet: &Packet,
+        parsed: Option<&SlicedPacket>,
+    ) -> anyhow::Result<bool> {
+        // needs to see every packet to know the client of a connection
+        let client = match &self.args.client_id {
+            Some(client_id) => match parsed.and_then(Segment::of) {
+                Some(segment) => {
+                    self.clients.expire(packet.ts);
+                    if let Some(id) = segment.connect {
+                        self.clients.insert(segment.flow, packet.ts, id);
+                    }
+                    let client = self.clients.get_mut(&segment.flow, packet.ts);
+                    let matches = client.is_some_and(|id| id == client_id);
+                    if segment.closing {
+                        self.clients.close(&segment.flow, packet.ts);
+                    }
+                    matches
+                }
+                None => false,
+            },
+            None => true,
+        };
+        if !client {
+            return Ok(false);
+        }
+
+        if let Some(window) = self.args.time {
+            if !window.contains(packet.ts) {
+                return Ok(false);
+            }
+        }
+
+        let args = &self.args;
+        if args.mqtt_type.is_some() || args.topic_prefix.is_some() || args.qos.is_some() {
+            let mut matches = false;
+            let payload = parsed.map_or(&[][..], |parsed| parsed.payload);
+            for_each_item(payload, |item| {
+                matches |= args.mqtt_type.is_none_or(|t| t.0 == item.msg_type)
+                    && args.topic_prefix.as_ref().is_none_or(|prefix| {
+                        item.topic
+                            .is_some_and(|topic| topic.starts_with(prefix.as_str()))
+                    })
+                    && args.qos.is_none_or(|qos| item.qos == Some(qos));
+            });
+            if !matches {
+                return Ok(false);
+            }
+        }
+
+        if let Some(expression) = &self.args.bpf {
+            let program = match self.programs.entry(packet.link_type) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => entry.insert(
+                    Capture::dead(Linktype(packet.link_type as i32))?
+                        .compile(expression, true)
+                        .with_context(|| {
+                            format!("compiling --bpf for link type {}", packet.link_type)
+                        })?,
+                ),
+            };
+            if !program.filter(&packet.data) {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+// Calls `f` with every MQTT packet in a TCP payload, once per topic for those with several. MQTT
+// 5 packets are told apart by failing to parse as 3.1.1, as in `client::client_id`.
+fn for_each_item(payload: &[u8], mut f: impl FnMut(Item)) {
+    let buf = &mut BytesMut::from(payload);
+    while let Some(&first) = buf.first() {
+        let msg_type = first >> 4;
+        let mut v4_buf = buf.clone();
+        // topics and QoS of the packet
+        let topics: Vec<(Option<String>, Option<u8>)> = match v4::read(&mut v4_buf, 1 << 30) {
+            Ok(mqtt_packet) => {
+                *buf = v4_buf;
+                match mqtt_packet {
+                    v4::Packet::Publish(publish) => {
+                        vec![(Some(publish.topic), Some(publish.qos as u8))]
+                    }
+                    v4::Packet::Subscribe(subscribe) => subscribe
+                        .filters
+                        .into_iter()
+                        .map(|filter| (Some(filter.path), Some(filter.qos as u8)))
+                        .collect(),
+                    v4::Packet::Unsubscribe(unsubscribe) => unsubscribe
+                        .topics
+                        .into_iter()
+                        .map(|topic| (Some(topic), None))
+                        .collect(),
+                    _ => vec![(None, None)],
+                }
+            }
+            Err(_) => match v5::read(buf, 1 << 30) {
+                Ok(v5::Packet::Publish(publish)) => {
+                    vec![(Some(publish.topic), Some(publish.qos as u8))]
+                }
+                Ok(v5::Packet::Subscribe(subscribe)) => subscribe
+                    .filters
+                    .into_iter()
+                    .map(|filter| (Some(filter.path), Some(filter.qos as u8)))
+                    .collect(),
+                Ok(v5::Packet::Unsubscribe(unsubscribe)) => unsubscribe
+                    .filters
+                    .into_iter()
+                    .map(|topic| (Some(topic), None))
+                    .collect(),
+                Ok(_) => vec![(None, None)],
+                Err(_) => break,
+            },
+        };
+
+        for (topic, qos) in &topics {
+            f(Item {
+                msg_type,
+                topic: topic.as_deref(),
+                qos: *qos,
+            });
+        }
+    }
+}
+
+// The TCP connections with a packet `args` matches, for keeping them whole.
+pub fn matching_connections(path: &Path, args: FilterArgs) -> anyhow::Result<HashSet<FlowKey>> {
+    if path.to_str() == Some("-") {
+        return Err(anyhow!(
+            "--whole-connections reads the input twice, it can't be stdin"
+        ));
+    }
+
+    let mut filter = Filter::new(args);
+    let mut connections = HashSet::new();
+    for packet in PacketReader::open(path)? {
+        let packet = packet?;
+        // skipped and counted on the pass that writes
+        let parsed = match packet.parse() {
+            Ok(parsed) => parsed,
+            Err(_) => continue,
+        };
+        if let Some(segment) = Segment::of(&parsed) {
+            if !connections.contains(&segment.flow) && filter.matches(&packet, Some(&parsed))? {
+                connections.insert(segment.flow);
+            }
+        }
+    }
+
+    Ok(connections)
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+    use mqttbytes::QoS;
+
+    use super::*;
+
+    const SEC: i64 = 1_000_000_000;
+
+    fn args() -> FilterArgs {
+        FilterArgs {
+            bpf: None,
+            time: None,
+            mqtt_type: None,
+            topic_prefix: None,
+            qos: None,
+            client_id: None,
+            whole_connections: false,
+        }
+    }
+
+    fn publish(topic: &str, qos: QoS) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        let mut publish = v4::Publish::new(topic, qos, "1");
+        publish.pkid = 1;
+        publish.write(&mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    fn connect(id: &str) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        v4::Connect::new(id).write(&mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    // a segment from a client port to the broker
+    fn packet(port: u16, ts: i64, payload: &[u8]) -> Packet {
+        let mut data = Vec::new();
+        PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv4([10, 0, 0, 5], [10, 0, 0, 1], 64)
+            .tcp(port, 1883, 1, 1024)
+            .ack(1)
+            .write(&mut data, payload)
+            .unwrap();
+        Packet {
+            ts,
+            nanos: true,
+            len: data.len() as u32,
+            link_type: 1,
+            snaplen: 65535,
+            data,
+        }
+    }
+
+    fn matches(filter: &mut Filter, packet: &Packet) -> bool {
+        let parsed = packet.parse().unwrap();
+        filter.matches(packet, Some(&parsed)).unwrap()
+    }
+
+    fn items(payload: &[u8]) -> Vec<(u8, Option<String>, Option<u8>)> {
+        let mut items = Vec::new();
+        for_each_item(payload, |item| {
+            items.push((item.msg_type, item.topic.map(String::from), item.qos))
+        });
+        items
+    }
+
+    #[test]
+    fn parses_mqtt_types_by_name_or_number() {
+        assert_eq!("publish".parse::<MqttType>().unwrap(), MqttType(3));
+        assert_eq!("PINGREQ".parse::<MqttType>().unwrap(), MqttType(12));
+        assert_eq!("14".parse::<MqttType>().unwrap(), MqttType(14));
+        assert!("0".parse::<MqttType>().is_err());
+        assert!("auth".parse::<MqttType>().is_err());
+    }
+
+    #[test]
+    fn reads_every_mqtt_packet_and_topic_of_a_segment() {
+        let mut subscribe = v4::Subscribe::new("a/#", QoS::AtLeastOnce);
+        subscribe.add("b/+".to_string(), QoS::AtMostOnce);
+        subscribe.pkid = 2;
+        let mut buf = BytesMut::new();
+        subscribe.write(&mut buf).unwrap();
+        let mut v5 = BytesMut::new();
+        v5::Publish::new("c/d", QoS::AtMostOnce, "1")
+            .write(&mut v5)
+            .unwrap();
+        let payload = [publish("x/y", QoS::ExactlyOnce), buf.to_vec(), v5.to_vec()].concat();
+
+        assert_eq!(
+            items(&payload),
+            [
+                (3, Some("x/y".into()), Some(2)),
+                (8, Some("a/#".into()), Some(1)),
+                (8, Some("b/+".into()), Some(0)),
+                (3, Some("c/d".into()), Some(0)),
+            ]
+        );
+        // a partial packet ends it
+        assert_eq!(items(&payload[..3]), []);
+    }
+
+    #[test]
+    fn mqtt_predicates_have_to_hold_for_one_packet() {
+        let mut filter = Filter::new(FilterArgs {
+            mqtt_type: Some(MqttType(3)),
+            topic_prefix: Some("sensors/".into()),
+            qos: Some(1),
+            ..args()
+        });
+        assert!(filter.needs_parse() && !filter.is_empty());
+
+        let both = [
+            publish("sensors/1", QoS::AtMostOnce),
+            publish("other", QoS::AtLeastOnce),
+        ]
+        .concat();
+        assert!(!matches(&mut filter, &packet(40000, 0, &both)));
+        let payload = publish("sensors/1", QoS::AtLeastOnce);
+        assert!(matches(&mut filter, &packet(40000, 0, &payload)));
+        assert!(!matches(&mut filter, &packet(40000, 0, &connect("a"))));
+    }
+
+    #[test]
+    fn client_ids_and_time_windows() {
+        let mut filter = Filter::new(FilterArgs {
+            client_id: Some("sensor-1".into()),
+            time: Some("10..20".parse().unwrap()),
+            ..args()
+        });
+        let payload = publish("t", QoS::AtMostOnce);
+
+        assert!(matches(
+            &mut filter,
+            &packet(40000, 11 * SEC, &connect("sensor-1"))
+        ));
+        assert!(!matches(
+            &mut filter,
+            &packet(40001, 11 * SEC, &connect("sensor-2"))
+        ));
+        assert!(matches(&mut filter, &packet(40000, 12 * SEC, &payload)));
+        assert!(!matches(&mut filter, &packet(40001, 12 * SEC, &payload)));
+        // the connection's client is remembered outside the window too
+        assert!(!matches(&mut filter, &packet(40000, 25 * SEC, &payload)));
+        assert!(matches(&mut filter, &packet(40000, 19 * SEC, &payload)));
+        assert!(!Filter::new(args()).needs_parse() && Filter::new(args()).is_empty());
+    }
+}
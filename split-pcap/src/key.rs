@@ -0,0 +1,204 @@
+use std::{fmt, net::Ipv4Addr, str::FromStr};
+
+use anyhow::anyhow;
+use etherparse::{InternetSlice, SlicedPacket, TransportSlice};
+use extractor::{flow_key, FlowKey};
+use serde::Serialize;
+
+// What decides the file a packet goes to.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SplitBy {
+    // source and destination host, so a conversation is split over two files
+    #[default]
+    Direction,
+    // both hosts, whichever sent the packet
+    Pair,
+    // both endpoints and the protocol, so a file per TCP connection
+    Connection,
+    // source host only
+    Source,
+    // the client id in the CONNECT of a TCP connection, for every packet of the connection
+    Client,
+    // a single output, to only cut the capture into chunks
+    All,
+}
+
+impl FromStr for SplitBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "direction" => Ok(SplitBy::Direction),
+            "pair" => Ok(SplitBy::Pair),
+            "connection" => Ok(SplitBy::Connection),
+            "source" => Ok(SplitBy::Source),
+            "client" => Ok(SplitBy::Client),
+            "all" => Ok(SplitBy::All),
+            _ => Err(anyhow!(
+                "unknown split key {s}, expected direction, pair, connection, source, client or all"
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitKey {
+    Hosts(Ipv4Addr, Ipv4Addr),
+    // IP protocol number and both endpoints; ports are 0 for protocols without them
+    Connection(u8, FlowKey),
+    Source(Ipv4Addr),
+    Client(String),
+    // connections whose client id never showed up
+    Unidentified,
+    All,
+}
+
+impl SplitKey {
+    // None for packets that aren't IPv4, unless they all go to the same output
+    pub fn of(by: SplitBy, packet: &SlicedPacket) -> Option<Self> {
+        if by == SplitBy::All {
+            return Some(SplitKey::All);
+        }
+        let header = match &packet.ip {
+            Some(InternetSlice::Ipv4(header, _ext)) => header,
+            None | Some(InternetSlice::Ipv6(_, _)) => return None,
+        };
+        let (src, dst) = (header.source_addr(), header.destination_addr());
+
+        Some(match by {
+            SplitBy::Direction => SplitKey::Hosts(src, dst),
+            SplitBy::Pair => SplitKey::Hosts(src.min(dst), src.max(dst)),
+            SplitBy::Connection => {
+                let (src_port, dst_port) = match &packet.transport {
+                    Some(TransportSlice::Tcp(tcp)) => (tcp.source_port(), tcp.destination_port()),
+                    Some(TransportSlice::Udp(udp)) => (udp.source_port(), udp.destination_port()),
+                    _ => (0, 0),
+                };
+                SplitKey::Connection(
+                    header.protocol(),
+                    flow_key((src, src_port), (dst, dst_port)),
+                )
+            }
+            SplitBy::Source => SplitKey::Source(src),
+            // the client id is in an earlier packet, see `Clients`
+            SplitBy::Client => return None,
+            SplitBy::All => SplitKey::All,
+        })
+    }
+}
+
+// the name of the file the packets of a key go to, without extension
+impl fmt::Display for SplitKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SplitKey::Hosts(a, b) => write!(f, "{a}-{b}"),
+            SplitKey::Connection(protocol, ((a, a_port), (b, b_port))) => {
+                let protocol = match protocol {
+                    6 => "tcp".to_string(),
+                    17 => "udp".to_string(),
+                    protocol => format!("ip{protocol}"),
+                };
+                write!(f, "{a}_{a_port}-{b}_{b_port}-{protocol}")
+            }
+            SplitKey::Source(src) => write!(f, "{src}"),
+            // percent-encoded, so any client id makes a file name of its own
+            SplitKey::Client(id) => {
+                f.write_str("client-")?;
+                for byte in id.bytes() {
+                    match byte {
+                        b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' => {
+                            write!(f, "{}", byte as char)?
+                        }
+                        _ => write!(f, "%{byte:02X}")?,
+                    }
+                }
+                Ok(())
+            }
+            SplitKey::Unidentified => f.write_str("unidentified"),
+            SplitKey::All => f.write_str("all"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use etherparse::PacketBuilder;
+
+    use super::*;
+
+    fn frame(src: ([u8; 4], u16), dst: ([u8; 4], u16), udp: bool) -> Vec<u8> {
+        let builder = PacketBuilder::ethernet2([1; 6], [2; 6]).ipv4(src.0, dst.0, 64);
+        let mut data = Vec::new();
+        match udp {
+            true => builder.udp(src.1, dst.1).write(&mut data, &[]),
+            false => builder.tcp(src.1, dst.1, 1, 1024).write(&mut data, &[]),
+        }
+        .unwrap();
+        data
+    }
+
+    fn name(by: SplitBy, data: &[u8]) -> Option<String> {
+        let packet = SlicedPacket::from_ethernet(data).unwrap();
+        SplitKey::of(by, &packet).map(|key| key.to_string())
+    }
+
+    #[test]
+    fn both_directions_share_a_key_unless_split_by_direction() {
+        let out = frame(([10, 0, 0, 5], 40000), ([10, 0, 0, 1], 1883), false);
+        let back = frame(([10, 0, 0, 1], 1883), ([10, 0, 0, 5], 40000), false);
+        let names = |by| (name(by, &out).unwrap(), name(by, &back).unwrap());
+
+        assert_eq!(
+            names(SplitBy::Direction),
+            ("10.0.0.5-10.0.0.1".into(), "10.0.0.1-10.0.0.5".into())
+        );
+        assert_eq!(
+            names(SplitBy::Pair),
+            ("10.0.0.1-10.0.0.5".into(), "10.0.0.1-10.0.0.5".into())
+        );
+        let (a, b) = names(SplitBy::Connection);
+        assert_eq!(a, b);
+        assert_eq!(a, "10.0.0.1_1883-10.0.0.5_40000-tcp");
+        assert_eq!(
+            names(SplitBy::Source),
+            ("10.0.0.5".into(), "10.0.0.1".into())
+        );
+        assert_eq!(names(SplitBy::All), ("all".into(), "all".into()));
+        // client ids are only known from the connection's CONNECT
+        assert_eq!(name(SplitBy::Client, &out), None);
+    }
+
+    #[test]
+    fn connections_keep_protocols_apart() {
+        let tcp = frame(([10, 0, 0, 5], 5000), ([10, 0, 0, 1], 5001), false);
+        let udp = frame(([10, 0, 0, 5], 5000), ([10, 0, 0, 1], 5001), true);
+        assert_eq!(
+            name(SplitBy::Connection, &udp).unwrap(),
+            "10.0.0.1_5001-10.0.0.5_5000-udp"
+        );
+        assert_ne!(
+            name(SplitBy::Connection, &tcp),
+            name(SplitBy::Connection, &udp)
+        );
+    }
+
+    #[test]
+    fn client_ids_make_safe_file_names() {
+        let key = SplitKey::Client("sensor/1 ü..".to_string());
+        assert_eq!(key.to_string(), "client-sensor%2F1%20%C3%BC%2E%2E");
+        assert_eq!(SplitKey::Unidentified.to_string(), "unidentified");
+    }
+
+    #[test]
+    fn non_ipv4_packets_have_no_key() {
+        let mut data = Vec::new();
+        PacketBuilder::ethernet2([1; 6], [2; 6])
+            .ipv6([1; 16], [2; 16], 64)
+            .udp(1, 2)
+            .write(&mut data, &[])
+            .unwrap();
+        assert_eq!(name(SplitBy::Pair, &data), None);
+        assert_eq!(name(SplitBy::All, &data), Some("all".into()));
+    }
+}
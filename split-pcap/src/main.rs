@@ -1,57 +1,196 @@
-use std::{collections::HashMap, env, fs::File, io::BufWriter};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
 
-use etherparse::{InternetSlice, SlicedPacket};
-use pcap::Capture;
-use pcap_file::PcapWriter;
+use anyhow::{anyhow, Context};
+use clap::Parser;
+use extractor::{FlowLimits, Limit, PacketReader, SkipCounts, Skipped};
+use serde::Serialize;
+
+use chunk::{Chunker, Summary};
+use client::{Clients, Segment};
+use filter::{Filter, FilterArgs};
+use key::{SplitBy, SplitKey};
+use output::Splitter;
+
+mod chunk;
+mod client;
+mod filter;
+mod key;
+mod output;
+
+#[derive(Parser)]
+struct Args {
+    /// pcap or pcapng, optionally compressed, `-` for stdin
+    pcap_file_path: PathBuf,
+    /// write the packets kept to this one file instead of splitting them up
+    #[arg(
+        long,
+        conflicts_with_all = ["output_dir", "by", "rotate_interval", "rotate_size", "name"]
+    )]
+    output: Option<PathBuf>,
+    /// directory the outputs are written to, created if missing
+    #[arg(long, default_value = ".")]
+    output_dir: PathBuf,
+    /// write a JSON index of the outputs to this file, with the key, packet count, size and
+    /// first and last timestamp of each
+    #[arg(long)]
+    index: Option<PathBuf>,
+    /// output files kept open at once; the least recently written one is closed to make room
+    /// and appended to when its key shows up again
+    #[arg(long, default_value = "1024")]
+    max_open: Limit<usize>,
+    /// close the output of a key idle for this many seconds of capture time, or `none`
+    #[arg(long, default_value = "none")]
+    idle_timeout: Limit<f64>,
+    /// what gets a file of its own: `direction` for each source and destination host,
+    /// `pair` for both hosts whichever sent, `connection` for each TCP connection or UDP
+    /// flow, `source` for each source host, `client` for each MQTT client id, with every
+    /// packet of the TCP connections it sent a CONNECT on, or `all` for a single one
+    #[arg(long, default_value = "direction")]
+    by: SplitBy,
+    /// start a new output every this many seconds of capture time, on multiples of it since the
+    /// epoch so `3600` cuts on the hour; packets out of timestamp order stay in the output they
+    /// came up in
+    #[arg(long)]
+    rotate_interval: Option<f64>,
+    /// start a new output once one would grow past this many megabytes
+    #[arg(long)]
+    rotate_size: Option<f64>,
+    /// output file names: `{key}` is replaced by the split key, `{start}` by the UTC start time
+    /// of the output, or of its interval with --rotate-interval, and `{seq}` by its number
+    /// among the outputs of its key, from 0; `{key}.pcap` by default, `{key}-{start}-{seq}.pcap`
+    /// when rotating
+    #[arg(long)]
+    name: Option<String>,
+    /// write frames that don't parse to this pcap file; they are skipped and counted either way
+    #[arg(long)]
+    quarantine: Option<PathBuf>,
+    /// read the outputs back once written and check they hold the input packets unchanged
+    #[arg(long)]
+    verify: bool,
+    #[command(flatten)]
+    filter: FilterArgs,
+}
+
+#[derive(Serialize)]
+struct Index<'a> {
+    input: &'a Path,
+    output_dir: &'a Path,
+    // frames that didn't parse, by reason
+    skipped: SkipCounts,
+    outputs: Vec<Summary>,
+}
 
 fn main() -> anyhow::Result<()> {
-    let mut args = env::args();
-    args.next().unwrap();
-
-    let pcap_file_path = args.next().expect("pass in file name");
-    let mut pcap = Capture::from_file(pcap_file_path)?;
-
-    let mut map = HashMap::new();
-    loop {
-        let packet = match pcap.next_packet() {
-            Err(pcap::Error::NoMorePackets) => break,
-            Err(e) => return Err(e.into()),
-            Ok(v) => v,
+    let args = Args::parse();
+    let pcap = PacketReader::open(&args.pcap_file_path)?;
+
+    let (dir, by, chunker) = match &args.output {
+        Some(output) => {
+            let name = output
+                .to_str()
+                .ok_or_else(|| anyhow!("{} isn't valid UTF-8", output.display()))?;
+            let chunker = Chunker::new(Some(name.to_string()), SplitBy::All, None, None)?;
+            (PathBuf::new(), SplitBy::All, chunker)
+        }
+        None => {
+            let chunker = Chunker::new(args.name, args.by, args.rotate_interval, args.rotate_size)?;
+            fs::create_dir_all(&args.output_dir)?;
+            (args.output_dir.clone(), args.by, chunker)
+        }
+    };
+    let limits = FlowLimits::default()
+        .idle_timeout(args.idle_timeout.0)
+        .max_entries(args.max_open.0);
+    let mut splitter = Splitter::new(dir.clone(), chunker, limits, args.verify);
+    // TCP connections kept whole
+    let connections = match args.filter.whole_connections {
+        true => Some(filter::matching_connections(
+            &args.pcap_file_path,
+            args.filter.clone(),
+        )?),
+        false => None,
+    };
+    let mut filter = Filter::new(args.filter);
+    let (mut read, mut kept) = (0, 0);
+    let mut skipped = Skipped::new(args.quarantine);
+    let mut clients = Clients::new();
+
+    // frames are only parsed when something needs more than their timestamp and bytes, so
+    // cutting a capture into chunks keeps frames etherparse can't make sense of
+    let parse = by != SplitBy::All || filter.needs_parse() || connections.is_some();
+
+    for packet in pcap {
+        let packet = packet?;
+        read += 1;
+        let parsed_packet = match parse {
+            true => match packet.parse() {
+                Ok(parsed_packet) => Some(parsed_packet),
+                Err(e) => {
+                    skipped.skip(&packet, e)?;
+                    continue;
+                }
+            },
+            false => None,
         };
-        let ts = packet.header.ts;
-        let parsed_packet = SlicedPacket::from_ip(&packet.data[16..])?;
-        // let parsed_packet = SlicedPacket::from_ethernet(packet.data)?;
-
-        let (src, dst) = match parsed_packet.ip {
-            None | Some(InternetSlice::Ipv6(_, _)) => continue,
-            Some(InternetSlice::Ipv4(header, _ext)) => {
-                (header.source_addr(), header.destination_addr())
-            }
+
+        let whole = match &connections {
+            Some(connections) => parsed_packet
+                .as_ref()
+                .and_then(Segment::of)
+                .is_some_and(|segment| connections.contains(&segment.flow)),
+            None => false,
         };
+        if !whole && !filter.matches(&packet, parsed_packet.as_ref())? {
+            continue;
+        }
+        kept += 1;
 
-        match map.get_mut(&(src, dst)) {
-            None => {
-                let file = File::create(format!("{src}-{dst}.pcap"))?;
-                let mut capture_file = PcapWriter::new(BufWriter::new(file))?;
-                capture_file.write(
-                    ts.tv_usec as u32,
-                    ts.tv_usec as u32 * 1000,
-                    packet.data,
-                    packet.data.len() as u32,
-                )?;
-                map.insert((src, dst), capture_file);
-            }
-            Some(capture_file) => {
-                capture_file.write(
-                    ts.tv_usec as u32,
-                    ts.tv_usec as u32 * 1000,
-                    packet.data,
-                    packet.data.len() as u32,
-                )?;
-            }
+        let routed = match (by, &parsed_packet) {
+            (SplitBy::All, _) => vec![(SplitKey::All, packet)],
+            (SplitBy::Client, Some(parsed_packet)) => match Segment::of(parsed_packet) {
+                Some(segment) => clients.route(segment, packet),
+                None => continue,
+            },
+            (by, Some(parsed_packet)) => match SplitKey::of(by, parsed_packet) {
+                Some(key) => vec![(key, packet)],
+                None => continue,
+            },
+            (_, None) => unreachable!("frames are parsed for every other key"),
+        };
+        for (key, packet) in routed {
+            splitter.write(key, &packet)?;
         }
     }
+    for (key, packet) in clients.finish() {
+        splitter.write(key, &packet)?;
+    }
+
+    if !filter.is_empty() {
+        eprintln!("kept {kept} of {read} packets");
+    }
+    let counts = skipped.finish()?;
+    if counts.total() > 0 {
+        eprintln!(
+            "skipped {} frames that don't parse ({counts})",
+            counts.total()
+        );
+    }
+    let outputs = splitter.finish()?;
+
+    if let Some(path) = &args.index {
+        let index = Index {
+            input: &args.pcap_file_path,
+            output_dir: &dir,
+            skipped: counts,
+            outputs,
+        };
+        let file = File::create(path).with_context(|| format!("creating {}", path.display()))?;
+        serde_json::to_writer_pretty(BufWriter::new(file), &index)?;
+    }
 
-    #[allow(unreachable_code)]
     Ok(())
 }
@@ -0,0 +1,397 @@
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs::{self, File, OpenOptions},
+    hash::{Hash, Hasher},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+use extractor::{FlowLimits, FlowTable, Packet, PacketReader};
+use pcap_file::PcapWriter;
+
+use crate::{
+    chunk::{Chunk, Chunker, Summary},
+    key::SplitKey,
+};
+
+// A capture file being written. Files closed early are reopened for appending, and the file
+// header `PcapWriter` starts with is swallowed, since they already have one.
+pub struct Output {
+    file: BufWriter<File>,
+    skip: usize,
+}
+
+impl Output {
+    fn create(path: &Path) -> io::Result<Self> {
+        // templates may put outputs in directories of their own
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            skip: 0,
+        })
+    }
+
+    fn append(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            file: BufWriter::new(OpenOptions::new().append(true).open(path)?),
+            skip: 24,
+        })
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.skip > 0 {
+            let n = buf.len().min(self.skip);
+            self.skip -= n;
+            return Ok(n);
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+// A packet as written, to check the output against. The bytes are only kept hashed.
+#[derive(PartialEq, Eq)]
+struct Written {
+    ts: i64,
+    len: u32,
+    caplen: usize,
+    data: u64,
+}
+
+impl Written {
+    fn new(packet: &Packet) -> Self {
+        let mut hasher = DefaultHasher::new();
+        packet.data.hash(&mut hasher);
+        Self {
+            ts: packet.ts,
+            len: packet.len,
+            caplen: packet.data.len(),
+            data: hasher.finish(),
+        }
+    }
+}
+
+// Packets written to an output since it was last read back.
+struct Unverified {
+    link_type: u32,
+    // packets at the start of the file that were read back already
+    verified: usize,
+    packets: Vec<Written>,
+    // once an output doesn't match it isn't read back again
+    differs: bool,
+}
+
+// Where the packets read back from `path` after the first `skip` first differ from those
+// written, if they do.
+fn verify(
+    path: &Path,
+    link_type: u32,
+    skip: usize,
+    written: &[Written],
+) -> anyhow::Result<Option<String>> {
+    let mut read = 0;
+    for packet in PacketReader::open(path)?.skip(skip) {
+        let packet = packet?;
+        let index = skip + read;
+        let expected = match written.get(read) {
+            Some(expected) => expected,
+            None => return Ok(Some(format!("more than the {index} packets written"))),
+        };
+        if packet.link_type != link_type {
+            return Ok(Some(format!("link type {}", packet.link_type)));
+        }
+        let actual = Written::new(&packet);
+        if actual.ts != expected.ts {
+            return Ok(Some(format!("packet {index}: timestamp {} ns", actual.ts)));
+        }
+        if (actual.len, actual.caplen) != (expected.len, expected.caplen) {
+            return Ok(Some(format!(
+                "packet {index}: {} of {} bytes",
+                actual.caplen, actual.len
+            )));
+        }
+        if actual != *expected {
+            return Ok(Some(format!("packet {index}: different bytes")));
+        }
+        read += 1;
+    }
+
+    Ok((read < written.len())
+        .then(|| format!("{} of {} packets", skip + read, skip + written.len())))
+}
+
+// Writes packets to the output of their key, keeping a bounded number of files open.
+pub struct Splitter {
+    // output names are relative to it
+    dir: PathBuf,
+    chunker: Chunker,
+    open: FlowTable<SplitKey, PcapWriter<Output>>,
+    // the output of every key seen, whether open or not
+    chunks: HashMap<SplitKey, Chunk>,
+    // outputs rotated out
+    done: Vec<Summary>,
+    verify: bool,
+    // by output name; read back and dropped whenever an output is closed, so only the packets
+    // of open outputs are held
+    written: HashMap<String, Unverified>,
+    verified: usize,
+}
+
+impl Splitter {
+    pub fn new(dir: PathBuf, chunker: Chunker, limits: FlowLimits, verify: bool) -> Self {
+        Self {
+            dir,
+            chunker,
+            open: FlowTable::new(limits).keep_evicted(),
+            chunks: HashMap::new(),
+            done: Vec::new(),
+            verify,
+            written: HashMap::new(),
+            verified: 0,
+        }
+    }
+
+    pub fn write(&mut self, key: SplitKey, packet: &Packet) -> anyhow::Result<()> {
+        // an idle output is written to by the old writer until it's flushed, so that has to
+        // happen before it's opened again
+        self.open.expire(packet.ts);
+        self.flush_evicted()?;
+
+        // whether the output has yet to be created, rather than appended to
+        let mut fresh = false;
+        let chunk = match self.chunks.get_mut(&key) {
+            None => {
+                fresh = true;
+                let chunk = self.chunker.first(&key, packet);
+                self.chunks.entry(key.clone()).or_insert(chunk)
+            }
+            Some(chunk) => {
+                if let Some(next) = self.chunker.next(&key, chunk, packet) {
+                    if let Some(done) = self.open.remove(&key) {
+                        done.into_writer().flush()?;
+                    }
+                    let done = std::mem::replace(chunk, next);
+                    check(&self.dir, &mut self.written, &mut self.verified, &done.name)?;
+                    self.done.push(done.summary(key.clone()));
+                    fresh = true;
+                }
+                chunk
+            }
+        };
+        if packet.link_type != chunk.link_type {
+            return Err(anyhow!(
+                "{}: packets with link types {} and {}, a pcap file only holds one",
+                chunk.name,
+                chunk.link_type,
+                packet.link_type
+            ));
+        }
+        if packet.nanos != chunk.nanos {
+            return Err(anyhow!(
+                "{}: packets with microsecond and nanosecond timestamps, a pcap file only holds one",
+                chunk.name
+            ));
+        }
+
+        let mut new = None;
+        if self.open.get(&key).is_none() {
+            let path = self.dir.join(&chunk.name);
+            let output = match fresh {
+                true => Output::create(&path),
+                false => Output::append(&path),
+            }
+            .with_context(|| format!("opening {}", path.display()))?;
+            new = Some(extractor::pcap_writer(output, packet)?);
+        }
+        let capture_file = self
+            .open
+            .get_or_insert_with(key, packet.ts, || new.unwrap());
+        extractor::write_packet(capture_file, packet)?;
+        chunk.wrote(packet);
+        if self.verify {
+            self.written
+                .entry(chunk.name.clone())
+                .or_insert_with(|| Unverified {
+                    link_type: chunk.link_type,
+                    verified: 0,
+                    packets: Vec::new(),
+                    differs: false,
+                })
+                .packets
+                .push(Written::new(packet));
+        }
+        // the least recently written output, if this one made room
+        self.flush_evicted()
+    }
+
+    fn flush_evicted(&mut self) -> anyhow::Result<()> {
+        for (key, closed) in self.open.take_evicted() {
+            closed.into_writer().flush()?;
+            let name = &self.chunks[&key].name;
+            check(&self.dir, &mut self.written, &mut self.verified, name)?;
+        }
+        Ok(())
+    }
+
+    // Closes every output, and reads back what's left to with `verify`. Gives every output
+    // written, by name.
+    pub fn finish(mut self) -> anyhow::Result<Vec<Summary>> {
+        let evictions = self.open.evictions();
+        if evictions.total() > 0 {
+            eprintln!(
+                "closed outputs early {} times, {} idle and {} over --max-open",
+                evictions.total(),
+                evictions.idle,
+                evictions.lru
+            );
+        }
+        let keys: Vec<_> = self.open.iter().map(|(key, _)| key.clone()).collect();
+        for key in keys {
+            self.open.remove(&key).unwrap().into_writer().flush()?;
+        }
+
+        let mut outputs = self.done;
+        outputs.extend(
+            self.chunks
+                .into_iter()
+                .map(|(key, chunk)| chunk.summary(key)),
+        );
+        outputs.sort_by(|a, b| a.file.cmp(&b.file));
+
+        if !self.verify {
+            return Ok(outputs);
+        }
+        let names: Vec<_> = self.written.keys().cloned().collect();
+        for name in names {
+            check(&self.dir, &mut self.written, &mut self.verified, &name)?;
+        }
+        let differ = self
+            .written
+            .values()
+            .filter(|output| output.differs)
+            .count();
+        if differ > 0 {
+            return Err(anyhow!(
+                "{differ} of {} outputs don't match the input",
+                self.written.len()
+            ));
+        }
+        eprintln!(
+            "verified {} packets in {} outputs",
+            self.verified,
+            self.written.len()
+        );
+
+        Ok(outputs)
+    }
+}
+
+// Reads back what was written to the output `name` since it was last checked, once it's closed,
+// and forgets those packets. Appending to an output again later picks up where this left off.
+fn check(
+    dir: &Path,
+    written: &mut HashMap<String, Unverified>,
+    verified: &mut usize,
+    name: &str,
+) -> anyhow::Result<()> {
+    let output = match written.get_mut(name) {
+        Some(output) if !output.differs && !output.packets.is_empty() => output,
+        _ => return Ok(()),
+    };
+    let path = dir.join(name);
+    if let Some(diff) = verify(&path, output.link_type, output.verified, &output.packets)? {
+        eprintln!("{name} doesn't match the input: {diff}");
+        output.differs = true;
+    }
+    output.verified += output.packets.len();
+    *verified += output.packets.len();
+    output.packets = Vec::new();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, process};
+
+    use super::*;
+    use crate::key::SplitBy;
+
+    fn packet(ts: i64, byte: u8) -> Packet {
+        Packet {
+            ts,
+            nanos: true,
+            len: 60,
+            link_type: 1,
+            snaplen: 65535,
+            data: vec![byte; 60],
+        }
+    }
+
+    fn key(host: u8) -> SplitKey {
+        SplitKey::Hosts(Ipv4Addr::new(10, 0, 0, host), Ipv4Addr::new(10, 0, 0, 100))
+    }
+
+    // one output open at a time, so every switch of key closes one and appends to the other
+    fn splitter(dir: &Path) -> Splitter {
+        let chunker = Chunker::new(None, SplitBy::Direction, None, None).unwrap();
+        let limits = FlowLimits::default().max_entries(Some(1));
+        Splitter::new(dir.to_path_buf(), chunker, limits, true)
+    }
+
+    fn read(path: &Path) -> Vec<(i64, u8)> {
+        PacketReader::open(path)
+            .unwrap()
+            .map(|packet| {
+                let packet = packet.unwrap();
+                (packet.ts, packet.data[0])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appends_to_outputs_closed_early() {
+        let dir = std::env::temp_dir().join(format!("split-pcap-append-{}", process::id()));
+        let mut splitter = splitter(&dir);
+        for i in 0..6 {
+            splitter
+                .write(key(1 + i as u8 % 2), &packet(i, i as u8))
+                .unwrap();
+        }
+        splitter.write(key(1), &packet(6, 6)).unwrap();
+        let outputs = splitter.finish().unwrap();
+
+        assert_eq!(outputs.len(), 2);
+        let first = dir.join(key(1).to_string() + ".pcap");
+        let second = dir.join(key(2).to_string() + ".pcap");
+        assert_eq!(read(&first), [(0, 0), (2, 2), (4, 4), (6, 6)]);
+        assert_eq!(read(&second), [(1, 1), (3, 3), (5, 5)]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn verify_catches_outputs_changed_after_closing() {
+        let dir = std::env::temp_dir().join(format!("split-pcap-verify-{}", process::id()));
+        let mut splitter = splitter(&dir);
+        splitter.write(key(1), &packet(0, 0)).unwrap();
+        splitter.write(key(1), &packet(1, 1)).unwrap();
+        // closes and checks the first output
+        splitter.write(key(2), &packet(2, 2)).unwrap();
+        assert!(splitter.written.values().all(|o| o.packets.len() <= 1));
+
+        // cut the last packet off
+        let path = dir.join(key(1).to_string() + ".pcap");
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(24 + 76).unwrap();
+        splitter.write(key(1), &packet(3, 3)).unwrap();
+        assert!(splitter.finish().is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}